@@ -0,0 +1,72 @@
+"use strict";
+
+// fills in the hidden fields of forms marked data-passkey with what the
+// browser's passkey prompt answers, then sends them on
+
+const fromBase64url = (text) => {
+	const base64 = text.replace(/-/g, "+").replace(/_/g, "/");
+	return Uint8Array.from(atob(base64), (c) => c.charCodeAt(0));
+};
+
+const toBase64url = (buffer) => {
+	const text = String.fromCharCode(...new Uint8Array(buffer));
+	return btoa(text).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+};
+
+async function register(form) {
+	const data = form.dataset;
+	const credential = await navigator.credentials.create({ publicKey: {
+		challenge: fromBase64url(data.challenge),
+		rp: { id: data.rpId, name: "LSYS" },
+		user: {
+			id: new TextEncoder().encode(data.userId),
+			name: data.userName,
+			displayName: data.userDisplay,
+		},
+		pubKeyCredParams: [
+			{ type: "public-key", alg: -7 },
+			{ type: "public-key", alg: -8 },
+			{ type: "public-key", alg: -257 },
+		],
+		excludeCredentials: JSON.parse(data.exclude || "[]")
+			.map((id) => ({ type: "public-key", id: fromBase64url(id) })),
+		authenticatorSelection: { residentKey: "required", userVerification: "preferred" },
+		attestation: "none",
+	} });
+	form.elements.client_data.value = toBase64url(credential.response.clientDataJSON);
+	form.elements.attestation.value = toBase64url(credential.response.attestationObject);
+}
+
+async function login(form) {
+	const data = form.dataset;
+	const credential = await navigator.credentials.get({ publicKey: {
+		challenge: fromBase64url(data.challenge),
+		rpId: data.rpId,
+		userVerification: "preferred",
+	} });
+	form.elements.credential_id.value = toBase64url(credential.rawId);
+	form.elements.client_data.value = toBase64url(credential.response.clientDataJSON);
+	form.elements.authenticator_data.value = toBase64url(credential.response.authenticatorData);
+	form.elements.signature.value = toBase64url(credential.response.signature);
+}
+
+const ceremonies = { register, login };
+
+window.addEventListener("DOMContentLoaded", () => {
+	for (const form of document.querySelectorAll("form[data-passkey]")) {
+		const error = form.querySelector(".passkey-error");
+		form.addEventListener("submit", async (event) => {
+			event.preventDefault();
+			if (!window.PublicKeyCredential) {
+				error.textContent = "This browser can't use passkeys.";
+				return;
+			}
+			try {
+				await ceremonies[form.dataset.passkey](form);
+				form.submit();
+			} catch (e) {
+				error.textContent = "The passkey prompt didn't finish: " + e.message;
+			}
+		});
+	}
+});
@@ -1,10 +1,125 @@
+-- server wide values, like the key links in mail are signed with
+DROP TABLE IF EXISTS settings;
+CREATE TABLE IF NOT EXISTS settings (
+	key TEXT NOT NULL PRIMARY KEY,
+	value TEXT NOT NULL
+);
+
+DROP TABLE IF EXISTS roles;
+CREATE TABLE IF NOT EXISTS roles (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	name TEXT NOT NULL UNIQUE
+);
+
+DROP TABLE IF EXISTS role_permissions;
+CREATE TABLE IF NOT EXISTS role_permissions (
+	role_id INTEGER NOT NULL,
+	permission TEXT NOT NULL
+		CHECK(permission IN ('reserve', 'circulate', 'catalog', 'manage_accounts')),
+	UNIQUE(role_id, permission),
+	FOREIGN KEY(role_id) REFERENCES roles(id)
+);
+
 DROP TABLE IF EXISTS accounts;
 CREATE TABLE IF NOT EXISTS accounts (
 	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
 	name TEXT NOT NULL,
 	email TEXT NOT NULL UNIQUE,
 	pass_hash TEXT NOT NULL,
-	is_worker BOOL NOT NULL DEFAULT false
+	-- new accounts are patrons
+	role_id INTEGER NOT NULL DEFAULT 1,
+	verified BOOL NOT NULL DEFAULT false,
+	-- when the account was deleted; the row stays, emptied, and its borrow_log rows are
+	-- moved to the one account with the email "deleted"
+	deleted INTEGER DEFAULT NULL,
+	FOREIGN KEY(role_id) REFERENCES roles(id)
+);
+
+-- times are unix seconds, a session ends when idle or old, whichever comes first;
+-- only a hash of each token is kept, the token itself is in the cookie
+DROP TABLE IF EXISTS sessions;
+CREATE TABLE IF NOT EXISTS sessions (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	token_hash TEXT NOT NULL UNIQUE,
+	user_id INTEGER NOT NULL,
+	created INTEGER NOT NULL,
+	last_seen INTEGER NOT NULL,
+	user_agent TEXT NOT NULL DEFAULT '',
+	FOREIGN KEY(user_id) REFERENCES accounts(id)
+);
+
+-- only a hash of each token is kept, the token itself is in the mail
+DROP TABLE IF EXISTS password_resets;
+CREATE TABLE IF NOT EXISTS password_resets (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	token_hash TEXT NOT NULL UNIQUE,
+	user_id INTEGER NOT NULL,
+	expires INTEGER NOT NULL,
+	used INTEGER DEFAULT NULL,
+	FOREIGN KEY(user_id) REFERENCES accounts(id)
+);
+
+-- logins refused for a while after too many wrong passwords,
+-- from one address (kind 'ip') or against one email (kind 'email')
+DROP TABLE IF EXISTS lockouts;
+CREATE TABLE IF NOT EXISTS lockouts (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	kind TEXT NOT NULL CHECK(kind IN ('ip', 'email')),
+	subject TEXT NOT NULL,
+	failures INTEGER NOT NULL,
+	created INTEGER NOT NULL,
+	until INTEGER NOT NULL,
+	-- set when an administrator ends it early
+	lifted INTEGER DEFAULT NULL
+);
+
+-- authenticator app secrets (hex), enabled once a first code was entered;
+-- last_step is the last 30 second step a code was used for, so none is used twice
+DROP TABLE IF EXISTS totp;
+CREATE TABLE IF NOT EXISTS totp (
+	user_id INTEGER NOT NULL PRIMARY KEY,
+	secret TEXT NOT NULL,
+	enabled INTEGER DEFAULT NULL,
+	last_step INTEGER NOT NULL DEFAULT 0,
+	FOREIGN KEY(user_id) REFERENCES accounts(id)
+);
+
+-- single use codes for when the authenticator is lost, only their hashes are kept
+DROP TABLE IF EXISTS recovery_codes;
+CREATE TABLE IF NOT EXISTS recovery_codes (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	user_id INTEGER NOT NULL,
+	code_hash TEXT NOT NULL UNIQUE,
+	used INTEGER DEFAULT NULL,
+	FOREIGN KEY(user_id) REFERENCES accounts(id)
+);
+
+-- passkeys' public keys as COSE_Key, credential ids in unpadded base64url as browsers give them
+DROP TABLE IF EXISTS passkeys;
+CREATE TABLE IF NOT EXISTS passkeys (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	user_id INTEGER NOT NULL,
+	credential_id TEXT NOT NULL UNIQUE,
+	public_key BLOB NOT NULL,
+	sign_count INTEGER NOT NULL DEFAULT 0,
+	name TEXT NOT NULL,
+	created INTEGER NOT NULL,
+	last_used INTEGER DEFAULT NULL,
+	FOREIGN KEY(user_id) REFERENCES accounts(id)
+);
+
+-- personal tokens for scripts, sent as "Authorization: Bearer"; like reset tokens only hashes are kept
+DROP TABLE IF EXISTS api_tokens;
+CREATE TABLE IF NOT EXISTS api_tokens (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	user_id INTEGER NOT NULL,
+	token_hash TEXT NOT NULL UNIQUE,
+	name TEXT NOT NULL,
+	scope TEXT NOT NULL CHECK(scope IN ('read', 'write')),
+	created INTEGER NOT NULL,
+	expires INTEGER NOT NULL,
+	last_used INTEGER DEFAULT NULL,
+	FOREIGN KEY(user_id) REFERENCES accounts(id)
 );
 
 DROP TABLE IF EXISTS books;
@@ -16,30 +131,42 @@ CREATE TABLE IF NOT EXISTS books (
 	time DATE DEFAULT NULL,
 	is_borrow BOOL DEFAULT NULL,
 	CHECK((time IS NULL) == (user_id IS NULL)),
-	CHECK((time IS NULL) == (is_borrow IS NULL))
+	CHECK((time IS NULL) == (is_borrow IS NULL)),
+	FOREIGN KEY(ISBN) REFERENCES book_info(ISBN)
 );
 
 DROP TABLE IF EXISTS book_info;
 CREATE TABLE IF NOT EXISTS book_info (
 	ISBN INTEGER NOT NULL PRIMARY KEY,
 	name TEXT NOT NULL,
-	published TEXT NOT NULL,
-	FOREIGN KEY(ISBN) REFERENCES books(ISBN)
+	published TEXT NOT NULL
 );
 
 DROP TABLE IF EXISTS authors;
 CREATE TABLE IF NOT EXISTS authors (
 	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
-	name TEXT NOT NULL UNIQUE
+	name TEXT NOT NULL UNIQUE,
+	born INTEGER,
+	died INTEGER
+);
+
+DROP TABLE IF EXISTS author_names;
+CREATE TABLE IF NOT EXISTS author_names (
+	author_id INTEGER NOT NULL,
+	name TEXT NOT NULL,
+	UNIQUE(author_id, name),
+	FOREIGN KEY(author_id) REFERENCES authors(id)
 );
 
 DROP TABLE IF EXISTS wrote;
 CREATE TABLE IF NOT EXISTS wrote (
 	author_id INTEGER NOT NULL,
 	ISBN INTEGER NOT NULL,
-	UNIQUE(author_id, ISBN),
+	role TEXT NOT NULL DEFAULT 'author'
+		CHECK(role IN ('author', 'editor', 'translator', 'illustrator')),
+	UNIQUE(author_id, ISBN, role),
 	FOREIGN KEY(author_id) REFERENCES authors(id),
-	FOREIGN KEY(ISBN) REFERENCES books(ISBN)
+	FOREIGN KEY(ISBN) REFERENCES book_info(ISBN)
 );
 
 DROP TABLE IF EXISTS borrow_log;
@@ -55,42 +182,212 @@ CREATE TABLE IF NOT EXISTS borrow_log (
 	FOREIGN KEY(book_id) REFERENCES books(id)
 );
 
+DROP TABLE IF EXISTS subjects;
+CREATE TABLE IF NOT EXISTS subjects (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	name TEXT NOT NULL,
+	parent_id INTEGER DEFAULT NULL,
+	UNIQUE(parent_id, name),
+	FOREIGN KEY(parent_id) REFERENCES subjects(id)
+);
+
+DROP TABLE IF EXISTS book_subjects;
+CREATE TABLE IF NOT EXISTS book_subjects (
+	subject_id INTEGER NOT NULL,
+	ISBN INTEGER NOT NULL,
+	UNIQUE(subject_id, ISBN),
+	FOREIGN KEY(subject_id) REFERENCES subjects(id),
+	FOREIGN KEY(ISBN) REFERENCES book_info(ISBN)
+);
+
+DROP TABLE IF EXISTS book_tags;
+CREATE TABLE IF NOT EXISTS book_tags (
+	ISBN INTEGER NOT NULL,
+	tag TEXT NOT NULL,
+	UNIQUE(ISBN, tag),
+	FOREIGN KEY(ISBN) REFERENCES book_info(ISBN)
+);
+
+DROP TABLE IF EXISTS series;
+CREATE TABLE IF NOT EXISTS series (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	name TEXT NOT NULL UNIQUE
+);
+
+DROP TABLE IF EXISTS series_volumes;
+CREATE TABLE IF NOT EXISTS series_volumes (
+	series_id INTEGER NOT NULL,
+	ISBN INTEGER NOT NULL UNIQUE,
+	volume INTEGER NOT NULL,
+	UNIQUE(series_id, volume),
+	FOREIGN KEY(series_id) REFERENCES series(id),
+	FOREIGN KEY(ISBN) REFERENCES book_info(ISBN)
+);
+
+DROP TABLE IF EXISTS works;
+CREATE TABLE IF NOT EXISTS works (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	title TEXT NOT NULL
+);
+
+DROP TABLE IF EXISTS editions;
+CREATE TABLE IF NOT EXISTS editions (
+	ISBN INTEGER NOT NULL PRIMARY KEY,
+	work_id INTEGER NOT NULL,
+	label TEXT NOT NULL DEFAULT '',
+	FOREIGN KEY(ISBN) REFERENCES book_info(ISBN),
+	FOREIGN KEY(work_id) REFERENCES works(id)
+);
+
+DROP TABLE IF EXISTS holds;
+CREATE TABLE IF NOT EXISTS holds (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	user_id INTEGER NOT NULL,
+	work_id INTEGER NOT NULL,
+	placed TEXT NOT NULL,
+	UNIQUE(user_id, work_id),
+	FOREIGN KEY(user_id) REFERENCES accounts(id),
+	FOREIGN KEY(work_id) REFERENCES works(id)
+);
+
+DROP TABLE IF EXISTS covers;
+CREATE TABLE IF NOT EXISTS covers (
+	ISBN INTEGER NOT NULL PRIMARY KEY,
+	width INTEGER NOT NULL,
+	height INTEGER NOT NULL,
+	uploaded INTEGER NOT NULL,
+	FOREIGN KEY(ISBN) REFERENCES book_info(ISBN)
+);
+
+DROP TABLE IF EXISTS author_redirects;
+CREATE TABLE IF NOT EXISTS author_redirects (
+	old_id INTEGER NOT NULL PRIMARY KEY,
+	new_id INTEGER NOT NULL,
+	FOREIGN KEY(new_id) REFERENCES authors(id)
+);
+
 INSERT INTO book_info
 	(ISBN, name, published)
 VALUES
-	(1499669402, "A Study In Scarlet", "1878"),
-	(0553293354, "Foundation"        , "1998"),
+	(9781499669404, "A Study In Scarlet", "1878"),
+	(9780553293357, "Foundation"        , "1998"),
 	(0000000005, "The Raven"         , "1978"),
 	(0000000006, "The Black Cat"     , "1978");
 
 INSERT INTO books
 	(ISBN)
 VALUES
-	(1499669402), (1499669402), (1499669402),
-	(0553293354), (0000000005), (0000000006),
-	(0000000006), (1499669402), (0553293354);
+	(9781499669404), (9781499669404), (9781499669404),
+	(9780553293357), (0000000005), (0000000006),
+	(0000000006), (9781499669404), (9780553293357);
 
 INSERT INTO authors
-	(id, name)
+	(id, name, born, died)
 VALUES
-	(1, "Edgar Allan Poe"),
-	(2, "Isaac Asimov"),
-	(3, "Arthur Conan Doyle");
+	(1, "Edgar Allan Poe"   , 1809, 1849),
+	(2, "Isaac Asimov"      , 1920, 1992),
+	(3, "Arthur Conan Doyle", 1859, 1930);
+
+INSERT INTO author_names
+	(author_id, name)
+VALUES
+	(1, "Edgar A. Poe"),
+	(2, "Paul French"),
+	(3, "A. Conan Doyle");
 
 INSERT INTO wrote
-	(ISBN, author_id)
+	(ISBN, author_id, role)
+VALUES
+	(0000000005, 1, 'author'),
+	(0000000006, 1, 'author'),
+	(9780553293357, 2, 'author'),
+	(9781499669404, 3, 'author'),
+	(9780553293357, 3, 'editor');
+
+INSERT INTO subjects
+	(id, name, parent_id)
+VALUES
+	(1, 'Fiction'        , NULL),
+	(2, 'Mystery'        , 1),
+	(3, 'Science Fiction', 1),
+	(4, 'Horror'         , 1),
+	(5, 'Poetry'         , NULL);
+
+INSERT INTO book_subjects
+	(ISBN, subject_id)
+VALUES
+	(9781499669404, 2),
+	(9780553293357, 3),
+	(0000000005, 5),
+	(0000000006, 4);
+
+INSERT INTO book_tags
+	(ISBN, tag)
+VALUES
+	(9781499669404, 'sherlock holmes'),
+	(9780553293357, 'galactic empire'),
+	(0000000006, 'short story');
+
+INSERT INTO series
+	(id, name)
+VALUES
+	(1, 'Foundation'),
+	(2, 'Sherlock Holmes');
+
+INSERT INTO series_volumes
+	(series_id, ISBN, volume)
+VALUES
+	(1, 9780553293357, 1),
+	(2, 9781499669404, 1);
+
+INSERT INTO works
+	(id, title)
+VALUES
+	(1, 'Foundation'),
+	(2, 'A Study In Scarlet');
+
+INSERT INTO editions
+	(ISBN, work_id, label)
+VALUES
+	(9780553293357, 1, 'paperback'),
+	(9781499669404, 2, 'paperback');
+
+INSERT INTO roles
+	(id, name)
+VALUES
+	(1, 'patron'),
+	(2, 'circulation clerk'),
+	(3, 'cataloger'),
+	(4, 'administrator');
+
+INSERT INTO role_permissions
+	(role_id, permission)
 VALUES
-	(0000000005, 1),
-	(0000000006, 1),
-	(0553293354, 2),
-	(1499669402, 3),
-	(0553293354, 3);
+	(1, 'reserve'),
+	(2, 'reserve'),
+	(2, 'circulate'),
+	(3, 'reserve'),
+	(3, 'catalog'),
+	(4, 'reserve'),
+	(4, 'circulate'),
+	(4, 'catalog'),
+	(4, 'manage_accounts');
 
 INSERT INTO accounts
-	(name,email,pass_hash,is_worker)
+	(name,email,pass_hash,role_id,verified)
+VALUES
+	('manse','pmanse@lsys.com','a6f85bd2-a2a5-360b-a4e4-13b8eb84b14e',4,true),
+	('manse','pedro@manse.com','a6f85bd2-a2a5-360b-a4e4-13b8eb84b14e',1,true);
+
+INSERT INTO borrow_log
+	(user_id, book_id, borrow_time, return_time)
 VALUES
-	('manse','pmanse@lsys.com','a6f85bd2-a2a5-360b-a4e4-13b8eb84b14e',true),
-	('manse','pedro@manse.com','a6f85bd2-a2a5-360b-a4e4-13b8eb84b14e',false);
+	(1, 1, '2024-01-08', '2024-01-20'),
+	(1, 4, '2024-01-22', '2024-02-10'),
+	(1, 5, '2024-02-12', '2024-02-19'),
+	(2, 2, '2024-01-15', '2024-01-29'),
+	(2, 5, '2024-02-01', '2024-02-08'),
+	(2, 6, '2024-02-09', '2024-02-20');
 
 -- select books to display
 --SELECT
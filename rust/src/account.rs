@@ -0,0 +1,385 @@
+// an account's own settings: its name, email and password, a copy of what is kept about it,
+// and deleting it; deleted accounts stay as an empty row, their loans going to one shared account
+
+use axum::{
+	Form,
+	extract::State,
+	http::header,
+	response::{IntoResponse, Redirect, Response},
+};
+use chrono::NaiveDate;
+use maud::{html, Markup, DOCTYPE};
+use serde_json::json;
+use std::sync::atomic::Ordering;
+use tower_cookies::Cookies;
+use crate::types::*;
+use crate::passwords::Verified;
+use crate::sessions::view_time;
+use crate::{
+	SharedState, ServerState,
+	read_state, read_viewer, make_redirect, login_url, view_error, csrf, passwords, roles, two_factor, verification,
+};
+
+// the account every deleted account's past loans are moved to; not an email, so no one can
+// register it, and like every deleted account it is never loaded
+pub const DELETED_EMAIL: &str = "deleted";
+
+fn now() -> i64 {
+	chrono::Utc::now().timestamp()
+}
+
+// only what every address has, mail delivery tells the rest
+pub fn valid_email(email: &str) -> bool {
+	email.split_once('@').is_some_and(|(user, host)| !user.is_empty() && !host.is_empty())
+		&& !email.chars().any(|chr| chr.is_whitespace() || chr.is_control())
+}
+
+pub async fn display_account(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+) -> Result<Markup, Redirect> {
+	let state = read_state(stt).await;
+	let acc = read_viewer(&state, &cookies).ok_or(make_redirect(login_url("/account")))?;
+	Ok( view_account(&state, &acc, "") )
+}
+
+pub async fn perform_change_name(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+	Form(form): Form<AccountNameForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+	let Some(acc) = read_viewer(&state, &cookies) else {
+		return Ok(make_redirect(login_url("/account")));
+	};
+	let name = form.name.trim();
+	if name.is_empty() {
+		return Err(view_account(&state, &acc, "The name can't be empty"));
+	}
+
+	let result = sqlx::query!(
+		"UPDATE accounts SET name = ? WHERE id = ?",
+		name, acc.uid,
+	).execute(&state.db).await;
+	if let Err(e) = result {
+		return Err(view_account(&state, &acc, &e.to_string()));
+	}
+	Account::update_maps(&mut state, Account{ name: name.to_owned(), ..(*acc).clone() });
+	Ok( make_redirect("/account".to_owned()) )
+}
+
+// a new email has to be confirmed again before reserving, the link goes to it
+pub async fn perform_change_email(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+	Form(form): Form<AccountEmailForm>,
+) -> Result<Redirect, Markup> {
+	let Some(acc) = read_viewer(&*stt.lock().await, &cookies) else {
+		return Ok(make_redirect(login_url("/account")));
+	};
+	let verified = passwords::verify(&form.pass, Some(&acc.pass_hash)).await;
+
+	// read again, the account may have changed while the lock was let go
+	let mut state = stt.lock().await;
+	let Some(acc) = read_viewer(&state, &cookies).filter(|current| current.uid == acc.uid) else {
+		return Ok(make_redirect(login_url("/account")));
+	};
+	let email = form.email.trim();
+	let error = if verified == Verified::Wrong {
+		Some("Wrong password")
+	} else if !valid_email(email) {
+		Some("That isn't an email address")
+	} else if email == acc.email {
+		Some("That is already your email")
+	} else if state.email_to_uid.contains_key(email) {
+		Some("Another account has that email")
+	} else {
+		None
+	};
+	if let Some(error) = error {
+		return Err(view_account(&state, &acc, error));
+	}
+
+	let result = sqlx::query!(
+		"UPDATE accounts SET email = ?, verified = false WHERE id = ?",
+		email, acc.uid,
+	).execute(&state.db).await;
+	if let Err(e) = result {
+		return Err(view_account(&state, &acc, &e.to_string()));
+	}
+	state.email_to_uid.remove(&acc.email);
+	let acc = Account{ email: email.to_owned(), verified: false, ..(*acc).clone() };
+	verification::send_verification(&state, &acc);
+	Account::update_maps(&mut state, acc);
+	Ok( make_redirect("/account".to_owned()) )
+}
+
+// everything kept about the account, as one json file
+pub async fn display_export(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+) -> Result<Response, Redirect> {
+	let state = stt.lock().await;
+	let acc = read_viewer(&state, &cookies).ok_or(make_redirect(login_url("/account")))?;
+
+	let loans = sqlx::query!(
+		r#"SELECT borrow_log.book_id, books.ISBN AS "ISBN?", book_info.name AS "title?",
+			borrow_log.borrow_time, borrow_log.return_time
+		FROM borrow_log
+		LEFT JOIN books ON books.id = borrow_log.book_id
+		LEFT JOIN book_info ON book_info.ISBN = books.ISBN
+		WHERE borrow_log.user_id = ?
+		ORDER BY borrow_log.borrow_time"#,
+		acc.uid,
+	).fetch_all(&state.db).await;
+	let passkeys = sqlx::query!(
+		"SELECT name, created, last_used FROM passkeys WHERE user_id = ? ORDER BY created",
+		acc.uid,
+	).fetch_all(&state.db).await;
+	let (loans, passkeys) = match (loans, passkeys) {
+		(Ok(loans), Ok(passkeys))=>(loans, passkeys),
+		(Err(e), _) | (_, Err(e))=>return Ok(view_error(e.to_string()).into_response()),
+	};
+
+	let mut copies = state.bid_to_book.values()
+		.filter(|book| book.status.get().is_with_viewer(acc.uid).0)
+		.collect::<Vec<_>>();
+	copies.sort_by_key(|book| book.bid);
+	let mut holds = state.wid_to_holds.values()
+		.flatten()
+		.filter(|hold| hold.user_id == acc.uid)
+		.collect::<Vec<_>>();
+	holds.sort_by_key(|hold| hold.id);
+	let mut sessions = state.hash_to_session.values()
+		.filter(|session| session.user_id == acc.uid)
+		.collect::<Vec<_>>();
+	sessions.sort_by_key(|session| session.created);
+	let mut api_tokens = state.hash_to_api_token.values()
+		.filter(|token| token.user_id == acc.uid)
+		.collect::<Vec<_>>();
+	api_tokens.sort_by_key(|token| token.created);
+
+	let export = json!({
+		"exported": view_time(now()),
+		"profile": {
+			"id": acc.uid,
+			"name": acc.name,
+			"email": acc.email,
+			"email_confirmed": acc.verified,
+			"role": state.rid_to_role.get(&acc.role).map(|role| role.name.clone()),
+			"two_factor": two_factor::is_enabled(&state, acc.uid),
+		},
+		"loans": loans.iter().map(|loan| json!({
+			"book_id": loan.book_id,
+			"ISBN": loan.ISBN,
+			"title": loan.title,
+			"borrowed": loan.borrow_time,
+			"returned": loan.return_time,
+		})).collect::<Vec<_>>(),
+		"copies": copies.iter().map(|book| {
+			let until = book.status.get().is_with_viewer(acc.uid).1;
+			json!({
+				"book_id": book.bid,
+				"ISBN": book.ISBN,
+				"title": book.name,
+				"status": book.status.get().to_string(),
+				"until": until.as_ref().map(NaiveDate::to_string),
+			})
+		}).collect::<Vec<_>>(),
+		"holds": holds.iter().map(|hold| json!({
+			"work_id": hold.work_id,
+			"title": state.wid_to_work.get(&hold.work_id).map(|work| work.title.clone()),
+			"placed": hold.placed.to_string(),
+		})).collect::<Vec<_>>(),
+		"sessions": sessions.iter().map(|session| json!({
+			"created": view_time(session.created),
+			"last_seen": view_time(session.last_seen.load(Ordering::Relaxed)),
+			"user_agent": session.user_agent,
+		})).collect::<Vec<_>>(),
+		"passkeys": passkeys.iter().map(|passkey| json!({
+			"name": passkey.name,
+			"created": view_time(passkey.created),
+			"last_used": passkey.last_used.map(view_time),
+		})).collect::<Vec<_>>(),
+		"api_tokens": api_tokens.iter().map(|token| json!({
+			"name": token.name,
+			"scope": token.scope.as_str(),
+			"created": view_time(token.created),
+			"expires": view_time(token.expires),
+		})).collect::<Vec<_>>(),
+		// the library charges no fines, so none are kept; listed so the export says so
+		"fines": [],
+	});
+	Ok( (
+		[
+			(header::CONTENT_TYPE, "application/json"),
+			(header::CONTENT_DISPOSITION, "attachment; filename=\"lsys-account.json\""),
+		],
+		serde_json::to_string_pretty(&export).unwrap_or_default(),
+	).into_response() )
+}
+
+pub async fn perform_delete_account(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+	Form(form): Form<AccountDeleteForm>,
+) -> Result<Redirect, Markup> {
+	let Some(acc) = read_viewer(&*stt.lock().await, &cookies) else {
+		return Ok(make_redirect(login_url("/account")));
+	};
+	let verified = passwords::verify(&form.pass, Some(&acc.pass_hash)).await;
+
+	// read again, the account may have changed while the lock was let go
+	let mut state = stt.lock().await;
+	let Some(acc) = read_viewer(&state, &cookies).filter(|current| current.uid == acc.uid) else {
+		return Ok(make_redirect(login_url("/account")));
+	};
+	if verified == Verified::Wrong {
+		return Err(view_account(&state, &acc, "Wrong password"));
+	}
+	let borrowing = state.bid_to_book.values()
+		.any(|book| book.status.get().is_borrowed() && book.status.get().is_with_viewer(acc.uid).0);
+	if borrowing {
+		return Err(view_account(&state, &acc, "Bring back the copies you have borrowed before deleting your account"));
+	}
+	// someone has to be left able to hand out roles
+	let other_admin = state.uid_to_account.values()
+		.any(|other| other.uid != acc.uid && roles::has_permission(&state, other, Permission::ManageAccounts));
+	if roles::has_permission(&state, &acc, Permission::ManageAccounts) && !other_admin {
+		return Err(view_account(&state, &acc, "Give another account the administrator role before deleting yours"));
+	}
+
+	if let Err(e) = delete_account(&mut state, &acc).await {
+		return Err(view_account(&state, &acc, &e.to_string()));
+	}
+	Ok( make_redirect("/".to_owned()) )
+}
+
+// logins, holds and reservations go; the account row is emptied rather than removed, as
+// books.user_id may still point at it, and its past loans are moved to the shared account,
+// so they stay counted without saying whose they were, or which of them were one patron's
+async fn delete_account(state: &mut ServerState, acc: &Account) -> Result<(), sqlx::Error> {
+	let uid = acc.uid;
+	let email = acc.email.trim().to_lowercase();
+	// not an email, so no account can have it
+	let tombstone = format!("deleted-{uid}");
+	let now = now();
+
+	let mut tx = state.db.begin().await?;
+	sqlx::query!("DELETE FROM sessions WHERE user_id = ?", uid).execute(&mut *tx).await?;
+	sqlx::query!("DELETE FROM password_resets WHERE user_id = ?", uid).execute(&mut *tx).await?;
+	sqlx::query!("DELETE FROM totp WHERE user_id = ?", uid).execute(&mut *tx).await?;
+	sqlx::query!("DELETE FROM recovery_codes WHERE user_id = ?", uid).execute(&mut *tx).await?;
+	sqlx::query!("DELETE FROM passkeys WHERE user_id = ?", uid).execute(&mut *tx).await?;
+	sqlx::query!("DELETE FROM api_tokens WHERE user_id = ?", uid).execute(&mut *tx).await?;
+	sqlx::query!("DELETE FROM holds WHERE user_id = ?", uid).execute(&mut *tx).await?;
+	sqlx::query!("DELETE FROM lockouts WHERE kind = 'email' AND subject = ?", email).execute(&mut *tx).await?;
+	sqlx::query!(
+		"UPDATE books SET user_id = NULL, time = NULL, is_borrow = NULL WHERE user_id = ? AND is_borrow = false",
+		uid,
+	).execute(&mut *tx).await?;
+	sqlx::query!(
+		"UPDATE accounts SET name = '', email = ?, pass_hash = '', verified = false, deleted = ? WHERE id = ?",
+		tombstone, now, uid,
+	).execute(&mut *tx).await?;
+	sqlx::query!(
+		"INSERT OR IGNORE INTO accounts (name, email, pass_hash, verified, deleted) VALUES ('', ?, '', false, ?)",
+		DELETED_EMAIL, now,
+	).execute(&mut *tx).await?;
+	let shared = sqlx::query!(
+		"SELECT id FROM accounts WHERE email = ?", DELETED_EMAIL,
+	).fetch_one(&mut *tx).await?.id;
+	// a loan the shared account already has would be the same copy borrowed the same second,
+	// which can't happen, so any the UPDATE has to leave behind are dropped
+	sqlx::query!(
+		"UPDATE OR IGNORE borrow_log SET user_id = ? WHERE user_id = ?", shared, uid,
+	).execute(&mut *tx).await?;
+	sqlx::query!("DELETE FROM borrow_log WHERE user_id = ?", uid).execute(&mut *tx).await?;
+	tx.commit().await?;
+
+	state.uid_to_account.remove(&uid);
+	state.email_to_uid.remove(&acc.email);
+	state.hash_to_session.retain(|_, session| session.user_id != uid);
+	state.token_to_pending_login.retain(|_, pending| pending.uid != uid);
+	state.hash_to_api_token.retain(|_, token| token.user_id != uid);
+	state.two_factor_uids.remove(&uid);
+	state.email_to_failures.remove(&email);
+	for holds in state.wid_to_holds.values_mut() {
+		holds.retain(|hold| hold.user_id != uid);
+	}
+	for book in state.bid_to_book.values() {
+		if book.status.get().is_reserved() && book.status.get().is_with_viewer(uid).0 {
+			book.status.set(BorrowStatus::Avaliable);
+		}
+	}
+	Ok(())
+}
+
+pub fn view_account(state: &ServerState, acc: &Account, error: &str) -> Markup {
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		title { "LSYS - Account" }
+	} body {
+		p style="color: red;" { (error) }
+		nav {
+			a href="/dashboard" { "Dashboard" }
+		}
+
+		h1 { "Your account" }
+		fieldset {
+			legend { "Name" }
+			form method="POST" action="/account/name" {
+				(csrf::field())
+				label for="account-name" { "name:" }
+				input id="account-name" name="name" type="text" value=(acc.name) {}
+				br {}
+				button { "Save" }
+			}
+		}
+
+		fieldset {
+			legend { "Email" }
+			p {
+				(acc.email)
+				@if !acc.verified { " (not confirmed yet)" }
+			}
+			form method="POST" action="/account/email" {
+				(csrf::field())
+				label for="account-email" { "new email:" }
+				input id="account-email" name="email" type="email" placeholder="email" {}
+				br {}
+				label for="account-email-pass" { "password:" }
+				input id="account-email-pass" name="pass" type="password" placeholder="password" {}
+				br {}
+				button { "Change email" }
+			}
+			@if roles::has_permission(state, acc, Permission::Reserve) {
+				p { "A new email has to be confirmed again before you can reserve." }
+			}
+		}
+
+		(passwords::view_change_password())
+
+		fieldset {
+			legend { "Your data" }
+			p { "Everything kept about your account: your profile, loans, copies you have, holds and logins." }
+			a href="/account/export" { "Download as JSON" }
+		}
+
+		fieldset {
+			legend { "Delete account" }
+			p {
+				"Your logins, holds and reservations are removed and you can't log in again. "
+				"Past loans stay in the library's history without your name or email."
+			}
+			form method="POST" action="/account/delete" {
+				(csrf::field())
+				label for="account-delete-pass" { "password:" }
+				input id="account-delete-pass" name="pass" type="password" placeholder="password" {}
+				br {}
+				button { "Delete my account" }
+			}
+		}
+	} }
+}
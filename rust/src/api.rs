@@ -0,0 +1,28 @@
+// JSON for scripts, reached with an API token (or a logged in browser) through roles::Authorized
+
+use axum::{
+	Json,
+	extract::State,
+};
+use maud::Markup;
+use crate::types::*;
+use crate::roles::{Authorized, Circulate};
+use crate::{SharedState, view_error};
+
+// every loan in borrow_log, latest first
+pub async fn display_loans(
+	State(stt): State<SharedState>,
+	_: Authorized<Circulate>,
+) -> Result<Json<Vec<Loan>>, Markup> {
+	let state = stt.lock().await;
+	let loans = sqlx::query_as!(
+		Loan,
+		r#"SELECT borrow_log.user_id, accounts.email AS "email?", borrow_log.book_id,
+			books.ISBN AS "ISBN?", borrow_log.borrow_time, borrow_log.return_time
+		FROM borrow_log
+		LEFT JOIN accounts ON accounts.id = borrow_log.user_id AND accounts.deleted IS NULL
+		LEFT JOIN books ON books.id = borrow_log.book_id
+		ORDER BY borrow_log.borrow_time DESC"#,
+	).fetch_all(&state.db).await.map_err(|e| view_error(e.to_string()))?;
+	Ok( Json(loans) )
+}
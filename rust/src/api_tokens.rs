@@ -0,0 +1,296 @@
+// personal tokens letting scripts act as their account without its password or cookie;
+// roles::Authorized takes them from an "Authorization: Bearer" header
+
+use axum::{
+	Form,
+	extract::{Request, State},
+	http::{header, HeaderMap, StatusCode},
+	middleware::Next,
+	response::{IntoResponse, Redirect, Response},
+};
+use maud::{html, Markup, DOCTYPE};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use tower_cookies::Cookies;
+use crate::types::*;
+use crate::sessions::{hash_token, new_token, view_time};
+use crate::{
+	SharedState, ServerState,
+	read_state, read_viewer, make_redirect, login_url, view_error, csrf,
+};
+
+// tells the tokens apart from other secrets when one turns up somewhere it shouldn't
+const TOKEN_PREFIX: &str = "lsys_";
+const MAX_NAME: usize = 64;
+const EXPIRY_DAYS: [i64; 4] = [7, 30, 90, 365];
+const DAY_SECS: i64 = 24 * 60 * 60;
+// last_used is only written back when it moved at least this much
+const TOUCH_SECS: i64 = 60;
+
+fn now() -> i64 {
+	chrono::Utc::now().timestamp()
+}
+
+pub async fn load(state: &mut ServerState) {
+	let now = now();
+	sqlx::query!(
+		"DELETE FROM api_tokens WHERE expires < ?",
+		now,
+	).execute(&state.db).await.expect("can't delete expired api tokens");
+
+	let tokens = sqlx::query!(
+		"SELECT id, user_id, token_hash, name, scope, created, expires, last_used FROM api_tokens",
+	).fetch_all(&state.db).await.expect("can't parse row from api_tokens");
+
+	for token in tokens {
+		let Some(scope) = TokenScope::from_db(&token.scope) else { continue };
+		state.hash_to_api_token.insert(token.token_hash, Arc::new(ApiToken{
+			id: token.id,
+			user_id: token.user_id,
+			name: token.name,
+			scope,
+			created: token.created,
+			expires: token.expires,
+			last_used: AtomicI64::new(token.last_used.unwrap_or(0)),
+		}));
+	}
+}
+
+// the token of an "Authorization: Bearer" header, if the request has one
+pub fn bearer(headers: &HeaderMap) -> Option<&str> {
+	let authorization = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+	let (scheme, token) = authorization.split_once(' ')?;
+	scheme.eq_ignore_ascii_case("bearer").then_some(token.trim())
+}
+
+// the token, if it exists and hasn't expired; using it counts for last_used
+pub fn read_token(state: &ServerState, token: &str) -> Option<Arc<ApiToken>> {
+	let api_token = state.hash_to_api_token.get(&hash_token(token))?;
+	let now = now();
+	if api_token.expires < now {
+		return None;
+	}
+
+	let last_used = api_token.last_used.load(Ordering::Relaxed);
+	if now - last_used >= TOUCH_SECS
+		&& api_token.last_used.compare_exchange(last_used, now, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+	{
+		let (db, id) = (state.db.clone(), api_token.id);
+		tokio::spawn(async move {
+			let result = sqlx::query!(
+				"UPDATE api_tokens SET last_used = ? WHERE id = ?",
+				now, id,
+			).execute(&db).await;
+			if let Err(e) = result {
+				eprintln!("can't update api token {id}: {e}");
+			}
+		});
+	}
+	Some(api_token.clone())
+}
+
+// a request with a valid token is the script's and never a browser session's: its cookies are
+// dropped before anything reads them, so pages that only take a logged in browser see nobody,
+// and roles::Authorized is left as the only way in; csrf::protect skips the marked requests
+pub async fn strip_cookies(
+	State(stt): State<SharedState>,
+	mut request: Request,
+	next: Next,
+) -> Response {
+	let accepted = match bearer(request.headers()) {
+		Some(token)=>{
+			let state = stt.lock().await;
+			read_token(&state, token)
+		}
+		None=>None,
+	};
+	if let Some(token) = accepted {
+		request.headers_mut().remove(header::COOKIE);
+		request.extensions_mut().insert(BearerToken(token));
+	}
+	next.run(request).await
+}
+
+// for a bearer token that isn't (or no longer) valid
+pub fn unauthorized() -> Response {
+	let error = "That API token isn't valid, it may have expired or been revoked".to_owned();
+	(StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")], view_error(error)).into_response()
+}
+
+pub async fn display_api_tokens(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+) -> Result<Markup, Redirect> {
+	let state = read_state(stt).await;
+	let acc = read_viewer(&state, &cookies).ok_or(make_redirect(login_url("/tokens")))?;
+	Ok( view_api_tokens(&state, &acc, None, "") )
+}
+
+// every token of the account, for when whoever made them may not have been its owner
+pub async fn revoke_all(state: &mut ServerState, uid: Uid) -> Result<(), String> {
+	sqlx::query!(
+		"DELETE FROM api_tokens WHERE user_id = ?", uid,
+	).execute(&state.db).await.map_err(|e| e.to_string())?;
+	state.hash_to_api_token.retain(|_, token| token.user_id != uid);
+	Ok(())
+}
+
+// the new token is shown this once, only its hash is kept
+pub async fn perform_new_api_token(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+	Form(form): Form<NewApiTokenForm>,
+) -> Result<Markup, Redirect> {
+	let mut state = stt.lock().await;
+	let acc = read_viewer(&state, &cookies).ok_or(make_redirect(login_url("/tokens")))?;
+	match new_api_token(&mut state, &acc, &form).await {
+		Ok(token)=>Ok( view_api_tokens(&state, &acc, Some(&token), "") ),
+		Err(e)=>Ok( view_api_tokens(&state, &acc, None, &e) ),
+	}
+}
+
+async fn new_api_token(state: &mut ServerState, acc: &Account, form: &NewApiTokenForm) -> Result<String, String> {
+	let scope = TokenScope::from_db(&form.scope).ok_or("Pick what the token may do".to_owned())?;
+	if !EXPIRY_DAYS.contains(&form.days) {
+		return Err("Pick when the token expires".to_owned());
+	}
+	let name = match form.name.trim() {
+		""=>return Err("Name the token, e.g. after the script using it".to_owned()),
+		name=>name.chars().take(MAX_NAME).collect::<String>(),
+	};
+
+	let token = format!("{TOKEN_PREFIX}{}", new_token());
+	let token_hash = hash_token(&token);
+	let created = now();
+	let expires = created + form.days * DAY_SECS;
+	let scope_str = scope.as_str();
+	let result = sqlx::query!(
+		"INSERT INTO api_tokens (user_id, token_hash, name, scope, created, expires) VALUES (?, ?, ?, ?, ?, ?)",
+		acc.uid, token_hash, name, scope_str, created, expires,
+	).execute(&state.db).await.map_err(|e| e.to_string())?;
+
+	state.hash_to_api_token.insert(token_hash, Arc::new(ApiToken{
+		id: result.last_insert_rowid(),
+		user_id: acc.uid,
+		name,
+		scope,
+		created,
+		expires,
+		last_used: AtomicI64::new(0),
+	}));
+	Ok(token)
+}
+
+// only revokes tokens of the account, the id comes from a form
+pub async fn perform_revoke_api_token(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+	Form(form): Form<ApiTokenForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+	let Some(acc) = read_viewer(&state, &cookies) else {
+		return Ok(make_redirect(login_url("/tokens")));
+	};
+
+	let result = sqlx::query!(
+		"DELETE FROM api_tokens WHERE id = ? AND user_id = ?",
+		form.id, acc.uid,
+	).execute(&state.db).await;
+	if let Err(e) = result {
+		return Err(view_api_tokens(&state, &acc, None, &e.to_string()));
+	}
+	state.hash_to_api_token.retain(|_, token| token.user_id != acc.uid || token.id != form.id);
+	Ok( make_redirect("/tokens".to_owned()) )
+}
+
+fn view_api_tokens(state: &ServerState, acc: &Account, new_token: Option<&str>, error: &str) -> Markup {
+	let mut tokens = state.hash_to_api_token.values()
+		.filter(|token| token.user_id == acc.uid)
+		.collect::<Vec<_>>();
+	tokens.sort_by_key(|token| token.created);
+	let now = now();
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		title { "LSYS - API tokens" }
+	} body {
+		p style="color: red;" { (error) }
+		nav {
+			a href="/dashboard" { "Dashboard" }
+		}
+
+		h1 { "API tokens" }
+		p {
+			"Scripts can act as you by sending a token in an "
+			code { "Authorization: Bearer" }
+			" header, with what your role allows, limited to what the token may do."
+		}
+		@if let Some(new_token) = new_token {
+			p { b { "Copy your new token now, it won't be shown again:" } }
+			pre { (new_token) }
+		}
+		@if tokens.is_empty() {
+			p { "You have no API tokens." }
+		} @else {
+			table {
+				thead { tr {
+					td { "Name" }
+					td { "May" }
+					td { "Created" }
+					td { "Expires" }
+					td { "Last used" }
+					td {}
+				} }
+				tbody {
+					@for token in &tokens { tr {
+						td { (token.name) }
+						td { (token.scope.describe()) }
+						td { (view_time(token.created)) }
+						td {
+							(view_time(token.expires))
+							@if token.expires < now { b { " (expired)" } }
+						}
+						td {
+							@match token.last_used.load(Ordering::Relaxed) {
+								0=>{ "never" }
+								last_used=>{ (view_time(last_used)) }
+							}
+						}
+						td {
+							form method="POST" action="/tokens/revoke" {
+								(csrf::field())
+								input type="hidden" name="id" value=(token.id) {}
+								button { "Revoke" }
+							}
+						}
+					} }
+				}
+			}
+		}
+
+		fieldset {
+			legend { "New token" }
+			form method="POST" action="/tokens/new" {
+				(csrf::field())
+				label for="token-name" { "name:" }
+				input id="token-name" name="name" type="text" placeholder="e.g. loans report" maxlength=(MAX_NAME) {}
+				br {}
+				label for="token-scope" { "may:" }
+				select id="token-scope" name="scope" {
+					@for scope in TokenScope::ALL {
+						option value=(scope.as_str()) { (scope.describe()) }
+					}
+				}
+				br {}
+				label for="token-days" { "expires in:" }
+				select id="token-days" name="days" {
+					@for days in EXPIRY_DAYS {
+						option value=(days) selected[days == 90] { (days) " days" }
+					}
+				}
+				br {}
+				button { "Create" }
+			}
+		}
+	} }
+}
@@ -0,0 +1,676 @@
+// author pages and authority data, the roles linking authors to titles,
+// and finding and merging authors entered twice under different spellings
+
+use axum::{
+	Form,
+	extract::State,
+	extract::Query,
+	response::Redirect,
+};
+use maud::{html, Markup, DOCTYPE};
+use std::sync::Arc;
+use tower_cookies::Cookies;
+use crate::types::*;
+use crate::roles::{self, Authorized, Catalog};
+use crate::catalog::normalize_name;
+use crate::{
+	SharedState, ServerState,
+	read_state, read_viewer, make_redirect,
+	view_404, view_error, view_books_table, title_copy, csrf,
+};
+
+// jaro-winkler similarity of normalized names above which they are reported as duplicates
+const SPELLING_THRESHOLD: f64 = 0.93;
+
+pub async fn load(state: &mut ServerState) {
+	let redirects = sqlx::query!(
+		"SELECT old_id, new_id FROM author_redirects",
+	).fetch_all(&state.db).await.expect("can't parse row from author_redirects");
+
+	state.aid_redirects = redirects.into_iter()
+		.map(|redirect| (redirect.old_id, redirect.new_id))
+		.collect();
+}
+
+// why two normalized names may be the same person, None when they don't look alike
+fn names_match(name: &str, other: &str) -> Option<String> {
+	if name == other {
+		return Some("same name".to_owned());
+	}
+	let words = name.split(' ').collect::<Vec<_>>();
+	let other_words = other.split(' ').collect::<Vec<_>>();
+
+	let mut sorted = words.clone();
+	let mut other_sorted = other_words.clone();
+	sorted.sort_unstable();
+	other_sorted.sort_unstable();
+	if sorted == other_sorted {
+		return Some("same words in another order".to_owned());
+	}
+
+	// "a c doyle", "arthur doyle" and "sir arthur conan doyle" against "arthur conan doyle"
+	if words.last() == other_words.last() {
+		let initial = |words: &[&str]| words.first().and_then(|word| word.chars().next());
+		if initial(&words) == initial(&other_words)
+			|| words.iter().all(|word| other_words.contains(word))
+			|| other_words.iter().all(|word| words.contains(word)) {
+			return Some("same family name".to_owned());
+		}
+	}
+
+	let score = strsim::jaro_winkler(name, other);
+	(score >= SPELLING_THRESHOLD).then(|| format!("similar spelling ({:.0}%)", score*100.0))
+}
+
+// the name and every alternate form of it, normalized
+fn normalized_names(author: &Author) -> Vec<String> {
+	std::iter::once(&author.name)
+		.chain(&author.alt_names)
+		.map(|name| normalize_name(name))
+		.collect()
+}
+
+// whether name is the author's name or one of its alternate forms
+pub fn is_named(author: &Author, name: &str) -> bool {
+	normalized_names(author).contains(&normalize_name(name))
+}
+
+// authors already in the catalog that may be the same person as name, exact matches first
+pub fn similar_authors(state: &ServerState, name: &str) -> Vec<Arc<Author>> {
+	let name = normalize_name(name);
+	let mut similar = state.aid_to_authors.values()
+		.filter(|author| {
+			normalized_names(author).iter().any(|known| names_match(&name, known).is_some())
+		})
+		.cloned()
+		.collect::<Vec<_>>();
+	similar.sort_by_key(|author| (!normalized_names(author).contains(&name), author.id));
+	similar
+}
+
+// every pair of likely duplicates, compared pairwise as catalogs have few authors
+fn duplicate_authors(state: &ServerState) -> Vec<(Arc<Author>, Arc<Author>, String)> {
+	let mut authors = state.aid_to_authors.values()
+		.map(|author| (normalized_names(author), author))
+		.collect::<Vec<_>>();
+	authors.sort_by_key(|(_, author)| author.id);
+
+	let mut pairs = Vec::new();
+	for (at, (names, author)) in authors.iter().enumerate() {
+		for (other_names, other) in &authors[at+1..] {
+			let reason = names.iter()
+				.flat_map(|name| other_names.iter().map(move |other_name| (name, other_name)))
+				.find_map(|(name, other_name)| names_match(name, other_name));
+			if let Some(reason) = reason {
+				pairs.push((Arc::clone(author), Arc::clone(other), reason));
+			}
+		}
+	}
+	pairs
+}
+
+// the titles an author contributed to, with the role of each
+pub fn author_titles(state: &ServerState, aid: Aid) -> Vec<(ISBN, ContributorRole)> {
+	let mut titles = state.ISBN_to_authors.iter()
+		.flat_map(|(isbn, contributors)| {
+			contributors.iter()
+				.filter(|contributor| contributor.author.id == aid)
+				.map(|contributor| (*isbn, contributor.role))
+		})
+		.collect::<Vec<_>>();
+	titles.sort_unstable_by_key(|(isbn, role)| (*isbn, role.as_str()));
+	titles
+}
+
+// Book.authors only has the names of contributors in the author role
+fn refresh_book_authors(state: &mut ServerState, isbns: &[ISBN]) {
+	for book in state.bid_to_book.values_mut().filter(|book| isbns.contains(&book.ISBN)) {
+		book.authors = state.ISBN_to_authors.get(&book.ISBN)
+			.map(Vec::as_slice)
+			.unwrap_or_default()
+			.iter()
+			.filter(|contributor| contributor.role == ContributorRole::Author)
+			.map(|contributor| contributor.author.name.clone())
+			.collect();
+	}
+}
+
+// swaps in a changed author everywhere the old one is shared
+fn replace_author(state: &mut ServerState, author: Author) {
+	let author = Arc::new(author);
+	let mut changed = Vec::new();
+	for (isbn, contributors) in state.ISBN_to_authors.iter_mut() {
+		for contributor in contributors.iter_mut().filter(|contributor| contributor.author.id == author.id) {
+			contributor.author = Arc::clone(&author);
+			changed.push(*isbn);
+		}
+	}
+	state.aid_to_authors.insert(author.id, author);
+	refresh_book_authors(state, &changed);
+}
+
+// moves every title of from to into, keeping from's names as alternate names of into,
+// and leaves a redirect in place of from
+async fn merge_authors(state: &mut ServerState, from: &Author, into: &Author) -> Result<(), sqlx::Error> {
+	let mut merged = into.clone();
+	for name in std::iter::once(&from.name).chain(&from.alt_names) {
+		if *name != merged.name && !merged.alt_names.contains(name) {
+			merged.alt_names.push(name.clone());
+		}
+	}
+	merged.alt_names.sort();
+	merged.born = merged.born.or(from.born);
+	merged.died = merged.died.or(from.died);
+	let (from, into) = (from.id, into.id);
+
+	let mut tx = state.db.begin().await?;
+	sqlx::query!(
+		"INSERT OR IGNORE INTO wrote (author_id, ISBN, role) SELECT ?, ISBN, role FROM wrote WHERE author_id = ?",
+		into, from,
+	).execute(&mut *tx).await?;
+	sqlx::query!(
+		"DELETE FROM wrote WHERE author_id = ?", from,
+	).execute(&mut *tx).await?;
+	sqlx::query!(
+		"DELETE FROM author_names WHERE author_id = ?", from,
+	).execute(&mut *tx).await?;
+	for name in &merged.alt_names {
+		sqlx::query!(
+			"INSERT OR IGNORE INTO author_names (author_id, name) VALUES (?, ?)", into, name,
+		).execute(&mut *tx).await?;
+	}
+	sqlx::query!(
+		"UPDATE authors SET born = ?, died = ? WHERE id = ?", merged.born, merged.died, into,
+	).execute(&mut *tx).await?;
+	sqlx::query!(
+		"UPDATE author_redirects SET new_id = ? WHERE new_id = ?", into, from,
+	).execute(&mut *tx).await?;
+	sqlx::query!(
+		"INSERT INTO author_redirects (old_id, new_id) VALUES (?, ?)", from, into,
+	).execute(&mut *tx).await?;
+	sqlx::query!(
+		"DELETE FROM authors WHERE id = ?", from,
+	).execute(&mut *tx).await?;
+	tx.commit().await?;
+
+	// the maps are changed together while the state is locked, so nobody sees half a merge
+	replace_author(state, merged);
+	let into_author = Arc::clone(&state.aid_to_authors[&into]);
+	state.aid_to_authors.remove(&from);
+	let mut changed = Vec::new();
+	for (isbn, contributors) in state.ISBN_to_authors.iter_mut() {
+		if !contributors.iter().any(|contributor| contributor.author.id == from) {
+			continue;
+		}
+		let kept = contributors.iter()
+			.filter(|contributor| contributor.author.id == into)
+			.map(|contributor| contributor.role)
+			.collect::<Vec<_>>();
+		contributors.retain(|contributor| contributor.author.id != from || !kept.contains(&contributor.role));
+		for contributor in contributors.iter_mut().filter(|contributor| contributor.author.id == from) {
+			contributor.author = Arc::clone(&into_author);
+		}
+		changed.push(*isbn);
+	}
+	refresh_book_authors(state, &changed);
+	for redirect in state.aid_redirects.values_mut().filter(|redirect| **redirect == from) {
+		*redirect = into;
+	}
+	state.aid_redirects.insert(from, into);
+	Ok(())
+}
+
+pub async fn display_author(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+	Query(aid): Query<AuthorParam>,
+) -> Result<Markup, Redirect> {
+	let state = read_state(stt).await;
+	let acc = read_viewer(&state, &cookies);
+
+	// merged away authors send their old links on to the author they were merged into
+	if let Some(into) = state.aid_redirects.get(&aid.aid) {
+		return Err(Redirect::permanent(&format!("/author?aid={into}")));
+	}
+	Ok( match state.aid_to_authors.get(&aid.aid) {
+		Some(author)=>view_author(&state, author, roles::can(&state, acc.as_deref(), Permission::Catalog), ""),
+		None=>view_404(format!("/author?aid={}", aid.aid)),
+	} )
+}
+
+pub async fn display_duplicate_authors(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+) -> Result<Markup, Redirect> {
+	let state = read_state(stt).await;
+
+	Ok( view_duplicate_authors(&state, "") )
+}
+
+pub async fn perform_merge_authors(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<AuthorMergeForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	if form.from == form.into {
+		return Err(view_duplicate_authors(&state, "Can't merge an author into itself"));
+	}
+	let (Some(from), Some(into)) = (
+		state.aid_to_authors.get(&form.from).cloned(),
+		state.aid_to_authors.get(&form.into).cloned(),
+	) else {
+		return Err(view_duplicate_authors(&state, "No such author, it may have been merged already"));
+	};
+
+	if let Err(e) = merge_authors(&mut state, &from, &into).await {
+		return Err(view_duplicate_authors(&state, &e.to_string()));
+	}
+	Ok( make_redirect(format!("/author?aid={}", form.into)) )
+}
+
+fn author_redirect(aid: Aid) -> Redirect {
+	make_redirect(format!("/author?aid={aid}"))
+}
+
+fn book_redirect(bid: Option<Bid>) -> Redirect {
+	match bid {
+		Some(bid)=>make_redirect(format!("/book?bid={bid}")),
+		None=>make_redirect("/".to_owned()),
+	}
+}
+
+pub async fn perform_update_authority(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<AuthorityForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	let Some(author) = state.aid_to_authors.get(&form.aid) else {
+		return Err(view_404(format!("/author?aid={}", form.aid)));
+	};
+	if let (Some(born), Some(died)) = (form.born, form.died) {
+		if died < born {
+			return Err(view_author(&state, author, true, "Can't have died before being born"));
+		}
+	}
+
+	sqlx::query!(
+		"UPDATE authors SET born = ?, died = ? WHERE id = ?", form.born, form.died, form.aid,
+	).execute(&state.db).await.map_err(|e| view_error(e.to_string()))?;
+	let mut author = (**author).clone();
+	author.born = form.born;
+	author.died = form.died;
+	replace_author(&mut state, author);
+	Ok( author_redirect(form.aid) )
+}
+
+pub async fn perform_add_alt_name(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<AltNameForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	let Some(author) = state.aid_to_authors.get(&form.aid) else {
+		return Err(view_404(format!("/author?aid={}", form.aid)));
+	};
+	let name = form.name.trim();
+	if name.is_empty() || name == author.name || author.alt_names.iter().any(|known| known == name) {
+		return Err(view_author(&state, author, true, "That name is empty or already known"));
+	}
+
+	sqlx::query!(
+		"INSERT INTO author_names (author_id, name) VALUES (?, ?)", form.aid, name,
+	).execute(&state.db).await.map_err(|e| view_error(e.to_string()))?;
+	let mut author = (**author).clone();
+	author.alt_names.push(name.to_owned());
+	author.alt_names.sort();
+	replace_author(&mut state, author);
+	Ok( author_redirect(form.aid) )
+}
+
+pub async fn perform_remove_alt_name(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<AltNameForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	let Some(author) = state.aid_to_authors.get(&form.aid) else {
+		return Err(view_404(format!("/author?aid={}", form.aid)));
+	};
+	sqlx::query!(
+		"DELETE FROM author_names WHERE author_id = ? AND name = ?", form.aid, form.name,
+	).execute(&state.db).await.map_err(|e| view_error(e.to_string()))?;
+	let mut author = (**author).clone();
+	author.alt_names.retain(|name| *name != form.name);
+	replace_author(&mut state, author);
+	Ok( author_redirect(form.aid) )
+}
+
+// links a title to the author with that name, or to a new author when there isn't one
+pub async fn perform_add_contributor(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<ContributorForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	let name = form.name.trim();
+	if name.is_empty() {
+		return Err(view_error("The contributor needs a name".to_owned()));
+	}
+	if title_copy(&state, form.ISBN).is_none() {
+		return Err(view_error(format!("No title with ISBN {}", form.ISBN)));
+	}
+
+	let known = state.aid_to_authors.values()
+		.find(|author| author.name == name)
+		.or_else(|| {
+			state.aid_to_authors.values()
+				.filter(|author| is_named(author, name))
+				.min_by_key(|author| author.id)
+		})
+		.cloned();
+	let author = match known {
+		Some(author)=>author,
+		None=>{
+			let result = sqlx::query!(
+				"INSERT INTO authors (name) VALUES (?)", name,
+			).execute(&state.db).await.map_err(|e| view_error(e.to_string()))?;
+			let author = Arc::new(Author{
+				id: result.last_insert_rowid() as Aid,
+				name: name.to_owned(),
+				born: None,
+				died: None,
+				alt_names: Vec::new(),
+			});
+			state.aid_to_authors.insert(author.id, Arc::clone(&author));
+			author
+		}
+	};
+
+	let role = form.role.as_str();
+	let linked = sqlx::query!(
+		"INSERT OR IGNORE INTO wrote (author_id, ISBN, role) VALUES (?, ?, ?)", author.id, form.ISBN, role,
+	).execute(&state.db).await.map_err(|e| view_error(e.to_string()))?.rows_affected() > 0;
+	if linked {
+		state.ISBN_to_authors.entry(form.ISBN).or_default().push(Contributor{
+			author,
+			role: form.role,
+		});
+		refresh_book_authors(&mut state, &[form.ISBN]);
+	}
+	Ok( book_redirect(form.bid) )
+}
+
+pub async fn perform_remove_contributor(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<RemoveContributorForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	let role = form.role.as_str();
+	sqlx::query!(
+		"DELETE FROM wrote WHERE author_id = ? AND ISBN = ? AND role = ?", form.aid, form.ISBN, role,
+	).execute(&state.db).await.map_err(|e| view_error(e.to_string()))?;
+	if let Some(contributors) = state.ISBN_to_authors.get_mut(&form.ISBN) {
+		contributors.retain(|contributor| contributor.author.id != form.aid || contributor.role != form.role);
+	}
+	refresh_book_authors(&mut state, &[form.ISBN]);
+	Ok( book_redirect(form.bid) )
+}
+
+// "1809-1849", "born 1920", or nothing when neither year is known
+fn view_lifespan(author: &Author) -> Markup {
+	html! {
+		@match (author.born, author.died) {
+			(Some(born), Some(died))=>{ (born) "–" (died) },
+			(Some(born), None)=>{ "born " (born) },
+			(None, Some(died))=>{ "died " (died) },
+			(None, None)=>{},
+		}
+	}
+}
+
+// links to each contributor of a title with their role, by name when it has no author rows
+pub fn view_author_links(state: &ServerState, book: &Book) -> Markup {
+	html! {
+		@match state.ISBN_to_authors.get(&book.ISBN) {
+			Some(contributors)=>{
+				@for (at, contributor) in contributors.iter().enumerate() {
+					@if at > 0 { ", " }
+					a href={"/author?aid=" (contributor.author.id)} { (contributor.author.name) }
+					@if contributor.role != ContributorRole::Author {
+						{" (" (contributor.role) ")"}
+					}
+				}
+			},
+			None=>{ (book.authors.join(", ")) },
+		}
+	}
+}
+
+fn view_role_options(selected: ContributorRole) -> Markup {
+	html! {
+		@for role in ContributorRole::ALL {
+			option value=(role) selected[role == selected] { (role) }
+		}
+	}
+}
+
+// contributor controls on a book page, only shown to workers
+pub fn view_book_contributors(state: &ServerState, book: &Book, can_catalog: bool) -> Markup {
+	let contributors = state.ISBN_to_authors.get(&book.ISBN).map(Vec::as_slice).unwrap_or_default();
+	html! {
+		@if can_catalog {
+			section id="contributors" {
+				h3 { "Contributors" }
+				ul {
+					@for contributor in contributors {
+						li {
+							a href={"/author?aid=" (contributor.author.id)} { (contributor.author.name) }
+							{" (" (contributor.role) ") "}
+							form method="POST" action="/contributors/remove" style="display: inline;" {
+								(csrf::field())
+								input type="hidden" name="ISBN" value=(book.ISBN) {}
+								input type="hidden" name="aid" value=(contributor.author.id) {}
+								input type="hidden" name="role" value=(contributor.role) {}
+								input type="hidden" name="bid" value=(book.bid) {}
+								button { "Remove" }
+							}
+						}
+					}
+				}
+				form method="POST" action="/contributors/add" {
+					(csrf::field())
+					input type="hidden" name="ISBN" value=(book.ISBN) {}
+					input type="hidden" name="bid" value=(book.bid) {}
+					input name="name" type="text" placeholder="name" {}
+					select name="role" { (view_role_options(ContributorRole::Author)) }
+					button { "Add contributor" }
+				}
+			}
+		}
+	}
+}
+
+fn view_author(state: &ServerState, author: &Author, can_catalog: bool, error: &str) -> Markup {
+	let titles = author_titles(state, author.id);
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		title { {"LSYS - " (author.name)} }
+	} body {
+		p style="color: red;" { (error) }
+		h1 { (author.name) }
+		p { (view_lifespan(author)) }
+		@if !author.alt_names.is_empty() {
+			p { {"Also known as: " (author.alt_names.join("; "))} }
+		}
+		a href="/" { "Catalog" }
+		@if can_catalog {
+			" | " a href="/authors/duplicates" { "Duplicate authors" }
+		}
+
+		@for role in ContributorRole::ALL {
+			@let books = titles.iter()
+				.filter(|(_, title_role)| *title_role == role)
+				.filter_map(|(isbn, _)| title_copy(state, *isbn).cloned())
+				.collect::<Vec<_>>();
+			@if !books.is_empty() {
+				h2 { {"As " (role)} }
+				(view_books_table(state, &books))
+			}
+		}
+
+		@if can_catalog {
+			fieldset {
+				legend { "Authority" }
+				form method="POST" action="/author/authority" {
+					(csrf::field())
+					input type="hidden" name="aid" value=(author.id) {}
+					label for="author-born" { "born:" }
+					input id="author-born" name="born" type="number" value=[author.born] {}
+					br {}
+					label for="author-died" { "died:" }
+					input id="author-died" name="died" type="number" value=[author.died] {}
+					br {}
+					button { "Save" }
+				}
+			}
+			fieldset {
+				legend { "Alternate names" }
+				ul {
+					@for name in &author.alt_names {
+						li {
+							(name) " "
+							form method="POST" action="/author/names/remove" style="display: inline;" {
+								(csrf::field())
+								input type="hidden" name="aid" value=(author.id) {}
+								input type="hidden" name="name" value=(name) {}
+								button { "Remove" }
+							}
+						}
+					}
+				}
+				form method="POST" action="/author/names/add" {
+					(csrf::field())
+					input type="hidden" name="aid" value=(author.id) {}
+					input name="name" type="text" placeholder="other name" {}
+					button { "Add" }
+				}
+			}
+		}
+	} }
+}
+
+fn view_author_merge(from: &Author, into: &Author) -> Markup {
+	html! {
+		form method="POST" action="/authors/merge" {
+			(csrf::field())
+			input type="hidden" name="from" value=(from.id) {}
+			input type="hidden" name="into" value=(into.id) {}
+			button { {"Keep \"" (into.name) "\""} }
+		}
+	}
+}
+
+fn view_duplicate_authors(state: &ServerState, error: &str) -> Markup {
+	let pairs = duplicate_authors(state);
+	let mut authors = state.aid_to_authors.values().collect::<Vec<_>>();
+	authors.sort_by(|author, other| author.name.cmp(&other.name));
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		title { "LSYS - Duplicate authors" }
+	} body {
+		p style="color: red;" { (error) }
+		h1 { "Duplicate authors" }
+		p { "Merging moves every title to the author kept, the other one's page redirects to it." }
+
+		@if pairs.is_empty() {
+			p { "No likely duplicates." }
+		} @else {
+			table {
+				thead { tr {
+					td { "Author" }
+					td { "Author" }
+					td { "Why" }
+					td { "Merge" }
+				} }
+				tbody {
+					@for (author, other, reason) in &pairs { tr {
+						td {
+							a href={"/author?aid=" (author.id)} { (author.name) }
+							{" (" (author_titles(state, author.id).len()) " titles)"}
+						}
+						td {
+							a href={"/author?aid=" (other.id)} { (other.name) }
+							{" (" (author_titles(state, other.id).len()) " titles)"}
+						}
+						td { (reason) }
+						td {
+							(view_author_merge(other, author))
+							(view_author_merge(author, other))
+						}
+					} }
+				}
+			}
+		}
+
+		fieldset {
+			legend { "Merge any two authors" }
+			form method="POST" action="/authors/merge" {
+				(csrf::field())
+				label for="merge-from" { "merge:" }
+				select id="merge-from" name="from" {
+					@for author in &authors {
+						option value=(author.id) { (author.name) }
+					}
+				}
+				br {}
+				label for="merge-into" { "into:" }
+				select id="merge-into" name="into" {
+					@for author in &authors {
+						option value=(author.id) { (author.name) }
+					}
+				}
+				br {}
+				button { "Merge" }
+			}
+		}
+	} }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// the sample catalog has Poe once; a second, inverted, Poe is made to merge into him
+	#[tokio::test]
+	async fn merging_moves_titles_names_and_links() {
+		const POE: Aid = 1;
+		const BLACK_CAT: ISBN = 6;
+		let db = crate::sample_db().await;
+		let duplicate = sqlx::query!(
+			"INSERT INTO authors (name) VALUES ('Poe, Edgar Allan')",
+		).execute(&db).await.unwrap().last_insert_rowid();
+		sqlx::query!(
+			"UPDATE wrote SET author_id = ? WHERE ISBN = ? AND author_id = ?", duplicate, BLACK_CAT, POE,
+		).execute(&db).await.unwrap();
+
+		let stt = crate::new_shared_state(db).await;
+		let mut state = stt.lock().await;
+		let (from, into) = (state.aid_to_authors[&duplicate].clone(), state.aid_to_authors[&POE].clone());
+		merge_authors(&mut state, &from, &into).await.unwrap();
+
+		assert!(!state.aid_to_authors.contains_key(&duplicate));
+		assert!(state.aid_to_authors[&POE].alt_names.contains(&"Poe, Edgar Allan".to_owned()));
+		assert!(state.ISBN_to_authors[&BLACK_CAT].iter().all(|contributor| contributor.author.id == POE));
+		assert_eq!(state.aid_redirects.get(&duplicate), Some(&POE));
+	}
+}
@@ -0,0 +1,336 @@
+// bulk changes to book_info, books, authors and wrote, shared by the import formats
+
+use maud::{html, Markup, DOCTYPE};
+use std::collections::HashMap;
+use crate::types::*;
+use crate::{ServerState, load_catalog};
+
+pub const MAX_COPIES: usize = 1000;
+
+// accepts ISBN-10 and ISBN-13 with or without hyphens, always returning the ISBN-13 so one
+// title can't be in the catalog (or an import) twice under both forms
+pub fn parse_isbn(raw: &str) -> Result<ISBN, String> {
+	// MARC 020 $a may carry a qualifier after the number: "0553293354 (pbk.)"
+	let raw = raw.split_whitespace().next().unwrap_or("");
+	let digits = raw
+		.chars()
+		.filter(|chr| *chr != '-')
+		.map(|chr| chr.to_ascii_uppercase())
+		.collect::<String>();
+
+	let valid = match digits.len() {
+		10 => {
+			let sum = digits.chars().enumerate().try_fold(0, |sum, (at, chr)| {
+				let value = match chr {
+					'X' if at == 9 => 10,
+					chr => chr.to_digit(10)?,
+				};
+				Some(sum + value*(10 - at as u32))
+			});
+			sum.is_some_and(|sum| sum % 11 == 0)
+		}
+		13 => {
+			let sum = digits.chars().enumerate().try_fold(0, |sum, (at, chr)| {
+				let weight = if at % 2 == 0 { 1 } else { 3 };
+				Some(sum + chr.to_digit(10)?*weight)
+			});
+			sum.is_some_and(|sum| sum % 10 == 0)
+		}
+		_ => return Err(format!("\"{raw}\" is not an ISBN")),
+	};
+	if !valid {
+		return Err(format!("\"{raw}\" has a wrong check digit"));
+	}
+
+	if digits.len() == 10 {
+		let body = format!("978{}", &digits[..9]);
+		let sum = body.chars().enumerate().fold(0, |sum, (at, chr)| {
+			let weight = if at % 2 == 0 { 1 } else { 3 };
+			sum + chr.to_digit(10).unwrap_or(0)*weight
+		});
+		return Ok(format!("{body}{}", (10 - sum % 10) % 10).parse().unwrap_or_default());
+	}
+	digits.parse().map_err(|_| format!("\"{raw}\" is not an ISBN"))
+}
+
+// databases from before parse_isbn returned only ISBN-13s still hold ISBN-10s; those are
+// rewritten once, at startup, in every table keyed by ISBN. A title also imported again under
+// its ISBN-13 since is merged into it: its copies move over, rows the ISBN-13 already has win
+pub async fn convert_isbn_10s(db: &sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+	let stored = sqlx::query!(
+		"SELECT ISBN FROM book_info WHERE ISBN < 10000000000",
+	).fetch_all(db).await?;
+	// numbers that aren't valid ISBN-10s, like the sample catalog's placeholders, stay as they are
+	let conversions = stored.into_iter()
+		.filter_map(|row| Some((row.ISBN, parse_isbn(&format_isbn(row.ISBN)).ok()?)))
+		.filter(|(old, new)| old != new)
+		.collect::<Vec<_>>();
+	if conversions.is_empty() {
+		return Ok(());
+	}
+
+	let mut tx = db.begin().await?;
+	// the rows pointing at book_info change in the same transaction as it
+	sqlx::query!("PRAGMA defer_foreign_keys = ON").execute(&mut *tx).await?;
+	for (old, new) in &conversions {
+		sqlx::query!("UPDATE books SET ISBN = ? WHERE ISBN = ?", new, old).execute(&mut *tx).await?;
+		sqlx::query!("UPDATE OR IGNORE book_info SET ISBN = ? WHERE ISBN = ?", new, old).execute(&mut *tx).await?;
+		sqlx::query!("UPDATE OR IGNORE wrote SET ISBN = ? WHERE ISBN = ?", new, old).execute(&mut *tx).await?;
+		sqlx::query!("UPDATE OR IGNORE book_subjects SET ISBN = ? WHERE ISBN = ?", new, old).execute(&mut *tx).await?;
+		sqlx::query!("UPDATE OR IGNORE book_tags SET ISBN = ? WHERE ISBN = ?", new, old).execute(&mut *tx).await?;
+		sqlx::query!("UPDATE OR IGNORE series_volumes SET ISBN = ? WHERE ISBN = ?", new, old).execute(&mut *tx).await?;
+		sqlx::query!("UPDATE OR IGNORE editions SET ISBN = ? WHERE ISBN = ?", new, old).execute(&mut *tx).await?;
+		sqlx::query!("UPDATE OR IGNORE covers SET ISBN = ? WHERE ISBN = ?", new, old).execute(&mut *tx).await?;
+		// what the ISBN-13 already had
+		sqlx::query!("DELETE FROM wrote WHERE ISBN = ?", old).execute(&mut *tx).await?;
+		sqlx::query!("DELETE FROM book_subjects WHERE ISBN = ?", old).execute(&mut *tx).await?;
+		sqlx::query!("DELETE FROM book_tags WHERE ISBN = ?", old).execute(&mut *tx).await?;
+		sqlx::query!("DELETE FROM series_volumes WHERE ISBN = ?", old).execute(&mut *tx).await?;
+		sqlx::query!("DELETE FROM editions WHERE ISBN = ?", old).execute(&mut *tx).await?;
+		sqlx::query!("DELETE FROM covers WHERE ISBN = ?", old).execute(&mut *tx).await?;
+		sqlx::query!("DELETE FROM book_info WHERE ISBN = ?", old).execute(&mut *tx).await?;
+	}
+	tx.commit().await?;
+
+	for (old, new) in conversions {
+		if let Err(e) = crate::covers::move_cover(old, new) {
+			eprintln!("can't move the cover of {old} to {new}: {e}");
+		}
+	}
+	Ok(())
+}
+
+// ISBNs are stored as numbers, so ISBN-10s lose their leading zeros
+pub fn format_isbn(isbn: ISBN) -> String {
+	if isbn < 10_000_000_000 {
+		format!("{isbn:010}")
+	} else {
+		format!("{isbn:013}")
+	}
+}
+
+// "Doyle, Arthur Conan." and "arthur  conan doyle" both become "arthur conan doyle"
+pub fn normalize_name(name: &str) -> String {
+	crate::marc::direct_name(name)
+		.to_lowercase()
+		.chars()
+		.map(|chr| if chr.is_alphanumeric() { chr } else { ' ' })
+		.collect::<String>()
+		.split_whitespace()
+		.collect::<Vec<_>>()
+		.join(" ")
+}
+
+// applies every entry in a single transaction, nothing is kept on a dry run
+pub async fn import_entries(
+	state: &mut ServerState,
+	entries: Vec<ImportRecord>,
+	dry_run: bool,
+) -> Result<Vec<ImportRow>, sqlx::Error> {
+	let mut tx = state.db.begin().await?;
+	let mut rows = Vec::new();
+	let mut seen = HashMap::<ISBN, usize>::new();
+
+	for (record, entry) in entries {
+		let entry = match entry {
+			Ok(entry)=>entry,
+			Err(reason)=>{
+				rows.push(ImportRow{
+					record,
+					ISBN: None,
+					name: String::new(),
+					outcome: ImportOutcome::Skipped(reason),
+				});
+				continue;
+			}
+		};
+		let outcome = match seen.get(&entry.ISBN) {
+			Some(first)=>ImportOutcome::Skipped(format!("duplicate of record {first}")),
+			None=>{
+				seen.insert(entry.ISBN, record);
+				import_entry(&mut tx, &entry).await?
+			}
+		};
+		rows.push(ImportRow{
+			record,
+			ISBN: Some(entry.ISBN),
+			name: entry.name,
+			outcome,
+		});
+	}
+
+	if dry_run {
+		tx.rollback().await?;
+	} else {
+		tx.commit().await?;
+		load_catalog(state).await;
+	}
+	Ok(rows)
+}
+
+async fn import_entry(
+	tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+	entry: &CatalogEntry,
+) -> Result<ImportOutcome, sqlx::Error> {
+	let known = sqlx::query!(
+		"SELECT name, published FROM book_info WHERE ISBN = ?", entry.ISBN,
+	).fetch_optional(&mut **tx).await?;
+
+	let mut changes = Vec::new();
+	match &known {
+		None=>{
+			sqlx::query!(
+				"INSERT INTO book_info (ISBN, name, published) VALUES (?, ?, ?)",
+				entry.ISBN, entry.name, entry.published,
+			).execute(&mut **tx).await?;
+		}
+		Some(known)=>{
+			if known.name != entry.name {
+				sqlx::query!(
+					"UPDATE book_info SET name = ? WHERE ISBN = ?", entry.name, entry.ISBN,
+				).execute(&mut **tx).await?;
+				changes.push(format!("name \"{}\" -> \"{}\"", known.name, entry.name));
+			}
+			if !entry.published.is_empty() && known.published != entry.published {
+				sqlx::query!(
+					"UPDATE book_info SET published = ? WHERE ISBN = ?", entry.published, entry.ISBN,
+				).execute(&mut **tx).await?;
+				changes.push(format!("published \"{}\" -> \"{}\"", known.published, entry.published));
+			}
+		}
+	}
+
+	for author in &entry.authors {
+		let aid = sqlx::query_scalar!(
+			"SELECT id FROM authors WHERE name = ?", author,
+		).fetch_optional(&mut **tx).await?;
+		let aid = match aid {
+			Some(aid)=>aid,
+			None=>sqlx::query!(
+				"INSERT INTO authors (name) VALUES (?)", author,
+			).execute(&mut **tx).await?.last_insert_rowid(),
+		};
+		let linked = sqlx::query!(
+			"INSERT OR IGNORE INTO wrote (author_id, ISBN) VALUES (?, ?)", aid, entry.ISBN,
+		).execute(&mut **tx).await?.rows_affected() > 0;
+		if linked && known.is_some() {
+			changes.push(format!("added author {author}"));
+		}
+	}
+
+	// copies are only ever added, never removed by an import
+	let have = sqlx::query_scalar!(
+		"SELECT COUNT(*) FROM books WHERE ISBN = ?", entry.ISBN,
+	).fetch_one(&mut **tx).await? as usize;
+	let want = entry.copies.max(1);
+	for _ in have..want {
+		sqlx::query!(
+			"INSERT INTO books (ISBN) VALUES (?)", entry.ISBN,
+		).execute(&mut **tx).await?;
+	}
+	if want > have && known.is_some() {
+		changes.push(format!("{} new copies", want-have));
+	}
+
+	Ok( match known {
+		None=>ImportOutcome::Created,
+		Some(_) if changes.is_empty()=>ImportOutcome::Skipped("already up to date".to_owned()),
+		Some(_)=>ImportOutcome::Updated(changes),
+	} )
+}
+
+pub fn view_import_report(title: &str, rows: &[ImportRow], dry_run: bool, back: &str, actions: Markup) -> Markup {
+	let count = |kind: fn(&ImportOutcome) -> bool| rows.iter().filter(|row| kind(&row.outcome)).count();
+	let created = count(|outcome| matches!(outcome, ImportOutcome::Created));
+	let updated = count(|outcome| matches!(outcome, ImportOutcome::Updated(_)));
+	let skipped = count(|outcome| matches!(outcome, ImportOutcome::Skipped(_)));
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		title { {"LSYS - " (title)} }
+	} body {
+		h1 { (title) }
+		@if dry_run {
+			p { b { "Dry run, nothing was saved." } }
+		}
+		p { {(created) " created, " (updated) " updated, " (skipped) " skipped"} }
+		a href=(back) { "Back" }
+		(actions)
+		table {
+			thead { tr {
+				td { "Record" }
+				td { "ISBN" }
+				td { "Name" }
+				td { "Result" }
+			} }
+			tbody {
+				@for row in rows { tr {
+					th { (row.record) }
+					td { @if let Some(isbn) = row.ISBN { (format_isbn(isbn)) } }
+					td { i { (row.name) } }
+					td {
+						@match &row.outcome {
+							ImportOutcome::Created=>{ "created" },
+							ImportOutcome::Updated(changes)=>{
+								"updated: "
+								@for change in changes {
+									p { (change) }
+								}
+							},
+							ImportOutcome::Skipped(reason)=>{ {"skipped: " (reason)} },
+						}
+					}
+				} }
+			}
+		}
+	} }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sqlx::Executor;
+	use crate::sample_db;
+
+	// Foundation, as the sample catalog has it and as it was stored before
+	const FOUNDATION: ISBN = 9780553293357;
+	const FOUNDATION_10: ISBN = 553293354;
+
+	#[test]
+	fn isbn_10_and_13_are_the_same_title() {
+		assert_eq!(parse_isbn("0-553-29335-4"), Ok(9780553293357));
+		assert_eq!(parse_isbn("9780553293357"), Ok(9780553293357));
+		assert_eq!(parse_isbn("080442957X"), Ok(9780804429573));
+		assert!(parse_isbn("0553293355").is_err());
+	}
+
+	#[tokio::test]
+	async fn search_finds_a_title_by_its_isbn_10() {
+		let stt = crate::new_shared_state(sample_db().await).await;
+		let state = stt.lock().await;
+		let foundation = crate::title_copy(&state, FOUNDATION).unwrap();
+		for query in ["0553293354", "0-553-29335-4", "9780553293357"] {
+			assert!(crate::book_matches(&state, foundation, query), "{query}");
+		}
+	}
+
+	#[tokio::test]
+	async fn stored_isbn_10s_are_converted() {
+		let db = sample_db().await;
+		let mut tx = db.begin().await.unwrap();
+		tx.execute("PRAGMA defer_foreign_keys = ON").await.unwrap();
+		for table in ["books", "book_info", "wrote", "book_subjects", "book_tags", "series_volumes", "editions"] {
+			sqlx::query(&format!("UPDATE {table} SET ISBN = ? WHERE ISBN = ?"))
+				.bind(FOUNDATION_10).bind(FOUNDATION)
+				.execute(&mut *tx).await.unwrap();
+		}
+		tx.commit().await.unwrap();
+
+		let stt = crate::new_shared_state(db).await;
+		let state = stt.lock().await;
+		assert!(crate::title_copy(&state, FOUNDATION_10).is_none());
+		assert_eq!(crate::availability(&state, FOUNDATION), (2, 2));
+		assert!(state.ISBN_to_series.contains_key(&FOUNDATION));
+		assert!(state.ISBN_to_edition.contains_key(&FOUNDATION));
+		assert_eq!(state.ISBN_to_authors[&FOUNDATION].len(), 2);
+	}
+}
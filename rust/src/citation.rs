@@ -0,0 +1,224 @@
+// BibTeX, RIS and CSL-JSON citations of catalog titles
+
+use axum::{
+	extract::State,
+	extract::Query,
+	http::header,
+	response::{Redirect, IntoResponse, Response},
+};
+use maud::{html, Markup};
+use serde_json::json;
+use std::collections::HashSet;
+use crate::types::*;
+use crate::catalog::format_isbn;
+use crate::{
+	SharedState,
+	read_state,
+	view_404, filter_books,
+};
+
+// "Edgar Allan Poe" or "Poe, Edgar Allan" -> ("Poe", "Edgar Allan")
+fn split_name(name: &str) -> (String, String) {
+	let name = crate::marc::direct_name(name);
+	match name.rsplit_once(' ') {
+		Some((given, family)) => (family.to_owned(), given.to_owned()),
+		None => (name, String::new()),
+	}
+}
+
+// published is free text, citations want the year
+fn year(book: &Book) -> Option<String> {
+	let digits = book.published.chars()
+		.skip_while(|chr| !chr.is_ascii_digit())
+		.take_while(char::is_ascii_digit)
+		.collect::<String>();
+	(digits.len() == 4).then_some(digits)
+}
+
+// poe1978raven, the same on every export so reference managers can dedupe
+fn cite_key(book: &Book) -> String {
+	let family = book.authors.first().map_or("anon".to_owned(), |author| split_name(author).0);
+	let word = book.name
+		.split_whitespace()
+		.map(|word| word.to_lowercase())
+		.find(|word| !["a", "an", "the"].contains(&word.as_str()))
+		.unwrap_or_default();
+	format!("{family}{}{word}", year(book).unwrap_or_default())
+		.chars()
+		.filter(char::is_ascii_alphanumeric)
+		.collect::<String>()
+		.to_lowercase()
+}
+
+fn bibtex_escape(value: &str) -> String {
+	let mut escaped = String::new();
+	for chr in value.chars() {
+		match chr {
+			'{' | '}' | '&' | '%' | '$' | '#' | '_' => {
+				escaped.push('\\');
+				escaped.push(chr);
+			}
+			'\\' => escaped.push_str("\\textbackslash{}"),
+			'~' => escaped.push_str("\\textasciitilde{}"),
+			'^' => escaped.push_str("\\textasciicircum{}"),
+			'\n' | '\r' => escaped.push(' '),
+			chr => escaped.push(chr),
+		}
+	}
+	escaped
+}
+
+fn to_bibtex(books: &[&Book]) -> String {
+	let mut bibtex = String::new();
+	for book in books {
+		bibtex.push_str(&format!("@book{{{},\n", cite_key(book)));
+		bibtex.push_str(&format!("\ttitle = {{{}}},\n", bibtex_escape(&book.name)));
+		if !book.authors.is_empty() {
+			let authors = book.authors.iter()
+				.map(|author| {
+					let (family, given) = split_name(author);
+					bibtex_escape(&format!("{family}, {given}"))
+				})
+				.collect::<Vec<_>>();
+			bibtex.push_str(&format!("\tauthor = {{{}}},\n", authors.join(" and ")));
+		}
+		if let Some(year) = year(book) {
+			bibtex.push_str(&format!("\tyear = {{{year}}},\n"));
+		}
+		bibtex.push_str(&format!("\tisbn = {{{}}},\n", format_isbn(book.ISBN)));
+		bibtex.push_str("}\n\n");
+	}
+	bibtex
+}
+
+fn to_ris(books: &[&Book]) -> String {
+	let mut ris = String::new();
+	let line = |ris: &mut String, tag: &str, value: &str| {
+		ris.push_str(&format!("{tag}  - {}\r\n", value.replace(['\r', '\n'], " ")));
+	};
+	for book in books {
+		line(&mut ris, "TY", "BOOK");
+		line(&mut ris, "ID", &cite_key(book));
+		for author in &book.authors {
+			let (family, given) = split_name(author);
+			line(&mut ris, "AU", &format!("{family}, {given}"));
+		}
+		line(&mut ris, "TI", &book.name);
+		if let Some(year) = year(book) {
+			line(&mut ris, "PY", &year);
+		}
+		line(&mut ris, "SN", &format_isbn(book.ISBN));
+		line(&mut ris, "ER", "");
+	}
+	ris
+}
+
+fn to_csl_json(books: &[&Book]) -> String {
+	let items = books.iter().map(|book| {
+		let authors = book.authors.iter()
+			.map(|author| {
+				let (family, given) = split_name(author);
+				json!({ "family": family, "given": given })
+			})
+			.collect::<Vec<_>>();
+		let mut item = json!({
+			"id": cite_key(book),
+			"type": "book",
+			"title": book.name,
+			"author": authors,
+			"ISBN": format_isbn(book.ISBN),
+		});
+		if let Some(year) = year(book).and_then(|year| year.parse::<i64>().ok()) {
+			item["issued"] = json!({ "date-parts": [[year]] });
+		}
+		item
+	}).collect::<Vec<_>>();
+	serde_json::to_string_pretty(&items).unwrap_or_default()
+}
+
+fn citation_response(books: &[&Book], format: CitationFormat, name: &str) -> Response {
+	let (body, content_type, extension) = match format {
+		CitationFormat::Bibtex=>(to_bibtex(books), "application/x-bibtex; charset=utf-8", "bib"),
+		CitationFormat::Ris=>(to_ris(books), "application/x-research-info-systems; charset=utf-8", "ris"),
+		CitationFormat::Csljson=>(to_csl_json(books), "application/vnd.citationstyles.csl+json", "json"),
+	};
+	let disposition = format!("attachment; filename=\"{name}.{extension}\"");
+	(
+		[
+			(header::CONTENT_TYPE, content_type.to_owned()),
+			(header::CONTENT_DISPOSITION, disposition),
+		],
+		body,
+	).into_response()
+}
+
+pub async fn display_book_citation(
+	State(stt): State<SharedState>,
+	Query(bid): Query<BookParam>,
+	Query(cite): Query<CiteParam>,
+) -> Result<Response, Redirect> {
+	let state = read_state(stt).await;
+	Ok( match state.bid_to_book.get(&bid.bid) {
+		Some(book)=>citation_response(&[book], cite.format, &cite_key(book)),
+		None=>view_404(format!("/cite?bid={}", bid.bid)).into_response(),
+	} )
+}
+
+pub async fn display_search_citation(
+	State(stt): State<SharedState>,
+	Query(filter): Query<CatalogFilter>,
+	Query(cite): Query<CiteParam>,
+) -> Result<Response, Redirect> {
+	let state = read_state(stt).await;
+	// the results list copies, a citation is per title
+	let books = filter_books(&state, &filter);
+	let mut seen = HashSet::new();
+	let titles = books.iter()
+		.filter(|book| seen.insert(book.ISBN))
+		.collect::<Vec<_>>();
+	Ok( citation_response(&titles, cite.format, "lsys-search") )
+}
+
+fn view_cite_links(base: &str) -> Markup {
+	html! {
+		"Cite: "
+		a href={(base) "format=bibtex"} { "BibTeX" }
+		" | "
+		a href={(base) "format=ris"} { "RIS" }
+		" | "
+		a href={(base) "format=csljson"} { "CSL-JSON" }
+	}
+}
+
+pub fn view_book_cite_links(book: &Book) -> Markup {
+	html! {
+		section id="cite" {
+			p { (view_cite_links(&format!("/cite?bid={}&", book.bid))) }
+		}
+	}
+}
+
+pub fn view_search_cite_links(filter: &CatalogFilter) -> Markup {
+	let query = serde_urlencoded::to_string(filter).unwrap_or_default();
+	html! {
+		p { (view_cite_links(&format!("/search/cite?{query}&"))) }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn names_split_in_either_order() {
+		let poe = ("Poe".to_owned(), "Edgar Allan".to_owned());
+		assert_eq!(split_name("Edgar Allan Poe"), poe);
+		assert_eq!(split_name("Poe, Edgar Allan"), poe);
+		assert_eq!(split_name("Homer"), ("Homer".to_owned(), String::new()));
+	}
+
+	#[test]
+	fn bibtex_special_characters_are_escaped() {
+		assert_eq!(bibtex_escape(r"a\b~c^d_e"), r"a\textbackslash{}b\textasciitilde{}c\textasciicircum{}d\_e");
+	}
+}
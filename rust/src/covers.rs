@@ -0,0 +1,349 @@
+// cover images, uploaded once per ISBN and stored as re-encoded JPEG thumbnails
+
+use axum::{
+	Form,
+	extract::State,
+	extract::Query,
+	extract::Multipart,
+	http::header,
+	response::{Redirect, IntoResponse, Response},
+};
+use image::{ImageFormat, RgbImage, Rgb};
+use image::imageops::FilterType;
+use image::codecs::jpeg::JpegEncoder;
+use maud::{html, Markup};
+use std::io::Cursor;
+use crate::types::*;
+use crate::roles::{Authorized, Catalog};
+use crate::{
+	SharedState, ServerState,
+	read_state, make_redirect,
+	view_error, title_copy, csrf,
+};
+
+const COVER_DIR: &str = "files/img/books";
+const ALLOWED_FORMATS: [ImageFormat; 4] = [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::Gif, ImageFormat::WebP];
+const MAX_COVER_SIDE: u32 = 8000;
+const JPEG_QUALITY: u8 = 85;
+const SIZES: [CoverSize; 3] = [CoverSize::Small, CoverSize::Medium, CoverSize::Large];
+
+pub async fn load(state: &mut ServerState) {
+	let covers = sqlx::query_as!(
+		Cover,
+		"SELECT ISBN, width, height, uploaded FROM covers",
+	).fetch_all(&state.db).await.expect("can't parse row from covers into Cover");
+
+	covers.into_iter().for_each(|cover| cover.update_maps(state));
+}
+
+impl Cover {
+	fn update_maps(self, state: &mut ServerState) {
+		state.ISBN_to_cover.insert(self.ISBN, self);
+	}
+}
+
+impl CoverSize {
+	fn name(self) -> &'static str {
+		match self {
+			CoverSize::Small=>"small",
+			CoverSize::Medium=>"medium",
+			CoverSize::Large=>"large",
+		}
+	}
+
+	// the box a cover is scaled to fit in, placeholders fill all of it
+	fn bounds(self) -> (u32, u32) {
+		match self {
+			CoverSize::Small=>(60, 90),
+			CoverSize::Medium=>(200, 300),
+			CoverSize::Large=>(400, 600),
+		}
+	}
+
+	// keeps the aspect ratio, and never scales up
+	fn fit(self, width: u32, height: u32) -> (u32, u32) {
+		let (max_width, max_height) = self.bounds();
+		let scale = (max_width as f64 / width as f64)
+			.min(max_height as f64 / height as f64)
+			.min(1.0);
+		let scaled = |side: u32| ((side as f64 * scale).round() as u32).max(1);
+		(scaled(width), scaled(height))
+	}
+}
+
+fn cover_path(isbn: ISBN, size: CoverSize) -> String {
+	format!("{COVER_DIR}/{isbn}/{}.jpg", size.name())
+}
+
+// for a title whose ISBN changed; a title that already had a cover under the new one keeps it
+pub fn move_cover(old: ISBN, new: ISBN) -> std::io::Result<()> {
+	let (old, new) = (format!("{COVER_DIR}/{old}"), format!("{COVER_DIR}/{new}"));
+	if !std::path::Path::new(&old).exists() {
+		return Ok(());
+	}
+	if std::path::Path::new(&new).exists() {
+		return std::fs::remove_dir_all(old);
+	}
+	std::fs::rename(old, new)
+}
+
+fn cover_url(cover: &Cover, size: CoverSize) -> String {
+	format!("/{}?v={}", cover_path(cover.ISBN, size), cover.uploaded)
+}
+
+// decoding and re-encoding drops EXIF, ICC and any other metadata the upload carried
+fn decode_cover(file: &[u8]) -> Result<RgbImage, String> {
+	let format = image::guess_format(file).map_err(|_| "The file isn't an image".to_owned())?;
+	if !ALLOWED_FORMATS.contains(&format) {
+		return Err("Covers must be JPEG, PNG, GIF or WebP images".to_owned());
+	}
+	let mut reader = image::io::Reader::with_format(Cursor::new(file), format);
+	let mut limits = image::io::Limits::default();
+	limits.max_image_width = Some(MAX_COVER_SIDE);
+	limits.max_image_height = Some(MAX_COVER_SIDE);
+	reader.limits(limits);
+	let image = reader.decode().map_err(|e| format!("Can't read the image: {e}"))?;
+
+	// JPEG has no transparency, so it is flattened onto white
+	let rgba = image.to_rgba8();
+	Ok( RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+		let [red, green, blue, alpha] = rgba.get_pixel(x, y).0;
+		let blend = |channel: u8| {
+			((channel as u32 * alpha as u32 + 255 * (255 - alpha as u32)) / 255) as u8
+		};
+		Rgb([blend(red), blend(green), blend(blue)])
+	}) )
+}
+
+// every size is encoded before anything is written, so a bad upload leaves the old cover alone
+fn save_cover(isbn: ISBN, file: &[u8]) -> Result<(u32, u32), String> {
+	let image = decode_cover(file)?;
+	let (width, height) = image.dimensions();
+
+	let mut thumbnails = Vec::new();
+	for size in SIZES {
+		let (thumb_width, thumb_height) = size.fit(width, height);
+		let thumb = image::imageops::resize(&image, thumb_width, thumb_height, FilterType::Lanczos3);
+		let mut jpeg = Vec::new();
+		JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY)
+			.encode(&thumb, thumb_width, thumb_height, image::ColorType::Rgb8)
+			.map_err(|e| e.to_string())?;
+		thumbnails.push((size, jpeg));
+	}
+
+	std::fs::create_dir_all(format!("{COVER_DIR}/{isbn}")).map_err(|e| e.to_string())?;
+	for (size, jpeg) in thumbnails {
+		let path = cover_path(isbn, size);
+		let partial = format!("{path}.part");
+		std::fs::write(&partial, jpeg).map_err(|e| e.to_string())?;
+		std::fs::rename(&partial, &path).map_err(|e| e.to_string())?;
+	}
+	Ok((width, height))
+}
+
+fn book_redirect(bid: Option<Bid>) -> Redirect {
+	match bid {
+		Some(bid)=>make_redirect(format!("/book?bid={bid}")),
+		None=>make_redirect("/".to_owned()),
+	}
+}
+
+pub async fn perform_upload_cover(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	mut multipart: Multipart,
+) -> Result<Redirect, Markup> {
+	let mut file = Vec::new();
+	let mut isbn = None;
+	let mut bid = None;
+	loop {
+		let field = match multipart.next_field().await {
+			Ok(Some(field))=>field,
+			Ok(None)=>break,
+			Err(e)=>return Err(view_error(e.to_string())),
+		};
+		let name = field.name().unwrap_or("").to_owned();
+		if name == "file" {
+			file = field.bytes().await.map_err(|e| view_error(e.to_string()))?.to_vec();
+			continue;
+		}
+		let value = field.text().await.unwrap_or_default();
+		match name.as_str() {
+			"ISBN"=>isbn = value.trim().parse::<ISBN>().ok(),
+			"bid"=>bid = value.trim().parse::<Bid>().ok(),
+			_=>{}
+		}
+	}
+	let isbn = isbn.ok_or(view_error("No ISBN given".to_owned()))?;
+	if file.is_empty() {
+		return Err(view_error("No image uploaded".to_owned()));
+	}
+	if title_copy(&*stt.lock().await, isbn).is_none() {
+		return Err(view_error(format!("No title with ISBN {isbn}")));
+	}
+
+	// resizing a large image takes a while, so it runs before the state is locked
+	let saved = tokio::task::spawn_blocking(move || save_cover(isbn, &file)).await;
+	let (width, height) = saved
+		.map_err(|e| view_error(e.to_string()))?
+		.map_err(view_error)?;
+
+	let mut state = stt.lock().await;
+	let cover = Cover{
+		ISBN: isbn,
+		width: width as i64,
+		height: height as i64,
+		uploaded: chrono::Utc::now().timestamp(),
+	};
+	sqlx::query!(
+		"INSERT INTO covers (ISBN, width, height, uploaded) VALUES (?, ?, ?, ?)
+		ON CONFLICT(ISBN) DO UPDATE SET width = excluded.width, height = excluded.height, uploaded = excluded.uploaded",
+		cover.ISBN, cover.width, cover.height, cover.uploaded,
+	).execute(&state.db).await.map_err(|e| view_error(e.to_string()))?;
+	cover.update_maps(&mut state);
+
+	Ok( book_redirect(bid) )
+}
+
+pub async fn perform_remove_cover(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<CoverForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	sqlx::query!(
+		"DELETE FROM covers WHERE ISBN = ?", form.ISBN,
+	).execute(&state.db).await.map_err(|e| view_error(e.to_string()))?;
+	state.ISBN_to_cover.remove(&form.ISBN);
+
+	match std::fs::remove_dir_all(format!("{COVER_DIR}/{}", form.ISBN)) {
+		Err(e) if e.kind() != std::io::ErrorKind::NotFound=>return Err(view_error(e.to_string())),
+		_=>{}
+	}
+	Ok( book_redirect(form.bid) )
+}
+
+// titles without a cover get one drawn from their name and authors
+pub async fn display_placeholder_cover(
+	State(stt): State<SharedState>,
+	Query(param): Query<CoverParam>,
+) -> Response {
+	let state = read_state(stt).await;
+	let (name, author) = match title_copy(&state, param.ISBN) {
+		Some(book)=>{
+			let author = match book.authors.as_slice() {
+				[]=>String::new(),
+				[author]=>author.clone(),
+				[author, ..]=>format!("{author} et al."),
+			};
+			(book.name.clone(), author)
+		}
+		None=>("No cover".to_owned(), String::new()),
+	};
+	(
+		[(header::CONTENT_TYPE, "image/svg+xml")],
+		view_placeholder(&name, &author, param.size),
+	).into_response()
+}
+
+// greedy word wrap, cutting the text short with an ellipsis when it runs out of lines
+fn wrap(text: &str, width: usize, max_lines: usize) -> Vec<String> {
+	let mut lines = Vec::<String>::new();
+	let mut line = String::new();
+	for word in text.split_whitespace() {
+		if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > width {
+			lines.push(std::mem::take(&mut line));
+		}
+		if !line.is_empty() {
+			line.push(' ');
+		}
+		line.push_str(word);
+	}
+	if !line.is_empty() {
+		lines.push(line);
+	}
+
+	let cut = lines.len() > max_lines;
+	lines.truncate(max_lines);
+	for line in &mut lines {
+		if line.chars().count() > width {
+			*line = line.chars().take(width-1).collect();
+			line.push('…');
+		}
+	}
+	if cut {
+		if let Some(last) = lines.last_mut() {
+			last.push('…');
+		}
+	}
+	lines
+}
+
+fn view_placeholder(name: &str, author: &str, size: CoverSize) -> Markup {
+	let (width, height) = size.bounds();
+	let hue = name.bytes().fold(0u32, |hash, byte| hash.wrapping_mul(31).wrapping_add(byte as u32)) % 360;
+	let title = wrap(name, 16, 6);
+	let author = wrap(author, 22, 1);
+	html! {
+		svg xmlns="http://www.w3.org/2000/svg" width=(width) height=(height) viewBox="0 0 200 300" {
+			rect width="200" height="300" fill={"hsl(" (hue) ", 35%, 30%)"} {}
+			rect x="12" y="12" width="176" height="276" fill="none" stroke="white" stroke-opacity="0.5" {}
+			@for (at, line) in title.iter().enumerate() {
+				text x="100" y=(70 + at*26) text-anchor="middle" font-family="serif" font-size="20" fill="white" {
+					(line)
+				}
+			}
+			@for line in &author {
+				text x="100" y="262" text-anchor="middle" font-family="sans-serif" font-size="14" fill="white" {
+					(line)
+				}
+			}
+		}
+	}
+}
+
+pub fn view_cover(state: &ServerState, book: &Book, size: CoverSize) -> Markup {
+	html! {
+		@match state.ISBN_to_cover.get(&book.ISBN) {
+			Some(cover)=>{
+				@let (width, height) = size.fit(cover.width as u32, cover.height as u32);
+				img src=(cover_url(cover, size)) width=(width) height=(height)
+					alt={"Cover of " (book.name)} {}
+			},
+			None=>{
+				@let (width, height) = size.bounds();
+				img src={"/covers/placeholder?ISBN=" (book.ISBN) "&size=" (size.name())}
+					width=(width) height=(height) alt={"No cover for " (book.name)} {}
+			},
+		}
+	}
+}
+
+// the cover on a book page, linking to the large size, with upload controls for workers
+pub fn view_book_cover(state: &ServerState, book: &Book, can_catalog: bool) -> Markup {
+	let cover = state.ISBN_to_cover.get(&book.ISBN);
+	html! {
+		@match cover {
+			Some(cover)=>{ a href=(cover_url(cover, CoverSize::Large)) { (view_cover(state, book, CoverSize::Medium)) } },
+			None=>{ (view_cover(state, book, CoverSize::Medium)) },
+		}
+		@if can_catalog {
+			form method="POST" action="/covers/upload" enctype="multipart/form-data" {
+				(csrf::field())
+				input type="hidden" name="ISBN" value=(book.ISBN) {}
+				input type="hidden" name="bid" value=(book.bid) {}
+				input name="file" type="file" accept="image/jpeg,image/png,image/gif,image/webp" {}
+				button { @if cover.is_some() { "Replace cover" } @else { "Upload cover" } }
+			}
+			@if cover.is_some() {
+				form method="POST" action="/covers/remove" {
+					(csrf::field())
+					input type="hidden" name="ISBN" value=(book.ISBN) {}
+					input type="hidden" name="bid" value=(book.bid) {}
+					button { "Remove cover" }
+				}
+			}
+		}
+	}
+}
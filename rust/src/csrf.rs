@@ -0,0 +1,146 @@
+// every form carries the token from the lsys-csrf cookie, and no POST goes through without it;
+// another site can make a browser send the cookie, but can't read it to fill in the form
+
+use axum::{
+	body::{Body, Bytes},
+	extract::{FromRequest, Multipart, Request},
+	http::{header, Method, StatusCode},
+	middleware::Next,
+	response::{IntoResponse, Response},
+};
+use futures_util::{stream, StreamExt};
+use maud::{html, Markup};
+use subtle::ConstantTimeEq;
+use tower_cookies::{Cookie, Cookies, cookie::SameSite};
+use crate::types::BearerToken;
+use crate::{view_error, sessions::new_token, tls};
+
+const COOKIE_CSRF_NAME: &str = "lsys-csrf";
+const FIELD_NAME: &str = "csrf";
+// the same as axum's DefaultBodyLimit, forms are never bigger
+const MAX_FORM_SIZE: usize = 2 * 1024 * 1024;
+// how much of an upload is read for its first field; the route's own limit covers the rest
+const MULTIPART_PREFIX_SIZE: usize = 16 * 1024;
+
+tokio::task_local! {
+	// the token of the request being handled, for the views it renders
+	static TOKEN: String;
+}
+
+// the hidden input every POST form starts with
+pub fn field() -> Markup {
+	let token = TOKEN.try_with(String::clone).unwrap_or_default();
+	html! {
+		input type="hidden" name=(FIELD_NAME) value=(token) {}
+	}
+}
+
+// where a goto may send the user: only paths on this site, never "//host" or "/\host"
+pub fn safe_goto(goto: Option<&str>) -> &str {
+	match goto {
+		Some(goto) if goto.starts_with('/')
+			&& !goto.starts_with("//")
+			&& !goto.starts_with("/\\")
+			&& !goto.chars().any(char::is_control)=>goto,
+		_=>"/",
+	}
+}
+
+pub async fn protect(
+	cookies: Cookies,
+	request: Request,
+	next: Next,
+) -> Response {
+	let token = match cookies.get(COOKIE_CSRF_NAME) {
+		Some(cookie) if !cookie.value().is_empty()=>cookie.value().to_owned(),
+		_=>{
+			let token = new_token();
+			cookies.add(
+				Cookie::build((COOKIE_CSRF_NAME, token.clone()))
+					.path("/")
+					.secure(tls::enabled())
+					.http_only(true)
+					.same_site(SameSite::Lax)
+					.into()
+			);
+			token
+		}
+	};
+
+	// scripts with an accepted API token have no form to fill in, and no cookies a forged request could ride on
+	let request = if is_safe(request.method()) || request.extensions().get::<BearerToken>().is_some() {
+		request
+	} else {
+		match check(request, &token).await {
+			Ok(request)=>request,
+			Err(response)=>return response,
+		}
+	};
+	TOKEN.scope(token, next.run(request)).await
+}
+
+fn is_safe(method: &Method) -> bool {
+	matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+fn rejection() -> Response {
+	let error = "This form is out of date, go back, reload the page and send it again".to_owned();
+	(StatusCode::FORBIDDEN, view_error(error)).into_response()
+}
+
+// reads the submitted token out of the body, then hands the body on untouched
+async fn check(request: Request, token: &str) -> Result<Request, Response> {
+	let (parts, body) = request.into_parts();
+	let content_type = parts.headers.get(header::CONTENT_TYPE)
+		.and_then(|content_type| content_type.to_str().ok())
+		.unwrap_or_default();
+
+	let (submitted, body) = if content_type.starts_with("multipart/form-data") {
+		let (prefix, body) = read_prefix(body).await?;
+		(multipart_token(content_type, prefix).await, body)
+	} else {
+		let bytes = axum::body::to_bytes(body, MAX_FORM_SIZE).await
+			.map_err(|_| StatusCode::PAYLOAD_TOO_LARGE.into_response())?;
+		let submitted = serde_urlencoded::from_bytes::<Vec<(String, String)>>(&bytes)
+			.ok()
+			.and_then(|fields| fields.into_iter().find(|(name, _)| name == FIELD_NAME))
+			.map(|(_, value)| value);
+		(submitted, Body::from(bytes))
+	};
+	match submitted {
+		Some(submitted) if !token.is_empty() && bool::from(submitted.as_bytes().ct_eq(token.as_bytes()))=>{
+			Ok( Request::from_parts(parts, body) )
+		}
+		_=>Err(rejection()),
+	}
+}
+
+// the start of an upload, and the whole upload again to hand on, still streaming
+async fn read_prefix(body: Body) -> Result<(Bytes, Body), Response> {
+	let mut chunks = body.into_data_stream();
+	let mut prefix = Vec::new();
+	while prefix.len() < MULTIPART_PREFIX_SIZE {
+		match chunks.next().await {
+			Some(Ok(chunk))=>prefix.extend_from_slice(&chunk),
+			Some(Err(_))=>return Err(StatusCode::BAD_REQUEST.into_response()),
+			None=>break,
+		}
+	}
+	let prefix = Bytes::from(prefix);
+	let body = Body::from_stream(stream::once(std::future::ready(Ok(prefix.clone()))).chain(chunks));
+	Ok( (prefix, body) )
+}
+
+// the field comes first in every form, so the rest of an upload isn't looked at
+async fn multipart_token(content_type: &str, bytes: axum::body::Bytes) -> Option<String> {
+	let request = Request::builder()
+		.header(header::CONTENT_TYPE, content_type)
+		.body(Body::from(bytes))
+		.ok()?;
+	let mut multipart = Multipart::from_request(request, &()).await.ok()?;
+	let field = multipart.next_field().await.ok()??;
+	if field.name() != Some(FIELD_NAME) {
+		return None;
+	}
+	field.text().await.ok()
+}
@@ -0,0 +1,259 @@
+// bulk import of titles from CSV, previewed as a dry run before being committed
+
+use axum::{
+	Form,
+	extract::State,
+	extract::Multipart,
+	response::Redirect,
+};
+use maud::{html, Markup, DOCTYPE};
+use uuid::Uuid;
+use crate::types::*;
+use crate::roles::{Authorized, Catalog};
+use crate::catalog::{parse_isbn, import_entries, view_import_report, MAX_COPIES};
+use crate::{SharedState, csrf};
+
+// previews nobody committed are dropped after this long
+const PENDING_IMPORT_HOURS: i64 = 2;
+
+// header names recognized for each field when no column is given
+const ISBN_HEADERS: [&str; 2] = ["isbn", "isbn13"];
+const TITLE_HEADERS: [&str; 2] = ["title", "name"];
+const PUBLISHED_HEADERS: [&str; 3] = ["published", "year", "date"];
+const AUTHORS_HEADERS: [&str; 2] = ["authors", "author"];
+const COPIES_HEADERS: [&str; 4] = ["copies", "copy count", "count", "quantity"];
+
+#[derive(Debug, Default)]
+struct ColumnNames {
+	isbn: String,
+	title: String,
+	published: String,
+	authors: String,
+	copies: String,
+}
+
+struct Columns {
+	isbn: usize,
+	title: usize,
+	published: Option<usize>,
+	authors: Option<usize>,
+	copies: Option<usize>,
+}
+
+fn find_column(headers: &csv::StringRecord, given: &str, known: &[&str]) -> Result<Option<usize>, String> {
+	let given = given.trim();
+	if !given.is_empty() {
+		return headers.iter()
+			.position(|header| header.eq_ignore_ascii_case(given))
+			.map(Some)
+			.ok_or(format!("No column named \"{given}\""));
+	}
+	Ok( headers.iter().position(|header| {
+		known.iter().any(|known| header.trim().eq_ignore_ascii_case(known))
+	}) )
+}
+
+fn find_columns(headers: &csv::StringRecord, names: &ColumnNames) -> Result<Columns, String> {
+	Ok(Columns{
+		isbn: find_column(headers, &names.isbn, &ISBN_HEADERS)?
+			.ok_or("Can't find the ISBN column")?,
+		title: find_column(headers, &names.title, &TITLE_HEADERS)?
+			.ok_or("Can't find the title column")?,
+		published: find_column(headers, &names.published, &PUBLISHED_HEADERS)?,
+		authors: find_column(headers, &names.authors, &AUTHORS_HEADERS)?,
+		copies: find_column(headers, &names.copies, &COPIES_HEADERS)?,
+	})
+}
+
+fn row_to_entry(row: &csv::StringRecord, columns: &Columns) -> Result<CatalogEntry, String> {
+	let cell = |at: Option<usize>| at.and_then(|at| row.get(at)).unwrap_or("").trim();
+
+	let isbn = cell(Some(columns.isbn));
+	if isbn.is_empty() {
+		return Err("no ISBN".to_owned());
+	}
+	let isbn = parse_isbn(isbn)?;
+	let name = cell(Some(columns.title));
+	if name.is_empty() {
+		return Err("no title".to_owned());
+	}
+	let authors = cell(columns.authors)
+		.split(';')
+		.map(str::trim)
+		.filter(|author| !author.is_empty())
+		.map(str::to_owned)
+		.collect();
+	let copies = match cell(columns.copies) {
+		"" => 1,
+		copies => copies.parse::<usize>()
+			.ok()
+			.filter(|copies| (1..=MAX_COPIES).contains(copies))
+			.ok_or(format!("copy count \"{copies}\" isn't a number from 1 to {MAX_COPIES}"))?,
+	};
+
+	Ok(CatalogEntry{
+		ISBN: isbn,
+		name: name.to_owned(),
+		published: cell(columns.published).to_owned(),
+		authors,
+		copies,
+	})
+}
+
+// entries numbered by their line in the file, the header being line 1
+fn parse_csv(file: &[u8], names: &ColumnNames) -> Result<Vec<ImportRecord>, String> {
+	let mut reader = csv::ReaderBuilder::new()
+		.flexible(true)
+		.from_reader(file);
+	let headers = reader.headers().map_err(|e| e.to_string())?.clone();
+	let columns = find_columns(&headers, names)?;
+
+	let mut entries = Vec::new();
+	for (at, row) in reader.records().enumerate() {
+		let (line, entry) = match row {
+			Ok(row)=>{
+				let line = row.position().map_or(at+2, |position| position.line() as usize);
+				(line, row_to_entry(&row, &columns))
+			}
+			Err(e)=>{
+				let line = e.position().map_or(at+2, |position| position.line() as usize);
+				(line, Err(e.to_string()))
+			}
+		};
+		entries.push((line, entry));
+	}
+	Ok(entries)
+}
+
+pub async fn display_csv_import(
+	_: Authorized<Catalog>,
+) -> Result<Markup, Redirect> {
+	Ok( view_csv_import("") )
+}
+
+pub async fn perform_csv_preview(
+	State(stt): State<SharedState>,
+	Authorized(acc, _): Authorized<Catalog>,
+	mut multipart: Multipart,
+) -> Result<Markup, Redirect> {
+	let mut file = Vec::new();
+	let mut names = ColumnNames::default();
+	loop {
+		let field = match multipart.next_field().await {
+			Ok(Some(field))=>field,
+			Ok(None)=>break,
+			Err(e)=>return Ok(view_csv_import(&e.to_string())),
+		};
+		let name = field.name().unwrap_or("").to_owned();
+		if name == "file" {
+			match field.bytes().await {
+				Ok(bytes)=>file = bytes.to_vec(),
+				Err(e)=>return Ok(view_csv_import(&e.to_string())),
+			}
+			continue;
+		}
+		let value = field.text().await.unwrap_or_default();
+		match name.as_str() {
+			"isbn_column"=>names.isbn = value,
+			"title_column"=>names.title = value,
+			"published_column"=>names.published = value,
+			"authors_column"=>names.authors = value,
+			"copies_column"=>names.copies = value,
+			_=>{}
+		}
+	}
+	if file.is_empty() {
+		return Ok(view_csv_import("No file uploaded"));
+	}
+
+	let entries = match parse_csv(&file, &names) {
+		Ok(entries)=>entries,
+		Err(e)=>return Ok(view_csv_import(&e)),
+	};
+
+	let mut state = stt.lock().await;
+	let rows = match import_entries(&mut state, entries.clone(), true).await {
+		Ok(rows)=>rows,
+		Err(e)=>return Ok(view_csv_import(&e.to_string())),
+	};
+
+	let now = chrono::Utc::now();
+	state.pending_imports.retain(|_, pending| {
+		now - pending.created < chrono::Duration::hours(PENDING_IMPORT_HOURS)
+	});
+	let token = Uuid::new_v4();
+	state.pending_imports.insert(token, PendingImport{
+		uid: acc.uid,
+		entries,
+		created: now,
+	});
+
+	let importable = rows.iter().any(|row| !matches!(row.outcome, ImportOutcome::Skipped(_)));
+	let actions = html! {
+		@if importable {
+			form method="POST" action="/import/csv/commit" {
+				(csrf::field())
+				input type="hidden" name="token" value=(token) {}
+				button { "Commit import" }
+			}
+		}
+	};
+	Ok( view_import_report("CSV import preview", &rows, true, "/import/csv", actions) )
+}
+
+pub async fn perform_csv_commit(
+	State(stt): State<SharedState>,
+	Authorized(acc, _): Authorized<Catalog>,
+	Form(form): Form<ImportCommitForm>,
+) -> Result<Markup, Redirect> {
+	let mut state = stt.lock().await;
+
+	let pending = match state.pending_imports.get(&form.token) {
+		Some(pending) if pending.uid == acc.uid=>pending.clone(),
+		_=>return Ok(view_csv_import("That preview expired, upload the file again")),
+	};
+	state.pending_imports.remove(&form.token);
+
+	Ok( match import_entries(&mut state, pending.entries, false).await {
+		Ok(rows)=>view_import_report("CSV import", &rows, false, "/import/csv", html!{}),
+		Err(e)=>view_csv_import(&format!("Import rolled back: {e}")),
+	} )
+}
+
+fn view_csv_import(error: &str) -> Markup {
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		title { "LSYS - CSV import" }
+	} body {
+		p style="color: red;" { (error) }
+
+		fieldset {
+			legend { "CSV import" }
+			form method="POST" action="/import/csv" enctype="multipart/form-data" {
+				(csrf::field())
+				label for="csv-file" { "file:" }
+				input id="csv-file" name="file" type="file" accept=".csv,text/csv" {}
+				br {}
+				p { "Columns, leave empty to find them by header name:" }
+				label for="csv-isbn" { "ISBN:" }
+				input id="csv-isbn" name="isbn_column" type="text" placeholder="isbn" {}
+				br {}
+				label for="csv-title" { "title:" }
+				input id="csv-title" name="title_column" type="text" placeholder="title" {}
+				br {}
+				label for="csv-published" { "published:" }
+				input id="csv-published" name="published_column" type="text" placeholder="published" {}
+				br {}
+				label for="csv-authors" { "authors:" }
+				input id="csv-authors" name="authors_column" type="text" placeholder="authors" {}
+				br {}
+				label for="csv-copies" { "copies:" }
+				input id="csv-copies" name="copies_column" type="text" placeholder="copies" {}
+				br {}
+				button { "Preview" }
+			}
+			p { "Separate multiple authors with \";\". Rows without a copy count get one copy." }
+		}
+	} }
+}
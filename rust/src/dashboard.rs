@@ -0,0 +1,123 @@
+// a patron's own page: the copies they have, their holds and what they might read next
+
+use axum::{
+	extract::State,
+	response::Redirect,
+};
+use maud::{html, Markup, DOCTYPE};
+use tower_cookies::Cookies;
+use crate::types::*;
+use crate::{
+	SharedState, ServerState,
+	read_state, read_account, make_redirect, login_url,
+	days_until, recommend, roles, verification, works, csrf,
+};
+
+pub async fn display_dashboard(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+) -> Result<Markup, Redirect> {
+	let state = read_state(stt).await;
+	let loginback = make_redirect(login_url("/dashboard"));
+	let acc = read_account(state.clone(), cookies, loginback)?;
+
+	Ok( view_dashboard(&state, &acc) )
+}
+
+fn view_dashboard(state: &ServerState, viewer: &Account) -> Markup {
+	let mut books = state.bid_to_book.values()
+		.filter(|book| book.status.get().is_with_viewer(viewer.uid).0)
+		.collect::<Vec<_>>();
+	books.sort_by_key(|book| book.bid);
+
+	let mut holds = state.wid_to_holds.iter()
+		.filter(|(_, holds)| holds.iter().any(|hold| hold.user_id == viewer.uid))
+		.filter_map(|(wid, _)| state.wid_to_work.get(wid))
+		.collect::<Vec<_>>();
+	holds.sort_by_key(|work| work.id);
+
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		title { "LSYS - Dashboard" }
+	} body {
+		h1 { {"Hello, " (viewer.name)} }
+		nav {
+			a href="/" { "Catalog" }
+			" | "
+			a href="/search" { "Search" }
+			" | "
+			a href="/account" { "Account" }
+			" | "
+			a href="/sessions" { "Sessions" }
+			" | "
+			a href="/two_factor" { "Two-factor" }
+			" | "
+			a href="/passkeys" { "Passkeys" }
+			" | "
+			a href="/tokens" { "API tokens" }
+			@if roles::has_permission(state, viewer, Permission::Circulate) {
+				" | "
+				a href="/accounts/unverified" { "Unconfirmed accounts" }
+			}
+			@if roles::has_permission(state, viewer, Permission::ManageAccounts) {
+				" | "
+				a href="/admin/roles" { "Roles" }
+				" | "
+				a href="/admin/lockouts" { "Lockouts" }
+			}
+			" | "
+			form method="POST" action="/logout" style="display: inline;" {
+				(csrf::field())
+				button { "Log out" }
+			}
+		}
+
+		@if !viewer.verified {
+			section id="verify" {
+				(verification::view_resend())
+			}
+		}
+
+		section id="loans" {
+			h2 { "Your books" }
+			@if books.is_empty() {
+				p { "You have no books right now." }
+			}
+			ul {
+				@for book in &books {
+					@let status = book.status.get();
+					li {
+						a href={"/book?bid=" (book.bid)} { i { (book.name) } }
+						@match status {
+							BorrowStatus::Reserved(_, until)=>{
+								{", reserved, pick it up within " (days_until(until)) " days"}
+							},
+							BorrowStatus::Borrowed(_, until)=>{
+								{", borrowed, return it within " (days_until(until)) " days"}
+							},
+							BorrowStatus::Avaliable=>{},
+						}
+					}
+				}
+			}
+		}
+
+		@if !holds.is_empty() {
+			section id="holds" {
+				h2 { "Your holds" }
+				ul {
+					@for work in &holds {
+						li {
+							a href={"/work?wid=" (work.id)} { i { (work.title) } }
+							@if let Some(position) = works::hold_position(state, work.id, viewer.uid) {
+								{", number " (position) " in line"}
+							}
+						}
+					}
+				}
+			}
+		}
+
+		(recommend::view_patron_recommendations(state, viewer))
+	} }
+}
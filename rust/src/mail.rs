@@ -0,0 +1,135 @@
+// sending mail to patrons, through a transport picked at startup
+
+use async_trait::async_trait;
+use lettre::{
+	AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+	message::{header::ContentType, Mailbox},
+	transport::smtp::authentication::Credentials,
+};
+use std::sync::Arc;
+
+const DEFAULT_MAIL_DIR: &str = "mail";
+// submission with STARTTLS, 465 is taken to mean TLS from the start
+const DEFAULT_SMTP_PORT: u16 = 587;
+const SMTPS_PORT: u16 = 465;
+const DEFAULT_PUBLIC_URL: &str = "http://localhost:8080";
+
+#[derive(Debug, Clone)]
+pub struct Mail {
+	pub to: String,
+	pub subject: String,
+	pub body: String,
+}
+
+#[async_trait]
+pub trait MailTransport: std::fmt::Debug + Send + Sync {
+	async fn send(&self, mail: &Mail) -> Result<(), String>;
+}
+
+// MAIL_TRANSPORT picks "smtp" (the default once SMTP_HOST is set), or for development "log"
+// or "file", which writes to MAIL_DIR; patrons would never get their mail, so without any
+// the server refuses to start
+pub fn transport_from_env() -> Arc<dyn MailTransport> {
+	let transport = std::env::var("MAIL_TRANSPORT").ok()
+		.or(std::env::var("SMTP_HOST").ok().map(|_| "smtp".to_owned()));
+	match transport.as_deref() {
+		Some("smtp")=>Arc::new(SmtpTransport::from_env()),
+		Some("file")=>{
+			let dir = std::env::var("MAIL_DIR").unwrap_or(DEFAULT_MAIL_DIR.to_owned());
+			Arc::new(FileTransport{ dir })
+		}
+		Some("log")=>Arc::new(LogTransport),
+		Some(other)=>panic!("MAIL_TRANSPORT is \"{other}\", it must be smtp, file or log"),
+		None=>panic!("no mail transport: set SMTP_HOST (and SMTP_PORT, SMTP_USER, SMTP_PASS, MAIL_FROM), or MAIL_TRANSPORT=log or file"),
+	}
+}
+
+// links in mail point here, PUBLIC_URL overrides it
+pub fn public_url() -> String {
+	std::env::var("PUBLIC_URL")
+		.unwrap_or(DEFAULT_PUBLIC_URL.to_owned())
+		.trim_end_matches('/')
+		.to_owned()
+}
+
+fn format_mail(mail: &Mail) -> String {
+	format!(
+		"To: {}\r\nSubject: {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n",
+		mail.to, mail.subject, chrono::Utc::now().to_rfc2822(), mail.body,
+	)
+}
+
+#[derive(Debug)]
+pub struct LogTransport;
+
+#[async_trait]
+impl MailTransport for LogTransport {
+	async fn send(&self, mail: &Mail) -> Result<(), String> {
+		eprintln!("mail:\n{}", format_mail(mail));
+		Ok(())
+	}
+}
+
+// one .eml file per mail, for development and for a separate process to pick up
+#[derive(Debug)]
+pub struct FileTransport {
+	dir: String,
+}
+
+#[async_trait]
+impl MailTransport for FileTransport {
+	async fn send(&self, mail: &Mail) -> Result<(), String> {
+		tokio::fs::create_dir_all(&self.dir).await.map_err(|e| e.to_string())?;
+		let token = crate::sessions::new_token();
+		let name = format!(
+			"{}/{}-{}.eml",
+			self.dir, chrono::Utc::now().format("%Y%m%d%H%M%S"), &token[..8],
+		);
+		tokio::fs::write(&name, format_mail(mail)).await.map_err(|e| e.to_string())
+	}
+}
+
+// SMTP_HOST and MAIL_FROM are required, SMTP_USER and SMTP_PASS when the relay wants a login
+#[derive(Debug)]
+pub struct SmtpTransport {
+	relay: AsyncSmtpTransport<Tokio1Executor>,
+	from: Mailbox,
+}
+
+impl SmtpTransport {
+	fn from_env() -> Self {
+		let host = std::env::var("SMTP_HOST").expect("MAIL_TRANSPORT=smtp needs SMTP_HOST");
+		let port = match std::env::var("SMTP_PORT") {
+			Ok(port)=>port.parse::<u16>().expect("SMTP_PORT isn't a port number"),
+			Err(_)=>DEFAULT_SMTP_PORT,
+		};
+		let from = std::env::var("MAIL_FROM").expect("MAIL_TRANSPORT=smtp needs MAIL_FROM")
+			.parse::<Mailbox>().expect("MAIL_FROM isn't a mail address");
+
+		let builder = if port == SMTPS_PORT {
+			AsyncSmtpTransport::<Tokio1Executor>::relay(&host)
+		} else {
+			AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)
+		}.expect("can't set up TLS for SMTP_HOST").port(port);
+		let builder = match (std::env::var("SMTP_USER"), std::env::var("SMTP_PASS")) {
+			(Ok(user), Ok(pass))=>builder.credentials(Credentials::new(user, pass)),
+			_=>builder,
+		};
+		SmtpTransport{ relay: builder.build(), from }
+	}
+}
+
+#[async_trait]
+impl MailTransport for SmtpTransport {
+	async fn send(&self, mail: &Mail) -> Result<(), String> {
+		let to = mail.to.parse::<Mailbox>().map_err(|e| e.to_string())?;
+		let message = Message::builder()
+			.from(self.from.clone())
+			.to(to)
+			.subject(&mail.subject)
+			.header(ContentType::TEXT_PLAIN)
+			.body(mail.body.clone())
+			.map_err(|e| e.to_string())?;
+		self.relay.send(message).await.map(|_| ()).map_err(|e| e.to_string())
+	}
+}
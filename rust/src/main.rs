@@ -4,10 +4,13 @@
 
 use axum::{
 	Form,
-	routing::get,
+	routing::{get, post},
 	extract::State,
 	response::Redirect,
 	extract::Query,
+	extract::DefaultBodyLimit,
+	extract::ConnectInfo,
+	http::HeaderMap,
 };
 #[allow(unused_imports)]
 use axum::debug_handler;
@@ -18,15 +21,45 @@ use serde::{Deserialize};
 use chrono::{NaiveDate};
 use std::{
 	sync::Arc,
-	collections::HashMap,
+	collections::{HashMap, HashSet},
+	net::{IpAddr, SocketAddr},
 };
 use uuid::Uuid;
-use tower_cookies::{Cookie, CookieManagerLayer, Cookies};
+use tower_cookies::{CookieManagerLayer, Cookies};
 use tower_http::services::{ServeDir, ServeFile};
 mod types;
+mod taxonomy;
+mod series;
+mod works;
+mod catalog;
+mod marc;
+mod csv_import;
+mod citation;
+mod covers;
+mod metadata;
+mod authors;
+mod recommend;
+mod dashboard;
+mod passwords;
+mod sessions;
+mod roles;
+mod mail;
+mod password_reset;
+mod settings;
+mod verification;
+mod csrf;
+mod throttle;
+mod tls;
+mod two_factor;
+mod passkeys;
+mod api_tokens;
+mod api;
+mod account;
 use types::*;
+use roles::Authorized;
 
-const COOKIE_UUID_NAME: &str = "lsys-uuid";
+const MAX_IMPORT_SIZE: usize = 64 * 1024 * 1024;
+const MAX_COVER_SIZE: usize = 16 * 1024 * 1024;
 #[tokio::main]
 async fn main() {
 	dotenvy::dotenv().unwrap();
@@ -40,22 +73,114 @@ async fn main() {
 		.connect(&db_connection_str).await
 		.expect("can't connect to database");
 
+	let state = new_shared_state(pool).await;
+	recommend::spawn_refresh(state.clone());
+	sessions::spawn_cleanup(state.clone());
+
 	let app = axum::Router::new()
 		.route("/", get(display_all) )
 		.route("/login", get(display_login).post(perform_login) )
+		.route("/login/two_factor", get(two_factor::display_second_step)
+			.post(two_factor::perform_second_step))
+		.route("/login/passkey", get(passkeys::display_passkey_login)
+			.post(passkeys::perform_passkey_login))
 		.route("/register", get(display_login).post(perform_register) )
 		.route("/book", get( display_book ))
+		.route("/logout", post(sessions::perform_logout))
+		.route("/sessions", get(sessions::display_sessions))
+		.route("/sessions/end", post(sessions::perform_end_session))
+		.route("/sessions/end_all", post(sessions::perform_end_all_sessions))
+		.route("/password", post(passwords::perform_change_password))
+		.route("/password/forgot", get(password_reset::display_forgot_password)
+			.post(password_reset::perform_forgot_password))
+		.route("/password/reset", get(password_reset::display_reset_password)
+			.post(password_reset::perform_reset_password))
+		.route("/admin/roles", get(roles::display_roles))
+		.route("/admin/roles/assign", post(roles::perform_assign_role))
+		.route("/admin/lockouts", get(throttle::display_lockouts))
+		.route("/admin/lockouts/lift", post(throttle::perform_lift_lockout))
+		.route("/admin/two_factor", post(two_factor::perform_policy))
+		.route("/two_factor", get(two_factor::display_two_factor))
+		.route("/two_factor/setup", post(two_factor::perform_setup))
+		.route("/two_factor/enable", post(two_factor::perform_enable))
+		.route("/two_factor/recovery", post(two_factor::perform_new_recovery_codes))
+		.route("/two_factor/disable", post(two_factor::perform_disable))
+		.route("/passkeys", get(passkeys::display_passkeys))
+		.route("/passkeys/add", post(passkeys::perform_add_passkey))
+		.route("/passkeys/remove", post(passkeys::perform_remove_passkey))
+		.route("/tokens", get(api_tokens::display_api_tokens))
+		.route("/tokens/new", post(api_tokens::perform_new_api_token))
+		.route("/tokens/revoke", post(api_tokens::perform_revoke_api_token))
+		.route("/api/loans", get(api::display_loans))
+		.route("/account", get(account::display_account))
+		.route("/account/name", post(account::perform_change_name))
+		.route("/account/email", post(account::perform_change_email))
+		.route("/account/export", get(account::display_export))
+		.route("/account/delete", post(account::perform_delete_account))
+		.route("/verify", get(verification::display_verify))
+		.route("/verify/resend", post(verification::perform_resend_verification))
+		.route("/accounts/unverified", get(verification::display_unverified))
+		.route("/accounts/verify", post(verification::perform_staff_verify))
+		.route("/accounts/resend_verification", post(verification::perform_staff_resend))
+		.route("/dashboard", get(dashboard::display_dashboard))
 		.route("/reserve", get(display_reserve_book).post(perform_reserve))
+		.route("/author", get(authors::display_author))
+		.route("/authors/duplicates", get(authors::display_duplicate_authors))
+		.route("/authors/merge", post(authors::perform_merge_authors))
+		.route("/author/authority", post(authors::perform_update_authority))
+		.route("/author/names/add", post(authors::perform_add_alt_name))
+		.route("/author/names/remove", post(authors::perform_remove_alt_name))
+		.route("/contributors/add", post(authors::perform_add_contributor))
+		.route("/contributors/remove", post(authors::perform_remove_contributor))
+		.route("/books/new", get(metadata::display_add_book).post(metadata::perform_add_book))
+		.route("/search", get(display_search))
+		.route("/search/cite", get(citation::display_search_citation))
+		.route("/cite", get(citation::display_book_citation))
+		.route("/subjects", get(taxonomy::display_subjects))
+		.route("/subject", get(taxonomy::display_subject))
+		.route("/subjects/manage", get(taxonomy::display_manage_subjects))
+		.route("/subjects/new", post(taxonomy::perform_new_subject))
+		.route("/subjects/delete", post(taxonomy::perform_delete_subject))
+		.route("/subjects/assign", post(taxonomy::perform_assign_subject))
+		.route("/subjects/unassign", post(taxonomy::perform_unassign_subject))
+		.route("/tags/add", post(taxonomy::perform_add_tag))
+		.route("/tags/remove", post(taxonomy::perform_remove_tag))
+		.route("/series", get(series::display_all_series))
+		.route("/series/show", get(series::display_series))
+		.route("/series/manage", get(series::display_manage_series))
+		.route("/series/new", post(series::perform_new_series))
+		.route("/series/add", post(series::perform_add_volume))
+		.route("/series/remove", post(series::perform_remove_volume))
+		.route("/work", get(works::display_work))
+		.route("/work/hold", post(works::perform_hold))
+		.route("/work/unhold", post(works::perform_cancel_hold))
+		.route("/works/manage", get(works::display_manage_works))
+		.route("/works/new", post(works::perform_new_work))
+		.route("/works/link", post(works::perform_link_edition))
+		.route("/works/unlink", post(works::perform_unlink_edition))
+		.route("/marc", get(marc::display_marc))
+		.route("/marc/import", post(marc::perform_marc_import)
+			.layer(DefaultBodyLimit::max(MAX_IMPORT_SIZE)))
+		.route("/marc/export", get(marc::display_marc_export))
+		.route("/import/csv", get(csv_import::display_csv_import)
+			.post(csv_import::perform_csv_preview)
+			.layer(DefaultBodyLimit::max(MAX_IMPORT_SIZE)))
+		.route("/import/csv/commit", post(csv_import::perform_csv_commit))
+		.route("/covers/upload", post(covers::perform_upload_cover)
+			.layer(DefaultBodyLimit::max(MAX_COVER_SIZE)))
+		.route("/covers/remove", post(covers::perform_remove_cover))
+		.route("/covers/placeholder", get(covers::display_placeholder_cover))
 		.route("/test", get(dtest))
+		.layer(axum::middleware::from_fn(csrf::protect))
 		.layer(CookieManagerLayer::new())
+		.layer(axum::middleware::from_fn_with_state(state.clone(), api_tokens::strip_cookies))
 		.nest_service("/files",
 			ServeDir::new("files")
 				.fallback(ServeFile::new("files/404.html"))
 		)
-		.with_state(new_shared_state(pool).await);
+		.with_state(state);
 
-	let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await.unwrap();
-	axum::serve(listener, app).await.unwrap();
+	tls::serve(app).await;
 }
 
 //TODO: use sub-state to account maps, book maps
@@ -65,37 +190,144 @@ type SharedState = Arc<tokio::sync::Mutex<ServerState>>;
 struct ServerState {
 	db: sqlx::Pool<sqlx::Sqlite>,
 	bid_to_book: HashMap<Bid, Book>,
-	uuid_to_account: HashMap<Uuid, Arc<Account>>,
+	hash_to_session: HashMap<String, Arc<Session>>,
+	rid_to_role: HashMap<Rid, Arc<Role>>,
+	session_limits: SessionLimits,
+	ip_to_failures: HashMap<IpAddr, LoginFailures>,
+	email_to_failures: HashMap<String, LoginFailures>,
+	token_to_pending_login: HashMap<String, PendingLogin>,
+	two_factor_uids: HashSet<Uid>,
+	staff_two_factor: bool,
+	passkey_challenges: HashMap<String, PasskeyChallenge>,
+	hash_to_api_token: HashMap<String, Arc<ApiToken>>,
 	uid_to_account: HashMap<Uid, Arc<Account>>,
 	email_to_uid: HashMap<String, Uid>,
-	ISBN_to_authors: HashMap<Bid, Vec<Arc<Author>>>,
+	ISBN_to_authors: HashMap<ISBN, Vec<Contributor>>,
 	aid_to_authors: HashMap<Aid, Arc<Author>>,
+	aid_redirects: HashMap<Aid, Aid>,
+	sid_to_subject: HashMap<Sid, Arc<Subject>>,
+	ISBN_to_subjects: HashMap<ISBN, Vec<Sid>>,
+	ISBN_to_tags: HashMap<ISBN, Vec<String>>,
+	srid_to_series: HashMap<Srid, Arc<Series>>,
+	srid_to_volumes: HashMap<Srid, Vec<SeriesVolume>>,
+	ISBN_to_series: HashMap<ISBN, Srid>,
+	wid_to_work: HashMap<Wid, Arc<Work>>,
+	ISBN_to_edition: HashMap<ISBN, Edition>,
+	wid_to_holds: HashMap<Wid, Vec<Hold>>,
+	pending_imports: HashMap<Uuid, PendingImport>,
+	recommendations: Recommendations,
+	ISBN_to_cover: HashMap<ISBN, Cover>,
+	metadata: Arc<dyn metadata::MetadataProvider>,
+	mail: Arc<dyn mail::MailTransport>,
+	link_secret: String,
 	visits: i64,
 }
 
 async fn new_shared_state(db: sqlx::Pool<sqlx::Sqlite>) -> SharedState {
 	let accounts = sqlx::query_as!(
 		AccountQuery,
-		"SELECT id, name, email, pass_hash, is_worker FROM accounts;",
+		"SELECT id, name, email, pass_hash, role_id, verified FROM accounts WHERE deleted IS NULL;",
 	).fetch_all(&db).await.expect("can't parse row from accounts into AccountQuery");
 
-	let authors = sqlx::query_as!(
-		Author,
-		"SELECT id, name FROM authors",
-	).fetch_all(&db).await.expect("can't parse row from authors into Author");
+	let mut state = ServerState{
+		db,
+		bid_to_book: HashMap::new(),
+		hash_to_session: HashMap::new(),
+		rid_to_role: HashMap::new(),
+		session_limits: sessions::limits_from_env(),
+		ip_to_failures: HashMap::new(),
+		email_to_failures: HashMap::new(),
+		token_to_pending_login: HashMap::new(),
+		two_factor_uids: HashSet::new(),
+		staff_two_factor: false,
+		passkey_challenges: HashMap::new(),
+		hash_to_api_token: HashMap::new(),
+		uid_to_account: HashMap::new(),
+		email_to_uid: HashMap::new(),
+		aid_to_authors: HashMap::new(),
+		aid_redirects: HashMap::new(),
+		ISBN_to_authors: HashMap::new(),
+		sid_to_subject: HashMap::new(),
+		ISBN_to_subjects: HashMap::new(),
+		ISBN_to_tags: HashMap::new(),
+		srid_to_series: HashMap::new(),
+		srid_to_volumes: HashMap::new(),
+		ISBN_to_series: HashMap::new(),
+		wid_to_work: HashMap::new(),
+		ISBN_to_edition: HashMap::new(),
+		wid_to_holds: HashMap::new(),
+		pending_imports: HashMap::new(),
+		recommendations: Recommendations::default(),
+		ISBN_to_cover: HashMap::new(),
+		metadata: metadata::provider_from_env(),
+		mail: mail::transport_from_env(),
+		link_secret: String::new(),
+		visits: 0,
+	};
+
+	accounts.iter()
+		.map(Account::from_query)
+		.for_each( |acc| Account::update_maps(&mut state, acc) );
+	sessions::load(&mut state).await;
+	throttle::load(&mut state).await;
+	roles::load(&mut state).await;
+	two_factor::load(&mut state).await;
+	api_tokens::load(&mut state).await;
+	verification::load(&mut state).await;
+	catalog::convert_isbn_10s(&state.db).await.expect("can't convert ISBN-10s to ISBN-13s");
+	load_catalog(&mut state).await;
+	authors::load(&mut state).await;
+	taxonomy::load(&mut state).await;
+	series::load(&mut state).await;
+	works::load(&mut state).await;
+	covers::load(&mut state).await;
+
+	Arc::new( tokio::sync::Mutex::new( state ))
+}
+
+// the sample catalog of schema.sql, in memory, for tests
+#[cfg(test)]
+async fn sample_db() -> sqlx::Pool<sqlx::Sqlite> {
+	use sqlx::Executor;
+	let db = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap();
+	db.execute(include_str!("../schema.sql")).await.unwrap();
+	std::env::set_var("MAIL_TRANSPORT", "log");
+	db
+}
+
+// (re)loads books and authors from the db, keeping the status of copies already in memory
+async fn load_catalog(state: &mut ServerState) {
+	let authors = sqlx::query!(
+		"SELECT id, name, born, died FROM authors",
+	).fetch_all(&state.db).await.expect("can't parse row from authors");
+
+	let alt_names = sqlx::query!(
+		"SELECT author_id, name FROM author_names ORDER BY name",
+	).fetch_all(&state.db).await.expect("can't parse row from author_names");
 
 	let wrotes = sqlx::query!(
-		"SELECT author_id, ISBN FROM wrote",
-	).fetch_all(&db).await.expect("can't parse row from wrote");
+		"SELECT author_id, ISBN, role FROM wrote ORDER BY rowid",
+	).fetch_all(&state.db).await.expect("can't parse row from wrote");
+
+	let mut aid_to_alt_names = HashMap::<Aid, Vec<String>>::new();
+	for alt_name in alt_names {
+		aid_to_alt_names.entry(alt_name.author_id).or_default().push(alt_name.name);
+	}
 
 	//TODO: impl for Author
 	// : Author::update_maps(Self, &mut state, Vec<ISBN>)
 	// : Self.update_map(&mut state, ISBN)
-	let mut ISBN_to_authors = HashMap::<ISBN, Vec<Arc<Author>>>::new();
+	let mut ISBN_to_authors = HashMap::<ISBN, Vec<Contributor>>::new();
 	let mut ISBN_to_anames = HashMap::<ISBN, Vec<String>>::new();
 	let mut aid_to_authors = HashMap::<Aid, Arc<Author>>::new();
 	for author in authors {
-		let author = Arc::new(author);
+		let author = Arc::new(Author{
+			id: author.id,
+			name: author.name,
+			born: author.born,
+			died: author.died,
+			alt_names: aid_to_alt_names.remove(&author.id).unwrap_or_default(),
+		});
 		aid_to_authors.insert(author.id as Aid, author);
 	}
 
@@ -103,47 +335,35 @@ async fn new_shared_state(db: sqlx::Pool<sqlx::Sqlite>) -> SharedState {
 		let author = aid_to_authors
 			.get(&wrote.author_id)
 			.expect("author_id in Wrote doens't match to an author");
-		match ISBN_to_anames.get_mut(&wrote.ISBN) {
-			Some(authors)=>{authors.push(author.name.clone())}
-			None=>{
-				ISBN_to_anames.insert(wrote.ISBN, vec![author.name.clone()]);
-			}
-		}
-		match ISBN_to_authors.get_mut(&wrote.ISBN) {
-			Some(authors)=>{authors.push(Arc::clone(author))}
-			None=>{
-				ISBN_to_authors.insert(wrote.ISBN, vec![Arc::clone(author)]);
-			}
+		let role = ContributorRole::from_db(&wrote.role);
+		// a book's authors are the ones it's cited by, editors and translators aren't among them
+		if role == ContributorRole::Author {
+			ISBN_to_anames.entry(wrote.ISBN).or_default().push(author.name.clone());
 		}
+		ISBN_to_authors.entry(wrote.ISBN).or_default().push(Contributor{
+			author: Arc::clone(author),
+			role,
+		});
 	}
 
 	let books = sqlx::query_as!(
 		BookQuery,
 		"SELECT * FROM books INNER JOIN book_info USING(ISBN);"
-	).fetch_all(&db).await.expect("can't parse row from books into BookQuery");
+	).fetch_all(&state.db).await.expect("can't parse row from books into BookQuery");
 
 	let mut bid_to_book = HashMap::new();
 	for book in books {
 		let book = Book::from_query(&book, ISBN_to_anames.get(&book.ISBN));
+		// reservations only live in memory for now
+		if let Some(known) = state.bid_to_book.get(&book.bid) {
+			book.status.set(known.status.get());
+		}
 		bid_to_book.insert(book.bid, book);
 	}
 
-	let mut state = ServerState{
-		db,
-		bid_to_book,
-		uuid_to_account: HashMap::new(),
-		uid_to_account: HashMap::new(),
-		email_to_uid: HashMap::new(),
-		aid_to_authors,
-		ISBN_to_authors,
-		visits: 0,
-	};
-
-	accounts.iter()
-		.map(Account::from_query)
-		.for_each( |acc| Account::update_maps(&mut state, acc) );
-
-	Arc::new( tokio::sync::Mutex::new( state ))
+	state.bid_to_book = bid_to_book;
+	state.aid_to_authors = aid_to_authors;
+	state.ISBN_to_authors = ISBN_to_authors;
 }
 
 async fn read_state(
@@ -159,11 +379,26 @@ fn read_account(
 	cookies: Cookies,
 	red: Redirect,
 ) -> Result<Arc<Account>, Redirect> {
-	let acc = cookies.get(COOKIE_UUID_NAME).ok_or(red.clone())?;
-	let acc = acc.value().to_owned();
-	let acc = Uuid::parse_str(&acc).or(Err(red.clone()))?;
-	let acc = state.uuid_to_account.get(&acc).ok_or(red)?;
-	Ok(acc.clone())
+	read_viewer(&state, &cookies).ok_or(red)
+}
+
+// the logged in account, if any, for the pages anyone may browse
+fn read_viewer(
+	state: &ServerState,
+	cookies: &Cookies,
+) -> Option<Arc<Account>> {
+	let session = sessions::read_session(state, cookies)?;
+	state.uid_to_account.get(&session.user_id).cloned()
+}
+
+// path sending the user on to goto afterwards, goto may have its own query
+fn with_goto(path: &str, goto: &str) -> String {
+	let query = serde_urlencoded::to_string([("goto", goto)]).unwrap_or_default();
+	format!("{path}?{query}")
+}
+
+fn login_url(goto: &str) -> String {
+	with_goto("/login", goto)
 }
 
 fn make_redirect(
@@ -179,16 +414,16 @@ async fn display_reserve_book(
 	Form(reserve): Form<ReserveBookForm>
 ) -> Result<Markup, Redirect> {
 	let state = read_state(stt).await;
-	let loginback = make_redirect(format!("/login?goto=/reserve?bid={}", reserve.bid));
-	read_account(state.clone(), cookies, loginback)?;
+	let loginback = make_redirect(login_url(&format!("/reserve?bid={}", reserve.bid)));
+	let acc = read_account(state.clone(), cookies, loginback)?;
 
 	let book = state.bid_to_book.get(&reserve.bid);
 	let book = book.map(|book|{
 		let status = &book.status.get();
 		if let BorrowStatus::Avaliable = status {
-			view_avaliable_book(book)
+			view_avaliable_book(&state, book, &acc)
 		} else {
-			view_reserved_book(book, status)
+			view_reserved_book(&state, book, status, &acc)
 		}
 	});
 
@@ -211,18 +446,17 @@ async fn dtest(
 
 async fn perform_reserve(
 	State(stt): State<SharedState>,
-	cookies: Cookies,
+	Authorized(acc, _): Authorized<roles::Reserve>,
 	Form(reserve): Form<ReserveBookForm>
-) -> Result<Markup, Redirect> {
+) -> Result<Redirect, Markup> {
 	let state = Arc::clone(&stt);
 	let state = state.lock().await;
-	let loginback = make_redirect(format!("/login?goto=/book?bid={}", reserve.bid));
-	let home = make_redirect("/".to_owned());
-	let acc = read_account(state.clone(), cookies, loginback)?;
-	let book = state.bid_to_book.get(&reserve.bid).ok_or(home.clone())?;
+	let Some(book) = state.bid_to_book.get(&reserve.bid) else {
+		return Ok(make_redirect("/".to_owned()));
+	};
 	let reserve_error = book.reserve(&acc, &state.db);
 	if let Some(error) = reserve_error {
-		return Ok( match error {
+		return Err( match error {
 			ReserveBookError::Reserved(until)=>html!{ p { (until.format("%d/%m/%y")) } },
 			ReserveBookError::Borrowed(until)=>html!{ p { (until.format("%d/%m/%y")) } },
 			ReserveBookError::DBError(err)=>html!{ p { (err) } },
@@ -236,7 +470,7 @@ async fn perform_reserve(
 	let state = Arc::clone(&stt);
 	let mut state = state.lock().await;
 	state.bid_to_book.insert(book.bid, book.clone());
-	Ok(view_book(book.clone(), &acc))
+	Ok( make_redirect(format!("/book?bid={}", book.bid)) )
 }
 	//state.bid_to_book.insert(book.bid, book.clone());
 	//TODO: display_reserve_book
@@ -260,32 +494,65 @@ async fn perform_reserve(
 #[debug_handler]
 async fn perform_login(
 	State(stt): State<SharedState>,
+	ConnectInfo(addr): ConnectInfo<SocketAddr>,
 	cookies: Cookies,
+	headers: HeaderMap,
 	Query(goto): Query<Goto>,
 	Form(login): Form<FormLogin>,
 ) -> Result<Redirect, Markup> {
-	let state = Arc::clone(&stt);
-	let state = state.lock().await;
+	let goto = csrf::safe_goto(goto.goto.as_deref());
 
-	let goto = match &goto.goto {
-		Some(goto)=>goto.as_str(),
-		None=>"/",
+	let ip = addr.ip();
+	let acc = {
+		let state = stt.lock().await;
+		if let Err(secs) = throttle::check(&state, ip, &login.email) {
+			return Err(view_login(&throttle::view_wait(secs), "", goto));
+		}
+		state.email_to_uid.get(&login.email)
+			.and_then(|uid| state.uid_to_account.get(uid))
+			.cloned()
+	};
+	// the same answer, after the same work, whether the email or the password was wrong
+	let verified = passwords::verify(&login.pass, acc.as_ref().map(|acc| acc.pass_hash.as_str())).await;
+	// old hashes are swapped for argon2id now that the password is known
+	let upgraded = match verified {
+		passwords::Verified::Legacy=>Some(passwords::hash(&login.pass).await),
+		_=>None,
 	};
 
-	let acc = state.email_to_uid.get(&login.email).ok_or(view_login("No such email", "", goto))?;
-	let acc = state.uid_to_account.get(acc).ok_or(view_login("Can't find account", "", goto))?;
-	let pass_hash = hash_pass(login.pass.as_bytes());
-	if acc.pass_hash != pass_hash {
-		Err(view_login("Wrong password", "", goto))
-	} else {
-		cookies.add(
-			Cookie::build((COOKIE_UUID_NAME, acc.uuid.to_string()))
-				.path("/")
-				.secure(false)
-				.http_only(true).into()
-		);
-		Ok(Redirect::to( goto ))
+	let mut state = stt.lock().await;
+	// the account may have gone, or changed its password, while the lock was let go
+	let acc = acc
+		.filter(|_| verified != passwords::Verified::Wrong)
+		.filter(|acc| state.uid_to_account.get(&acc.uid).is_some_and(|current| current.pass_hash == acc.pass_hash));
+	let Some(acc) = acc else {
+		throttle::record_failure(&mut state, ip, &login.email).await;
+		return Err(view_login("Wrong email or password", "", goto));
+	};
+	throttle::record_success(&mut state, &login.email);
+
+	// failing to upgrade the hash isn't a reason to refuse the login
+	if let Some(pass_hash) = upgraded {
+		let result = match pass_hash {
+			Ok(pass_hash)=>passwords::set_pass_hash(&mut state, &acc, pass_hash).await,
+			Err(e)=>Err(e),
+		};
+		if let Err(e) = result {
+			eprintln!("can't upgrade password hash of account {}: {e}", acc.uid);
+		}
 	}
+	if two_factor::is_enabled(&state, acc.uid) {
+		cookies.add(two_factor::start_login(&mut state, acc.uid, sessions::user_agent(&headers)));
+		return Ok(make_redirect(with_goto("/login/two_factor", goto)));
+	}
+	let cookie = sessions::start(&mut state, acc.uid, sessions::user_agent(&headers)).await
+		.map_err(|e| view_login(&e, "", goto))?;
+	cookies.add(cookie);
+	// staff without two-factor set it up first when it is required
+	if two_factor::missing(&state, &acc) {
+		return Ok(make_redirect("/two_factor".to_owned()));
+	}
+	Ok(Redirect::to( goto ))
 }
 
 async fn display_book(
@@ -294,13 +561,12 @@ async fn display_book(
 	Query(bid): Query<BookParam>,
 ) -> Result<Markup, Redirect> {
 	let state = read_state(stt).await;
-	let loginback = make_redirect(format!("/login?goto=/book?bid={}", bid.bid));
-	let acc = read_account(state.clone(), cookies, loginback)?;
+	let acc = read_viewer(&state, &cookies);
 
 	let req_book = state.bid_to_book.get(&bid.bid);
 
 	Ok( match req_book {
-		Some(book)=>view_book(book.clone(), &acc),
+		Some(book)=>view_book(&state, book.clone(), acc.as_deref()),
 		None=>view_404(format!("/book?Bid={}", bid.bid)),
 	} )
 }
@@ -308,10 +574,7 @@ async fn display_book(
 async fn display_login(
 	Query(goto): Query<Goto>,
 ) -> Markup {
-	let goto = match &goto.goto {
-		Some(goto)=>goto.as_str(),
-		None=>"/",
-	};
+	let goto = csrf::safe_goto(goto.goto.as_deref());
 
 	view_login("", "", goto)
 }
@@ -319,59 +582,118 @@ async fn display_login(
 async fn perform_register(
 	State(stt): State<SharedState>,
 	cookies: Cookies,
+	headers: HeaderMap,
 	Query(goto): Query<Goto>,
 	Form(register): Form<FormRegister>,
 ) -> Result<Redirect, Markup> {
 
-	let goto = match &goto.goto {
-		Some(goto)=>goto.as_str(),
-		None=>"/",
-	};
+	let goto = csrf::safe_goto(goto.goto.as_deref());
 
-	let mut state = stt.lock().await;
-	let acc = Account::new(&state.db, register).await;
+	// the password is hashed without the state locked
+	let db = stt.lock().await.db.clone();
+	let acc = Account::new(&db, register).await;
 	let acc = acc.map_err(|e| view_login("", e.as_str(), goto))?;
-
-	cookies.add(
-		Cookie::build((COOKIE_UUID_NAME, acc.uuid.to_string()))
-			.path("/")
-			.secure(false)
-			.http_only(true).into()
-	);
+	let mut state = stt.lock().await;
+	let uid = acc.uid;
+	verification::send_verification(&state, &acc);
 	Account::update_maps(&mut state, acc);
 
-	Ok( Redirect::to("/") )
+	let cookie = sessions::start(&mut state, uid, sessions::user_agent(&headers)).await
+		.map_err(|e| view_login("", &e, goto))?;
+	cookies.add(cookie);
+
+	Ok( Redirect::to(goto) )
 }
 
 async fn display_all(
 	State(stt): State<SharedState>,
 	cookies: Cookies,
+	Query(filter): Query<CatalogFilter>,
 ) -> Result<Markup, Redirect> {
 	let state = Arc::clone(&stt);
 	let state = state.lock().await;
+	let acc = read_viewer(&state, &cookies);
+
+	let books = filter_books(&state, &filter);
+
+	Ok( view_all_books(&state, &books, &filter, acc.is_some()) )
+}
+
+async fn display_search(
+	State(stt): State<SharedState>,
+	Query(filter): Query<CatalogFilter>,
+) -> Result<Markup, Redirect> {
+	let state = read_state(stt).await;
 
-	//let books = &state.books;
+	let searched = filter.q.as_deref().is_some_and(|q| !q.trim().is_empty());
+	let books = if searched {
+		filter_books(&state, &filter)
+	} else {
+		Vec::new()
+	};
+
+	Ok( view_search(&state, &books, &filter) )
+}
 
-	//TODO make into function
-	let cookie = cookies.get(COOKIE_UUID_NAME);
-	let acc = cookie.ok_or(Redirect::to("/login"))?.value().to_owned();
-	let acc = Uuid::parse_str(&acc).or(Err(Redirect::to("/login")))?;
-	state.uuid_to_account.get(&acc).ok_or(Redirect::to("/login"))?;
+// books matching every given part of the filter, sorted by name
+fn filter_books(state: &ServerState, filter: &CatalogFilter) -> Vec<Book> {
+	let query = filter.q.as_deref()
+		.map(str::trim)
+		.filter(|q| !q.is_empty())
+		.map(str::to_lowercase);
+	let tag = filter.tag.as_deref()
+		.map(str::trim)
+		.filter(|tag| !tag.is_empty());
+	let subjects = filter.subject
+		.map(|sid| taxonomy::subject_descendants(state, sid));
 
 	let mut books = state.bid_to_book
-		.clone()
-		.into_values()
+		.values()
+		.filter(|book| query.as_ref().is_none_or(|q| book_matches(state, book, q)))
+		.filter(|book| tag.is_none_or(|tag| taxonomy::book_has_tag(state, book.ISBN, tag)))
+		.filter(|book| subjects.as_ref().is_none_or(|sids| taxonomy::book_in_subjects(state, book.ISBN, sids)))
+		.cloned()
 		.collect::<Vec<Book>>();
 	books.sort_by_key(|book|book.name.clone());
+	books
+}
+
+// a copy of the title to link to, preferring one that can be reserved
+fn title_copy(state: &ServerState, isbn: ISBN) -> Option<&Book> {
+	state.bid_to_book
+		.values()
+		.filter(|book| book.ISBN == isbn)
+		.min_by_key(|book| (!book.status.get().is_avaliable(), book.bid))
+}
+
+// (avaliable copies, total copies) of a title
+fn availability(state: &ServerState, isbn: ISBN) -> (usize, usize) {
+	state.bid_to_book
+		.values()
+		.filter(|book| book.ISBN == isbn)
+		.fold((0, 0), |(avaliable, total), book| {
+			(avaliable + book.status.get().is_avaliable() as usize, total+1)
+		})
+}
 
-	Ok( view_all_books(&books) )
+// query must already be lowercase, contributors match by any of their names
+fn book_matches(state: &ServerState, book: &Book, query: &str) -> bool {
+	let contributors = state.ISBN_to_authors.get(&book.ISBN).map(Vec::as_slice).unwrap_or_default();
+	book.ISBN.to_string() == query
+		|| catalog::format_isbn(book.ISBN) == query
+		|| catalog::parse_isbn(query).is_ok_and(|isbn| isbn == book.ISBN)
+		|| book.name.to_lowercase().contains(query)
+		|| book.authors.iter().any(|author| author.to_lowercase().contains(query))
+		|| contributors.iter().any(|contributor| {
+			std::iter::once(&contributor.author.name)
+				.chain(&contributor.author.alt_names)
+				.any(|name| name.to_lowercase().contains(query))
+		})
 }
 
-// password String -> hash i64 -> [u8] -> v3_uuid String
 impl Account {
 	fn update_maps(state: &mut ServerState, acc: Self) {
 		let acc = Arc::new(acc);
-		state.uuid_to_account.insert(acc.uuid, Arc::clone(&acc));
 		state.uid_to_account.insert(acc.uid, Arc::clone(&acc));
 		state.email_to_uid.insert(acc.email.clone(), acc.uid);
 	}
@@ -383,8 +705,11 @@ impl Account {
 		if form.name.is_empty() || form.email.is_empty() || form.pass.is_empty() {
 			return Err("Field with not input".to_string());
 		}
+		if !account::valid_email(&form.email) {
+			return Err("That isn't an email address".to_string());
+		}
 
-		let pass_hash = hash_pass(form.pass.as_bytes());
+		let pass_hash = passwords::hash(&form.pass).await?;
 		let result = sqlx::query!(
 	"INSERT INTO accounts
 		(name, email, pass_hash)
@@ -401,8 +726,8 @@ impl Account {
 			name: form.name,
 			email: form.email,
 			pass_hash,
-			uuid: Uuid::new_v4(),
-			is_worker: false,
+			role: roles::PATRON,
+			verified: false,
 		})
 	}
 
@@ -412,8 +737,8 @@ impl Account {
 			name: info.name.clone(),
 			email: info.email.clone(),
 			pass_hash: info.pass_hash.clone(),
-			uuid: Uuid::new_v4(),
-			is_worker: info.is_worker,
+			role: info.role_id,
+			verified: info.verified,
 		}
 	}
 }
@@ -427,7 +752,8 @@ fn view_login(log_error: &str, reg_error: &str, goto: &str) -> Markup {
 		p style="color: red;"{(reg_error)}
 		fieldset {
 			legend {"Login"}
-			form method="POST" action={"/login?goto="(goto)} {
+			form method="POST" action=(login_url(goto)) {
+				(csrf::field())
 				label for="login-email" {"email:"}
 				input id="login-email" name="email" type="email" placeholder="email" {}
 				br {}
@@ -436,11 +762,15 @@ fn view_login(log_error: &str, reg_error: &str, goto: &str) -> Markup {
 				br {}
 				button { "LogIn" }
 			}
+			a href="/password/forgot" { "Forgot your password?" }
+			" | "
+			a href=(with_goto("/login/passkey", goto)) { "Log in with a passkey" }
 		}
 
 		fieldset {
 			legend {"Register"}
-			form method="POST" action={"/register?goto="(goto)} {
+			form method="POST" action=(with_goto("/register", goto)) {
+				(csrf::field())
 				label for="register-username" {"username:"}
 				input id="register-username" name="name" type="text" placeholder="username" {}
 				br {}
@@ -469,38 +799,32 @@ fn view_404(query: String) -> Markup {
 	} }
 }
 
-fn view_reserved_book(book: &Book, status: &BorrowStatus) -> Markup {
+fn view_reserved_book(state: &ServerState, book: &Book, status: &BorrowStatus, viewer: &Account) -> Markup {
 	html!{ (DOCTYPE) head {
 		meta charset="UTF-8"{}
 		link rel="stylesheet" type="text/css" href="/files/css/book.css"{}
 		title { {"LSYS - " (book.name)} }
 	} body {
 		article {
-			aside { img
-				src={"/files/img/books/"(book.ISBN)}
-				onerror="this.src='/files/img/missing'" {}
-			}
+			aside { (covers::view_book_cover(state, book, roles::has_permission(state, viewer, Permission::Catalog))) }
 
 			section {
 				h1 id="book-name" { i { (book.name) } }
+				h2 id="book-authors" { "by " (authors::view_author_links(state, book)) }
 				h5 id="ISBN" { (book.ISBN) }
 				h2 { { "Published in: " (book.published) } }
 			}
 
-			section {
-				p { (format!("{book:#?}")) }
-			}
-
 			section {
 				h2 {{"Status: " (status.to_string())}}
 				@match status {
-					BorrowStatus::Reserved(uid, when)=>{
+					BorrowStatus::Reserved(_, when)=>{
 						@let days = days_until(when.to_owned());
 						p { {"days:" (days)} }
 						//p {"Still possible to read this book inside the library"}
 						//p {"Book will be taken in {{.borrower_time_left}} days"}
 					},
-					BorrowStatus::Borrowed(uid, until)=>{
+					BorrowStatus::Borrowed(_, until)=>{
 						@let days = days_until(until.to_owned());
 						@if days == 0 {
 							p {"Book should be returned today!"}
@@ -517,30 +841,25 @@ fn view_reserved_book(book: &Book, status: &BorrowStatus) -> Markup {
 	} }
 }
 
-fn view_avaliable_book(book: &Book) -> Markup {
+fn view_avaliable_book(state: &ServerState, book: &Book, viewer: &Account) -> Markup {
 	html! { (DOCTYPE) head {
 		meta charset="UTF-8"{}
 		link rel="stylesheet" type="text/css" href="/files/css/book.css"{}
 		title { {"LSYS - " (book.name)} }
 	} body {
 		article {
-			aside { img
-				src={"/files/img/books/"(book.ISBN)}
-				onerror="this.src='/files/img/missing'" {}
-			}
+			aside { (covers::view_book_cover(state, book, roles::has_permission(state, viewer, Permission::Catalog))) }
 
 			section {
 				h1 id="book-name" { i { (book.name) } }
+				h2 id="book-authors" { "by " (authors::view_author_links(state, book)) }
 				h5 id="ISBN" { (book.ISBN) }
 				h2 { { "Published in: " (book.published) } }
 			}
 
-			section {
-				p { (format!("{book:#?}")) }
-			}
-
 			section {
 				form method="POST" action={"/reserve?bid="(book.bid)}{
+					(csrf::field())
 					input style="display: none;" name="bid" value=(book.bid){}
 					button { "Reserve!" }
 				}
@@ -549,29 +868,33 @@ fn view_avaliable_book(book: &Book) -> Markup {
 	} }
 }
 
-fn view_book(book: Book, viewer: &Account) -> Markup {
+fn view_book(state: &ServerState, book: Book, viewer: Option<&Account>) -> Markup {
+	let can_catalog = roles::can(state, viewer, Permission::Catalog);
+	let (avaliable, total) = availability(state, book.ISBN);
 	html!{ (DOCTYPE) head {
 		meta charset="UTF-8"{}
 		link rel="stylesheet" type="text/css" href="/files/css/book.css"{}
 		title { {"LSYS - " (book.name)} }
 	} body {
 		article {
-			aside { img
-				src={"/files/img/books/"(book.ISBN)}
-				onerror="this.src='/files/img/missing'" {}
-			}
+			aside { (covers::view_book_cover(state, &book, can_catalog)) }
 
 			section {
 				h1 id="book-name" { i { (book.name) } }
+				h2 id="book-authors" { "by " (authors::view_author_links(state, &book)) }
 				h5 id="ISBN" { (book.ISBN) }
 				h2 { { "Published in: " (book.published) } }
 			}
 
 			section {
+				p { (avaliable) " of " (total) " copies avaliable" }
 				@let status = book.status.get();
-				@let (with_viewer, until) = status.is_with_viewer(viewer.uid);
-				p {{"is avaliable: "(status.is_avaliable())}}
-				@if status.is_avaliable() {
+				@let (with_viewer, until) = viewer.map_or((false, None), |viewer| status.is_with_viewer(viewer.uid));
+				@if viewer.is_none() {
+					a href=(login_url(&format!("/book?bid={}", book.bid))) { "Log in to reserve" }
+				} @else if status.is_avaliable() && !viewer.is_some_and(|viewer| viewer.verified) {
+					p { "Confirm your email to reserve this book." }
+				} @else if status.is_avaliable() {
 					a href={"/reserve?bid=" (book.bid)} {"Reserve"}
 				} @else if with_viewer {
 					@let until = until.unwrap();
@@ -589,6 +912,13 @@ fn view_book(book: Book, viewer: &Account) -> Markup {
 					p {"LOSER"}
 				}
 			}
+
+			(recommend::view_also_borrowed(state, &book, viewer))
+			(citation::view_book_cite_links(&book))
+			(works::view_book_editions(state, &book))
+			(series::view_book_series(state, &book))
+			(taxonomy::view_book_taxonomy(state, &book, can_catalog))
+			(authors::view_book_contributors(state, &book, can_catalog))
 		}
 	} }
 }
@@ -596,52 +926,116 @@ fn view_book(book: Book, viewer: &Account) -> Markup {
 //TODO
 //fn view_status(book, uid)
 
-fn view_all_books(books: &Vec<Book>) -> Markup {
-	html! { (DOCTYPE) body{
+fn view_catalog_filter(state: &ServerState, filter: &CatalogFilter, action: &str) -> Markup {
+	html! {
+		form method="GET" action=(action) {
+			input name="q" type="search" placeholder="title, author or ISBN" value=[filter.q.as_deref()] {}
+			select name="subject" {
+				option value="" { "(any subject)" }
+				(taxonomy::view_subject_options(state, filter.subject))
+			}
+			@if let Some(tag) = &filter.tag {
+				input type="hidden" name="tag" value=(tag) {}
+			}
+			label {
+				input type="checkbox" name="collapse" value="true" checked[filter.collapse == Some(true)] {}
+				"group editions"
+			}
+			button { "Filter" }
+		}
+		@if let Some(tag) = &filter.tag {
+			p { {"Tagged: " (tag) " "} a href=(action) { "(clear)" } }
+		}
+	}
+}
+
+fn view_books_table(state: &ServerState, books: &[Book]) -> Markup {
+	html! {
 		table {
 
 			thead{ tr {
+				td { "Cover" }
 				td { "ISBN" }
 				td { "Name" }
 				td { "Authors" }
 				td { "Published Date" }
+				td { "Avaliable copies" }
 			} }
 
 			tbody{
 			@for book in books { tr{
+				@let (avaliable, total) = availability(state, book.ISBN);
+				td { a href={"/book?bid="(book.bid)}{ (covers::view_cover(state, book, CoverSize::Small)) } }
 				th {
 					(book.ISBN)
 				}
 				td { a href={"/book?bid="(book.bid)}{ i { (book.name) } } }
-				td {
-					@for author in &book.authors {
-						p {(author)}
-					}
-				}
+				td { (authors::view_author_links(state, book)) }
 				td { (book.published) }
+				td { (avaliable) " of " (total) }
 			} }
 			}
 		}
-	} }
+	}
 }
 
-fn days_until(when: NaiveDate) -> i64 {
-	let today = chrono::Utc::now().date_naive();
-	(when-today).num_days()
+fn view_all_books(state: &ServerState, books: &[Book], filter: &CatalogFilter, logged_in: bool) -> Markup {
+	html! { (DOCTYPE) body{
+		nav {
+			@if logged_in {
+				a href="/dashboard" { "Dashboard" }
+				" | "
+				form method="POST" action="/logout" style="display: inline;" {
+					(csrf::field())
+					button { "Log out" }
+				}
+			} @else {
+				a href="/login" { "Log in" }
+			}
+			" | "
+			a href="/search" { "Search" }
+			" | "
+			a href="/subjects" { "Subjects" }
+			" | "
+			a href="/series" { "Series" }
+		}
+		(view_catalog_filter(state, filter, "/"))
+		@if filter.collapse == Some(true) {
+			(works::view_works_table(state, books))
+		} @else {
+			(view_books_table(state, books))
+		}
+	} }
 }
 
-// djb2
-fn hash(st: &[u8]) -> i64 {
-	let mut hash: i64 = 5381;
-	for chr in st {
-		hash = 33*hash+(*chr as i64);
-	}
-	hash
+fn view_search(state: &ServerState, books: &[Book], filter: &CatalogFilter) -> Markup {
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		title { "LSYS - Search" }
+	} body {
+		nav {
+			a href="/" { "All books" }
+			" | "
+			a href="/subjects" { "Subjects" }
+		}
+		(view_catalog_filter(state, filter, "/search"))
+		@if filter.q.as_deref().is_some_and(|q| !q.trim().is_empty()) {
+			p { (books.len()) " results" }
+			@if !books.is_empty() {
+				(citation::view_search_cite_links(filter))
+			}
+			@if filter.collapse == Some(true) {
+				(works::view_works_table(state, books))
+			} @else {
+				(view_books_table(state, books))
+			}
+		}
+	} }
 }
 
-fn hash_pass(pass: &[u8]) -> String {
-	let pass_hash = hash(pass).to_le_bytes();
-	Uuid::new_v3(&Uuid::NAMESPACE_OID, &pass_hash).to_string()
+fn days_until(when: NaiveDate) -> i64 {
+	let today = chrono::Utc::now().date_naive();
+	(when-today).num_days()
 }
 
 #[derive(Debug, Deserialize)]
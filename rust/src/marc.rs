@@ -0,0 +1,625 @@
+// MARC21 records, read and written as ISO 2709 or MARCXML
+
+use axum::{
+	extract::State,
+	extract::Query,
+	extract::Multipart,
+	http::header,
+	response::{Redirect, IntoResponse, Response},
+};
+use maud::{html, Markup, DOCTYPE};
+use quick_xml::{
+	Reader,
+	events::{Event, BytesStart},
+	escape::escape,
+};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use crate::types::*;
+use crate::roles::{Authorized, Catalog};
+use crate::catalog::{parse_isbn, format_isbn, import_entries, view_import_report};
+use crate::{SharedState, ServerState, read_state, csrf};
+
+const RECORD_END: u8 = 0x1D;
+const FIELD_END: u8 = 0x1E;
+const SUBFIELD_START: u8 = 0x1F;
+// the directory has 4 digits for a field's length, the leader 5 for the record's
+const MAX_FIELD_LENGTH: usize = 9999;
+const MAX_RECORD_LENGTH: usize = 99999;
+
+#[derive(Debug, Clone)]
+pub enum MarcField {
+	// 001-009
+	Control{ tag: String, value: String },
+	Data{ tag: String, ind: [char; 2], subfields: Vec<(char, String)> },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MarcRecord {
+	pub leader: String,
+	pub fields: Vec<MarcField>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MarcExportParam {
+	pub format: Option<String>,
+	// ISO 2709 even if records too long for it have to be left out
+	pub partial: Option<bool>,
+}
+
+impl MarcField {
+	fn tag(&self) -> &str {
+		match self {
+			MarcField::Control{tag, ..}=>tag,
+			MarcField::Data{tag, ..}=>tag,
+		}
+	}
+}
+
+impl MarcRecord {
+	fn data_fields<'a>(&'a self, want: &'a str) -> impl Iterator<Item=(&'a [char; 2], &'a [(char, String)])> + 'a {
+		self.fields.iter().filter_map(move |field| match field {
+			MarcField::Data{tag, ind, subfields} if tag == want => Some((ind, subfields.as_slice())),
+			_ => None,
+		})
+	}
+
+	fn subfield<'a>(&'a self, tag: &'a str, code: char) -> Option<&'a str> {
+		self.data_fields(tag)
+			.flat_map(|(_, subfields)| subfields)
+			.find(|(c, _)| *c == code)
+			.map(|(_, value)| value.as_str())
+	}
+}
+
+fn is_control_tag(tag: &str) -> bool {
+	tag.starts_with("00")
+}
+
+// one Result per record, so a broken record doesn't take the whole file with it
+pub fn parse_iso2709(bytes: &[u8]) -> Vec<Result<MarcRecord, String>> {
+	bytes
+		.split(|byte| *byte == RECORD_END)
+		.filter(|raw| !raw.iter().all(u8::is_ascii_whitespace))
+		.map(|raw| {
+			// files are often saved with a newline between records
+			let start = raw.iter().position(|byte| !byte.is_ascii_whitespace()).unwrap_or(0);
+			parse_iso2709_record(&raw[start..])
+		})
+		.collect()
+}
+
+fn parse_iso2709_record(raw: &[u8]) -> Result<MarcRecord, String> {
+	if raw.len() < 24 {
+		return Err("record shorter than its leader".to_owned());
+	}
+	let leader = String::from_utf8_lossy(&raw[..24]).into_owned();
+	let base = leader.get(12..17)
+		.and_then(|base| base.parse::<usize>().ok())
+		.filter(|base| *base > 24 && *base <= raw.len())
+		.ok_or("leader has no valid base address")?;
+
+	let mut fields = Vec::new();
+	for entry in raw[24..base-1].chunks(12) {
+		if entry.len() != 12 {
+			return Err("truncated directory".to_owned());
+		}
+		// multi-byte characters would put the slicing below off char boundaries
+		if !entry.is_ascii() {
+			return Err("directory isn't ASCII".to_owned());
+		}
+		let entry = std::str::from_utf8(entry).map_err(|_| "directory isn't ASCII")?;
+		let tag = entry[..3].to_owned();
+		let length = entry[3..7].parse::<usize>().map_err(|_| "bad field length")?;
+		let start = base + entry[7..].parse::<usize>().map_err(|_| "bad field start")?;
+		let data = raw.get(start..start+length).ok_or(format!("field {tag} is out of bounds"))?;
+		let data = data.strip_suffix(&[FIELD_END]).unwrap_or(data);
+
+		if is_control_tag(&tag) {
+			let value = String::from_utf8_lossy(data).into_owned();
+			fields.push(MarcField::Control{ tag, value });
+			continue;
+		}
+		let ind = match data {
+			[ind1, ind2, ..] => [*ind1 as char, *ind2 as char],
+			_ => return Err(format!("field {tag} has no indicators")),
+		};
+		let subfields = data[2..]
+			.split(|byte| *byte == SUBFIELD_START)
+			.skip(1)
+			.filter_map(|subfield| {
+				let subfield = String::from_utf8_lossy(subfield);
+				let mut chars = subfield.chars();
+				let code = chars.next()?;
+				Some((code, chars.as_str().to_owned()))
+			})
+			.collect();
+		fields.push(MarcField::Data{ tag, ind, subfields });
+	}
+	Ok(MarcRecord{ leader, fields })
+}
+
+// records too long for ISO 2709 are left out rather than written with a broken directory,
+// each of them is named, with why, in the second Vec
+pub fn write_iso2709(records: &[MarcRecord]) -> (Vec<u8>, Vec<String>) {
+	let mut out = Vec::new();
+	let mut skipped = Vec::new();
+	for (at, record) in records.iter().enumerate() {
+		match write_iso2709_record(record) {
+			Ok(bytes)=>out.extend(bytes),
+			Err(e)=>{
+				let title = record.subfield("245", 'a').unwrap_or("untitled");
+				skipped.push(format!("record {} ({title}): {e}", at+1));
+			}
+		}
+	}
+	(out, skipped)
+}
+
+fn write_iso2709_record(record: &MarcRecord) -> Result<Vec<u8>, String> {
+	let mut directory = Vec::new();
+	let mut data = Vec::new();
+	for field in &record.fields {
+		let start = data.len();
+		match field {
+			MarcField::Control{value, ..}=>data.extend_from_slice(value.as_bytes()),
+			MarcField::Data{ind, subfields, ..}=>{
+				data.extend_from_slice(format!("{}{}", ind[0], ind[1]).as_bytes());
+				for (code, value) in subfields {
+					data.push(SUBFIELD_START);
+					data.extend_from_slice(format!("{code}{value}").as_bytes());
+				}
+			}
+		}
+		data.push(FIELD_END);
+		let length = data.len()-start;
+		if length > MAX_FIELD_LENGTH {
+			return Err(format!("field {} is {length} bytes long, at most {MAX_FIELD_LENGTH} fit", field.tag()));
+		}
+		let entry = format!("{:0>3}{:04}{:05}", field.tag(), length, start);
+		directory.extend_from_slice(entry.as_bytes());
+	}
+	directory.push(FIELD_END);
+
+	// the base address and every field start are below the length, so they fit too
+	let base = 24 + directory.len();
+	let length = base + data.len() + 1;
+	if length > MAX_RECORD_LENGTH {
+		return Err(format!("it is {length} bytes long, at most {MAX_RECORD_LENGTH} fit"));
+	}
+	let mut out = Vec::with_capacity(length);
+	// status n(ew), type a(language material), level m(onograph), a = UTF-8
+	out.extend_from_slice(format!("{length:05}nam a22{base:05} i 4500").as_bytes());
+	out.extend(directory);
+	out.extend(data);
+	out.push(RECORD_END);
+	Ok(out)
+}
+
+fn xml_attribute(element: &BytesStart, name: &str) -> Option<String> {
+	element.try_get_attribute(name).ok()??
+		.unescape_value().ok()
+		.map(|value| value.into_owned())
+}
+
+pub fn parse_marcxml(xml: &str) -> Result<Vec<Result<MarcRecord, String>>, String> {
+	let mut reader = Reader::from_str(xml);
+	let mut records = Vec::new();
+	let mut record: Option<MarcRecord> = None;
+	// the element whose text is being read
+	let mut open: Option<(String, Option<String>, [char; 2])> = None;
+	let mut subfield: Option<char> = None;
+	let mut subfields = Vec::new();
+	let mut text = String::new();
+
+	loop {
+		let event = reader.read_event()
+			.map_err(|e| format!("XML error at byte {}: {e}", reader.buffer_position()))?;
+		match event {
+			Event::Start(element) => {
+				let name = String::from_utf8_lossy(element.local_name().as_ref()).into_owned();
+				text.clear();
+				match name.as_str() {
+					"record" => record = Some(MarcRecord::default()),
+					"controlfield" | "datafield" => {
+						let tag = xml_attribute(&element, "tag");
+						let ind = |name| xml_attribute(&element, name)
+							.and_then(|ind| ind.chars().next())
+							.unwrap_or(' ');
+						open = Some((name, tag, [ind("ind1"), ind("ind2")]));
+						subfields.clear();
+					}
+					"subfield" => {
+						subfield = xml_attribute(&element, "code").and_then(|code| code.chars().next());
+					}
+					_ => {}
+				}
+			}
+			Event::Text(content) => {
+				let content = content.unescape().map_err(|e| format!("XML error: {e}"))?;
+				text.push_str(&content);
+			}
+			Event::CData(content) => {
+				text.push_str(&String::from_utf8_lossy(&content));
+			}
+			Event::End(element) => {
+				let name = String::from_utf8_lossy(element.local_name().as_ref()).into_owned();
+				match (name.as_str(), record.as_mut()) {
+					("leader", Some(record)) => record.leader = text.clone(),
+					("subfield", _) => {
+						if let Some(code) = subfield.take() {
+							subfields.push((code, text.clone()));
+						}
+					}
+					("controlfield" | "datafield", Some(record)) => {
+						if let Some((kind, Some(tag), ind)) = open.take() {
+							record.fields.push(match kind.as_str() {
+								"controlfield" => MarcField::Control{ tag, value: text.clone() },
+								_ => MarcField::Data{ tag, ind, subfields: std::mem::take(&mut subfields) },
+							});
+						}
+					}
+					("record", Some(_)) => {
+						let record = record.take().unwrap_or_default();
+						records.push(if record.fields.is_empty() {
+							Err("record has no fields".to_owned())
+						} else {
+							Ok(record)
+						});
+					}
+					_ => {}
+				}
+				text.clear();
+			}
+			Event::Eof => break,
+			_ => {}
+		}
+	}
+	Ok(records)
+}
+
+pub fn write_marcxml(records: &[MarcRecord]) -> String {
+	let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+	xml.push_str("<collection xmlns=\"http://www.loc.gov/MARC21/slim\">\n");
+	for record in records {
+		xml.push_str("<record>\n");
+		xml.push_str(&format!("\t<leader>{}</leader>\n", escape(record.leader.as_str())));
+		for field in &record.fields {
+			match field {
+				MarcField::Control{tag, value}=>{
+					xml.push_str(&format!("\t<controlfield tag=\"{tag}\">{}</controlfield>\n", escape(value.as_str())));
+				}
+				MarcField::Data{tag, ind, subfields}=>{
+					xml.push_str(&format!("\t<datafield tag=\"{tag}\" ind1=\"{}\" ind2=\"{}\">\n", ind[0], ind[1]));
+					for (code, value) in subfields {
+						xml.push_str(&format!("\t\t<subfield code=\"{}\">{}</subfield>\n", escape(code.to_string().as_str()), escape(value.as_str())));
+					}
+					xml.push_str("\t</datafield>\n");
+				}
+			}
+		}
+		xml.push_str("</record>\n");
+	}
+	xml.push_str("</collection>\n");
+	xml
+}
+
+// ISBD punctuation that ends MARC subfields: "Foundation /", "Poe, Edgar Allan,"
+fn trim_punctuation(value: &str) -> &str {
+	value.trim().trim_end_matches([' ', '/', ':', ';', ',', '.', '=']).trim()
+}
+
+// like trim_punctuation, but keeps the period of a trailing initial: "Kernighan, Brian W.,"
+fn trim_name(name: &str) -> &str {
+	let name = name.trim().trim_end_matches([' ', '/', ':', ';', ',', '=']);
+	match name.strip_suffix('.') {
+		Some(rest) if rest.rsplit(' ').next().is_some_and(|word| word.chars().count() > 1) => rest,
+		_ => name,
+	}
+}
+
+// "Poe, Edgar Allan," -> "Edgar Allan Poe"
+pub fn direct_name(name: &str) -> String {
+	let name = trim_name(name);
+	match name.split_once(", ") {
+		Some((last, first)) => format!("{} {last}", trim_name(first)),
+		None => name.to_owned(),
+	}
+}
+
+// "Edgar Allan Poe" -> "Poe, Edgar Allan"
+fn inverted_name(name: &str) -> (char, String) {
+	match name.trim().rsplit_once(' ') {
+		Some((first, last)) => ('1', format!("{last}, {first}")),
+		None => ('0', name.trim().to_owned()),
+	}
+}
+
+// "c1998." or "[1998?]" -> "1998"
+fn publication_year(raw: &str) -> String {
+	let digits = raw.chars()
+		.skip_while(|chr| !chr.is_ascii_digit())
+		.take_while(char::is_ascii_digit)
+		.collect::<String>();
+	if digits.len() == 4 {
+		digits
+	} else {
+		trim_punctuation(raw).to_owned()
+	}
+}
+
+pub fn record_to_entry(record: &MarcRecord) -> Result<CatalogEntry, String> {
+	let isbns = record.data_fields("020")
+		.filter_map(|(_, subfields)| subfields.iter().find(|(code, _)| *code == 'a'))
+		.map(|(_, isbn)| parse_isbn(isbn))
+		.collect::<Vec<_>>();
+	let isbn = match isbns.iter().find_map(|isbn| isbn.as_ref().ok()) {
+		Some(isbn) => *isbn,
+		None => return Err(match isbns.into_iter().next() {
+			Some(Err(reason)) => reason,
+			_ => "no ISBN (020 $a)".to_owned(),
+		}),
+	};
+
+	let title = record.subfield("245", 'a').map(trim_punctuation).unwrap_or("");
+	if title.is_empty() {
+		return Err("no title (245 $a)".to_owned());
+	}
+	let name = match record.subfield("245", 'b').map(trim_punctuation) {
+		Some(subtitle) if !subtitle.is_empty() => format!("{title}: {subtitle}"),
+		_ => title.to_owned(),
+	};
+
+	let published = record.subfield("264", 'c')
+		.or(record.subfield("260", 'c'))
+		.map(publication_year)
+		.unwrap_or_default();
+
+	let mut authors = Vec::new();
+	for tag in ["100", "110", "700", "710"] {
+		for (_, subfields) in record.data_fields(tag) {
+			let Some((_, author)) = subfields.iter().find(|(code, _)| *code == 'a') else {
+				continue;
+			};
+			let author = direct_name(author);
+			if !author.is_empty() && !authors.contains(&author) {
+				authors.push(author);
+			}
+		}
+	}
+
+	// one 852 holdings field per copy
+	let copies = record.data_fields("852").count().max(1);
+	Ok(CatalogEntry{
+		ISBN: isbn,
+		name,
+		published,
+		authors,
+		copies,
+	})
+}
+
+fn entry_to_record(entry: &CatalogEntry, bids: &[Bid]) -> MarcRecord {
+	let isbn = format_isbn(entry.ISBN);
+	let mut fields = vec![
+		MarcField::Control{ tag: "001".to_owned(), value: isbn.clone() },
+		MarcField::Data{ tag: "020".to_owned(), ind: [' ', ' '], subfields: vec![('a', isbn)] },
+	];
+	let mut authors = entry.authors.iter();
+	let main_author = authors.next();
+	if let Some(author) = main_author {
+		let (ind, name) = inverted_name(author);
+		fields.push(MarcField::Data{ tag: "100".to_owned(), ind: [ind, ' '], subfields: vec![('a', name)] });
+	}
+	let title_ind = if main_author.is_some() { '1' } else { '0' };
+	fields.push(MarcField::Data{ tag: "245".to_owned(), ind: [title_ind, '0'], subfields: vec![('a', entry.name.clone())] });
+	if !entry.published.is_empty() {
+		fields.push(MarcField::Data{ tag: "264".to_owned(), ind: [' ', '1'], subfields: vec![('c', entry.published.clone())] });
+	}
+	for author in authors {
+		let (ind, name) = inverted_name(author);
+		fields.push(MarcField::Data{ tag: "700".to_owned(), ind: [ind, ' '], subfields: vec![('a', name)] });
+	}
+	for bid in bids {
+		fields.push(MarcField::Data{ tag: "852".to_owned(), ind: [' ', ' '], subfields: vec![
+			('a', "LSYS".to_owned()),
+			('p', bid.to_string()),
+		] });
+	}
+	MarcRecord{ leader: String::new(), fields }
+}
+
+fn catalog_records(state: &ServerState) -> Vec<MarcRecord> {
+	let mut titles = BTreeMap::<ISBN, (CatalogEntry, Vec<Bid>)>::new();
+	for book in state.bid_to_book.values() {
+		let (_, bids) = titles.entry(book.ISBN).or_insert_with(|| (CatalogEntry{
+			ISBN: book.ISBN,
+			name: book.name.clone(),
+			published: book.published.clone(),
+			authors: book.authors.clone(),
+			copies: 0,
+		}, Vec::new()));
+		bids.push(book.bid);
+	}
+	titles.into_values()
+		.map(|(entry, mut bids)| {
+			bids.sort();
+			let mut record = entry_to_record(&entry, &bids);
+			// the writers fill in the real lengths
+			record.leader = "00000nam a2200000 i 4500".to_owned();
+			record
+		})
+		.collect()
+}
+
+pub async fn display_marc(
+	_: Authorized<Catalog>,
+) -> Result<Markup, Redirect> {
+	Ok( view_marc("") )
+}
+
+pub async fn perform_marc_import(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	mut multipart: Multipart,
+) -> Result<Markup, Redirect> {
+	let mut file = Vec::new();
+	let mut dry_run = false;
+	loop {
+		let field = match multipart.next_field().await {
+			Ok(Some(field))=>field,
+			Ok(None)=>break,
+			Err(e)=>return Ok(view_marc(&e.to_string())),
+		};
+		match field.name() {
+			Some("file")=>match field.bytes().await {
+				Ok(bytes)=>file = bytes.to_vec(),
+				Err(e)=>return Ok(view_marc(&e.to_string())),
+			},
+			Some("dry_run")=>dry_run = true,
+			_=>{}
+		}
+	}
+	if file.is_empty() {
+		return Ok(view_marc("No file uploaded"));
+	}
+
+	let is_xml = file.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'<');
+	let records = if is_xml {
+		match parse_marcxml(&String::from_utf8_lossy(&file)) {
+			Ok(records)=>records,
+			Err(e)=>return Ok(view_marc(&e)),
+		}
+	} else {
+		parse_iso2709(&file)
+	};
+	let entries = records.iter()
+		.enumerate()
+		.map(|(at, record)| (at+1, record.as_ref().map_err(String::clone).and_then(record_to_entry)))
+		.collect();
+
+	let mut state = stt.lock().await;
+	Ok( match import_entries(&mut state, entries, dry_run).await {
+		Ok(rows)=>view_import_report("MARC import", &rows, dry_run, "/marc", html!{}),
+		Err(e)=>view_marc(&format!("Import rolled back: {e}")),
+	} )
+}
+
+pub async fn display_marc_export(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Query(param): Query<MarcExportParam>,
+) -> Result<Response, Redirect> {
+	let state = read_state(stt).await;
+
+	let records = catalog_records(&state);
+	Ok( match param.format.as_deref() {
+		Some("iso2709")=>{
+			let (file, skipped) = write_iso2709(&records);
+			if !skipped.is_empty() && param.partial != Some(true) {
+				return Ok(view_marc_skipped(&skipped).into_response());
+			}
+			(
+				[
+					(header::CONTENT_TYPE, "application/marc"),
+					(header::CONTENT_DISPOSITION, "attachment; filename=\"lsys.mrc\""),
+				],
+				file,
+			).into_response()
+		}
+		_=>(
+			[
+				(header::CONTENT_TYPE, "application/marcxml+xml"),
+				(header::CONTENT_DISPOSITION, "attachment; filename=\"lsys.xml\""),
+			],
+			write_marcxml(&records),
+		).into_response(),
+	} )
+}
+
+fn view_marc(error: &str) -> Markup {
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		title { "LSYS - MARC21" }
+	} body {
+		p style="color: red;" { (error) }
+
+		fieldset {
+			legend { "Import" }
+			form method="POST" action="/marc/import" enctype="multipart/form-data" {
+				(csrf::field())
+				label for="marc-file" { "file:" }
+				input id="marc-file" name="file" type="file" accept=".mrc,.marc,.xml" {}
+				br {}
+				label for="marc-dry-run" { "dry run:" }
+				input id="marc-dry-run" name="dry_run" type="checkbox" checked {}
+				br {}
+				button { "Import" }
+			}
+			p { "ISO 2709 (.mrc) and MARCXML files are accepted" }
+		}
+
+		fieldset {
+			legend { "Export" }
+			a href="/marc/export?format=marcxml" { "MARCXML" }
+			" | "
+			a href="/marc/export?format=iso2709" { "ISO 2709" }
+		}
+	} }
+}
+
+fn view_marc_skipped(skipped: &[String]) -> Markup {
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		title { "LSYS - MARC21" }
+	} body {
+		nav {
+			a href="/marc" { "MARC21" }
+		}
+		p style="color: red;" { "These records are too long for ISO 2709:" }
+		ul {
+			@for record in skipped {
+				li { (record) }
+			}
+		}
+		p {
+			a href="/marc/export?format=marcxml" { "Export MARCXML" }
+			", which has no such limit, or "
+			a href="/marc/export?format=iso2709&partial=true" { "export ISO 2709 without them" }
+			"."
+		}
+	} }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn non_ascii_directory_is_an_error() {
+		// a 24 byte leader with base address 00037, then a 12 byte directory entry whose tag ends inside an "é"
+		let mut raw = b"00050nam  2200037   4500".to_vec();
+		raw.extend_from_slice("24é000100000".as_bytes());
+		raw.push(FIELD_END);
+		raw.extend_from_slice(b"  \x1faTitle\x1e");
+		raw.push(RECORD_END);
+		let records = parse_iso2709(&raw);
+		assert!(matches!(records.as_slice(), [Err(_)]));
+	}
+
+	#[test]
+	fn records_too_long_are_left_out() {
+		let record = |title: &str| MarcRecord{
+			leader: String::new(),
+			fields: vec![MarcField::Data{ tag: "245".to_owned(), ind: ['0', '0'], subfields: vec![('a', title.to_owned())] }],
+		};
+		let long = "x".repeat(MAX_FIELD_LENGTH);
+		let (out, skipped) = write_iso2709(&[record("Foundation"), record(&long)]);
+		assert_eq!(skipped.len(), 1);
+		assert!(skipped[0].starts_with("record 2"));
+		let records = parse_iso2709(&out);
+		assert!(matches!(records.as_slice(), [Ok(record)] if record.subfield("245", 'a') == Some("Foundation")));
+	}
+}
@@ -0,0 +1,354 @@
+// looking up titles by ISBN, to pre-fill the add-book form
+
+use async_trait::async_trait;
+use axum::{
+	Form,
+	extract::State,
+	extract::Query,
+	response::Redirect,
+};
+use maud::{html, Markup, DOCTYPE};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::types::*;
+use crate::roles::{Authorized, Catalog};
+use crate::catalog::{parse_isbn, format_isbn, import_entries, MAX_COPIES};
+use crate::authors::{similar_authors, is_named};
+use crate::{
+	SharedState, ServerState,
+	read_state, make_redirect,
+	title_copy, availability, csrf,
+};
+
+const DEFAULT_OPEN_LIBRARY_URL: &str = "https://openlibrary.org";
+const DEFAULT_FIXTURES: &str = "fixtures/metadata.json";
+const LOOKUP_TIMEOUT_SECS: u64 = 5;
+
+#[async_trait]
+pub trait MetadataProvider: std::fmt::Debug + Send + Sync {
+	// Ok(None) when the provider doesn't know the ISBN
+	async fn lookup(&self, isbn: ISBN) -> Result<Option<BookMetadata>, String>;
+}
+
+// METADATA_PROVIDER picks "openlibrary" (the default), "fixtures" or "none"
+pub fn provider_from_env() -> Arc<dyn MetadataProvider> {
+	let provider = std::env::var("METADATA_PROVIDER").unwrap_or_default();
+	match provider.as_str() {
+		"fixtures"=>{
+			let path = std::env::var("METADATA_FIXTURES").unwrap_or(DEFAULT_FIXTURES.to_owned());
+			Arc::new(FixtureProvider::load(&path))
+		}
+		"none"=>Arc::new(NoProvider),
+		_=>{
+			let url = std::env::var("METADATA_URL").unwrap_or(DEFAULT_OPEN_LIBRARY_URL.to_owned());
+			Arc::new(OpenLibraryProvider::new(url))
+		}
+	}
+}
+
+// one ISBN in Open Library's books api with jscmd=data, the fixture file uses the same shape
+#[derive(Debug, Deserialize)]
+struct OpenLibraryRecord {
+	title: String,
+	#[serde(default)]
+	subtitle: Option<String>,
+	#[serde(default)]
+	authors: Vec<OpenLibraryAuthor>,
+	#[serde(default)]
+	publish_date: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenLibraryAuthor {
+	name: String,
+}
+
+impl From<OpenLibraryRecord> for BookMetadata {
+	fn from(record: OpenLibraryRecord) -> Self {
+		let name = match record.subtitle {
+			Some(subtitle) if !subtitle.is_empty()=>format!("{}: {subtitle}", record.title),
+			_=>record.title,
+		};
+		// "March 22, 1988" is stored as just the year, like the rest of the catalog
+		let year = record.publish_date
+			.split(|chr: char| !chr.is_ascii_digit())
+			.find(|part| part.len() == 4);
+		Self{
+			name,
+			published: year.unwrap_or(record.publish_date.trim()).to_owned(),
+			authors: record.authors.into_iter()
+				.map(|author| crate::marc::direct_name(&author.name))
+				.collect(),
+		}
+	}
+}
+
+#[derive(Debug)]
+pub struct OpenLibraryProvider {
+	client: reqwest::Client,
+	base_url: String,
+}
+
+impl OpenLibraryProvider {
+	pub fn new(base_url: String) -> Self {
+		let client = reqwest::Client::builder()
+			.timeout(std::time::Duration::from_secs(LOOKUP_TIMEOUT_SECS))
+			.user_agent("lsys")
+			.build()
+			.expect("can't build the metadata http client");
+		Self{
+			client,
+			base_url: base_url.trim_end_matches('/').to_owned(),
+		}
+	}
+}
+
+#[async_trait]
+impl MetadataProvider for OpenLibraryProvider {
+	async fn lookup(&self, isbn: ISBN) -> Result<Option<BookMetadata>, String> {
+		let key = format!("ISBN:{}", format_isbn(isbn));
+		let response = self.client
+			.get(format!("{}/api/books", self.base_url))
+			.query(&[("bibkeys", key.as_str()), ("format", "json"), ("jscmd", "data")])
+			.send().await
+			.and_then(|response| response.error_for_status())
+			.map_err(|e| e.to_string())?;
+		let mut records = response
+			.json::<HashMap<String, OpenLibraryRecord>>().await
+			.map_err(|e| e.to_string())?;
+		Ok( records.remove(&key).map(BookMetadata::from) )
+	}
+}
+
+// records read once from a JSON file, for working without network access
+#[derive(Debug)]
+pub struct FixtureProvider {
+	records: HashMap<ISBN, BookMetadata>,
+}
+
+impl FixtureProvider {
+	pub fn load(path: &str) -> Self {
+		let file = std::fs::read_to_string(path)
+			.unwrap_or_else(|e| panic!("can't read metadata fixtures {path}: {e}"));
+		let records = serde_json::from_str::<HashMap<String, OpenLibraryRecord>>(&file)
+			.unwrap_or_else(|e| panic!("can't parse metadata fixtures {path}: {e}"));
+		Self{
+			records: records.into_iter()
+				.filter_map(|(key, record)| {
+					let isbn = parse_isbn(key.trim_start_matches("ISBN:")).ok()?;
+					Some((isbn, BookMetadata::from(record)))
+				})
+				.collect(),
+		}
+	}
+}
+
+#[async_trait]
+impl MetadataProvider for FixtureProvider {
+	async fn lookup(&self, isbn: ISBN) -> Result<Option<BookMetadata>, String> {
+		Ok( self.records.get(&isbn).cloned() )
+	}
+}
+
+#[derive(Debug)]
+pub struct NoProvider;
+
+#[async_trait]
+impl MetadataProvider for NoProvider {
+	async fn lookup(&self, _isbn: ISBN) -> Result<Option<BookMetadata>, String> {
+		Ok(None)
+	}
+}
+
+pub async fn display_add_book(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Query(param): Query<ISBNLookupParam>,
+) -> Result<Markup, Redirect> {
+	let state = read_state(stt).await;
+
+	let raw = param.ISBN.unwrap_or_default();
+	let raw = raw.trim();
+	if raw.is_empty() {
+		return Ok(view_add_book(&state, "", None, html!{}, ""));
+	}
+	let isbn = match parse_isbn(raw) {
+		Ok(isbn)=>isbn,
+		Err(e)=>return Ok(view_add_book(&state, raw, None, html!{}, &e)),
+	};
+
+	// a title already in the catalog can only be given more copies
+	if let Some(book) = title_copy(&state, isbn) {
+		let form = NewBookForm{
+			ISBN: isbn,
+			name: book.name.clone(),
+			published: book.published.clone(),
+			authors: book.authors.join("; "),
+			copies: Some(availability(&state, isbn).1),
+		};
+		let notes = html! {
+			p {
+				"This title is already in the catalog, "
+				a href={"/book?bid=" (book.bid)} { "see it here" }
+				". Raising the copy count adds copies."
+			}
+		};
+		return Ok(view_add_book(&state, raw, Some(&form), notes, ""));
+	}
+
+	let empty = NewBookForm{ ISBN: isbn, ..NewBookForm::default() };
+	let metadata = match state.metadata.lookup(isbn).await {
+		Ok(Some(metadata))=>metadata,
+		Ok(None)=>{
+			let notes = html! { p { "Nothing is known about this ISBN, fill in the title by hand." } };
+			return Ok(view_add_book(&state, raw, Some(&empty), notes, ""));
+		}
+		Err(e)=>{
+			let error = format!("Metadata lookup failed: {e}");
+			return Ok(view_add_book(&state, raw, Some(&empty), html!{}, &error));
+		}
+	};
+
+	// authors the catalog already has are pre-filled with its spelling, so no duplicate is made
+	let mut authors = Vec::new();
+	let mut suggestions = Vec::new();
+	for author in &metadata.authors {
+		let similar = similar_authors(&state, author);
+		match similar.first() {
+			Some(known) if is_named(known, author)=>{
+				authors.push(known.name.clone());
+			}
+			_=>{
+				authors.push(author.clone());
+				if !similar.is_empty() {
+					suggestions.push((author.clone(), similar));
+				}
+			}
+		}
+	}
+	let form = NewBookForm{
+		ISBN: isbn,
+		name: metadata.name,
+		published: metadata.published,
+		authors: authors.join("; "),
+		copies: Some(1),
+	};
+	let notes = html! {
+		@for (author, similar) in &suggestions {
+			p {
+				{"\"" (author) "\" may already be in the catalog as: "}
+				@for (at, known) in similar.iter().enumerate() {
+					@if at > 0 { ", " }
+					b { (known.name) }
+				}
+			}
+		}
+	};
+	Ok( view_add_book(&state, raw, Some(&form), notes, "") )
+}
+
+pub async fn perform_add_book(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<NewBookForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	let lookup = format_isbn(form.ISBN);
+	let isbn = parse_isbn(&lookup)
+		.map_err(|e| view_add_book(&state, &lookup, None, html!{}, &e))?;
+	let name = form.name.trim();
+	if name.is_empty() {
+		return Err(view_add_book(&state, &lookup, Some(&form), html!{}, "The title needs a name"));
+	}
+	let copies = form.copies.unwrap_or(1);
+	if !(1..=MAX_COPIES).contains(&copies) {
+		let error = format!("The copy count must be from 1 to {MAX_COPIES}");
+		return Err(view_add_book(&state, &lookup, Some(&form), html!{}, &error));
+	}
+
+	let entry = CatalogEntry{
+		ISBN: isbn,
+		name: name.to_owned(),
+		published: form.published.trim().to_owned(),
+		authors: form.authors
+			.split(';')
+			.map(str::trim)
+			.filter(|author| !author.is_empty())
+			.map(str::to_owned)
+			.collect(),
+		copies,
+	};
+	if let Err(e) = import_entries(&mut state, vec![(1, Ok(entry))], false).await {
+		return Err(view_add_book(&state, &lookup, Some(&form), html!{}, &e.to_string()));
+	}
+
+	Ok( match title_copy(&state, isbn) {
+		Some(book)=>make_redirect(format!("/book?bid={}", book.bid)),
+		None=>make_redirect("/".to_owned()),
+	} )
+}
+
+fn view_add_book(
+	state: &ServerState,
+	lookup: &str,
+	form: Option<&NewBookForm>,
+	notes: Markup,
+	error: &str,
+) -> Markup {
+	let mut known = state.aid_to_authors.values().map(|author| author.name.as_str()).collect::<Vec<_>>();
+	known.sort_unstable();
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		title { "LSYS - Add a title" }
+	} body {
+		p style="color: red;" { (error) }
+
+		fieldset {
+			legend { "Look up an ISBN" }
+			form method="GET" action="/books/new" {
+				label for="lookup-isbn" { "ISBN:" }
+				input id="lookup-isbn" name="ISBN" type="text" placeholder="ISBN" value=(lookup) {}
+				button { "Look up" }
+			}
+		}
+
+		@if let Some(form) = form {
+			(notes)
+			fieldset {
+				legend { {"Add " (format_isbn(form.ISBN))} }
+				form method="POST" action="/books/new" {
+					(csrf::field())
+					input type="hidden" name="ISBN" value=(form.ISBN) {}
+					label for="book-name" { "title:" }
+					input id="book-name" name="name" type="text" placeholder="title" value=(form.name) {}
+					br {}
+					label for="book-published" { "published:" }
+					input id="book-published" name="published" type="text" placeholder="year" value=(form.published) {}
+					br {}
+					label for="book-authors" { "authors:" }
+					input id="book-authors" name="authors" type="text" placeholder="authors" value=(form.authors) {}
+					br {}
+					label for="book-copies" { "copies:" }
+					input id="book-copies" name="copies" type="number" min="1" max=(MAX_COPIES)
+						value=[form.copies] {}
+					br {}
+					button { "Save" }
+				}
+				p { "Separate multiple authors with \";\"." }
+			}
+			@if !known.is_empty() {
+				details {
+					summary { "Authors in the catalog" }
+					ul {
+						@for name in known {
+							li { (name) }
+						}
+					}
+				}
+			}
+		}
+	} }
+}
@@ -0,0 +1,638 @@
+// logging in with a passkey (WebAuthn) instead of a password;
+// attestation isn't asked for, so any authenticator can be added, software ones included
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{
+	Form,
+	extract::{Query, State},
+	http::{HeaderMap, Uri},
+	response::Redirect,
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use ciborium::Value;
+use maud::{html, Markup, DOCTYPE};
+use ring::signature::{
+	UnparsedPublicKey, RsaPublicKeyComponents,
+	ECDSA_P256_SHA256_ASN1, ED25519, RSA_PKCS1_2048_8192_SHA256,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tower_cookies::{Cookie, Cookies, cookie::SameSite};
+use crate::types::*;
+use crate::mail::public_url;
+use crate::sessions::view_time;
+use crate::{
+	SharedState, ServerState, Goto,
+	read_viewer, make_redirect, login_url, with_goto,
+	csrf, sessions, tls, two_factor,
+};
+
+const COOKIE_PASSKEY_NAME: &str = "lsys-passkey";
+const CHALLENGE_BYTES: usize = 32;
+const CHALLENGE_SECS: i64 = 5 * 60;
+// the login page is open to anyone, so only this many browsers can be mid-ceremony at once
+const MAX_CHALLENGES: usize = 10_000;
+const MAX_NAME: usize = 64;
+
+// authenticator data flags
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const ATTESTED_CREDENTIAL: u8 = 0x40;
+
+// COSE algorithms
+const ES256: i128 = -7;
+const EDDSA: i128 = -8;
+const RS256: i128 = -257;
+
+fn now() -> i64 {
+	chrono::Utc::now().timestamp()
+}
+
+// passkeys belong to the host in PUBLIC_URL, and only pages from there may use them
+fn relying_party() -> (String, String) {
+	let origin = public_url();
+	let id = origin.parse::<Uri>().ok()
+		.and_then(|uri| uri.host().map(str::to_owned))
+		.unwrap_or("localhost".to_owned());
+	(id, origin)
+}
+
+// the token telling this browser's challenge apart, a new one when it has none yet
+fn browser_token(cookies: &Cookies) -> String {
+	if let Some(cookie) = cookies.get(COOKIE_PASSKEY_NAME).filter(|cookie| !cookie.value().is_empty()) {
+		return cookie.value().to_owned();
+	}
+	let token = sessions::new_token();
+	cookies.add(
+		Cookie::build((COOKIE_PASSKEY_NAME, token.clone()))
+			.path("/")
+			.secure(tls::enabled())
+			.http_only(true)
+			.same_site(SameSite::Lax)
+			.into()
+	);
+	token
+}
+
+// a browser has one challenge at a time, a new page replaces the one it had;
+// expired ones are only swept when the map is full, and if that frees nothing the page is refused
+fn new_challenge(state: &mut ServerState, browser: &str, uid: Option<Uid>) -> Result<String, String> {
+	let now = now();
+	if state.passkey_challenges.len() >= MAX_CHALLENGES && !state.passkey_challenges.contains_key(browser) {
+		state.passkey_challenges.retain(|_, challenge| challenge.expires > now);
+		if state.passkey_challenges.len() >= MAX_CHALLENGES {
+			return Err("Too many passkey logins are going on, try again in a few minutes".to_owned());
+		}
+	}
+	let mut challenge = [0u8; CHALLENGE_BYTES];
+	OsRng.fill_bytes(&mut challenge);
+	let challenge = URL_SAFE_NO_PAD.encode(challenge);
+	state.passkey_challenges.insert(browser.to_owned(), PasskeyChallenge{
+		challenge: challenge.clone(),
+		uid,
+		expires: now + CHALLENGE_SECS,
+	});
+	Ok(challenge)
+}
+
+fn decode(field: &str) -> Result<Vec<u8>, String> {
+	URL_SAFE_NO_PAD.decode(field.trim_end_matches('='))
+		.map_err(|_| "Your browser's answer didn't come through right, try again".to_owned())
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+	#[serde(rename = "type")]
+	kind: String,
+	challenge: String,
+	origin: String,
+}
+
+// the browser's view of the ceremony: what it did, for which challenge and which site;
+// the browser's challenge is used up here, whether or not it matches
+fn check_client_data(state: &mut ServerState, browser: &str, client_data: &[u8], kind: &str, uid: Option<Uid>) -> Result<(), String> {
+	let expected = state.passkey_challenges.remove(browser);
+	let client_data = serde_json::from_slice::<ClientData>(client_data)
+		.map_err(|_| "Your browser's answer didn't come through right, try again".to_owned())?;
+	if client_data.kind != kind || client_data.origin != relying_party().1 {
+		return Err("That passkey answer wasn't for this page".to_owned());
+	}
+	let now = now();
+	expected
+		.filter(|expected| expected.expires > now && expected.uid == uid)
+		.filter(|expected| expected.challenge == client_data.challenge)
+		.ok_or("That took too long, try again".to_owned())?;
+	Ok(())
+}
+
+struct AuthenticatorData {
+	flags: u8,
+	sign_count: u32,
+	// with ATTESTED_CREDENTIAL, the new credential's id and COSE_Key
+	credential: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+// rpIdHash (32), flags (1), signCount (4), then when adding a passkey
+// aaguid (16), credentialIdLength (2), credentialId and the CBOR public key
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData, String> {
+	let broken = || "The passkey's answer isn't right".to_owned();
+	if data.len() < 37 {
+		return Err(broken());
+	}
+	let rp_id_hash = Sha256::digest(relying_party().0.as_bytes());
+	if data[..32] != rp_id_hash[..] {
+		return Err("That passkey is for another site".to_owned());
+	}
+	let flags = data[32];
+	let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+	let credential = match flags & ATTESTED_CREDENTIAL {
+		0=>None,
+		_=>{
+			let rest = data.get(37+16..).ok_or_else(broken)?;
+			let id_len = u16::from_be_bytes([*rest.first().ok_or_else(broken)?, *rest.get(1).ok_or_else(broken)?]) as usize;
+			let id = rest.get(2..2+id_len).ok_or_else(broken)?.to_vec();
+			let key = rest.get(2+id_len..).ok_or_else(broken)?;
+			// the key is followed by extensions, if any; only the key's own bytes are kept
+			let mut reader = std::io::Cursor::new(key);
+			ciborium::de::from_reader::<Value, _>(&mut reader).map_err(|_| broken())?;
+			let key = key[..reader.position() as usize].to_vec();
+			Some( (id, key) )
+		}
+	};
+	Ok( AuthenticatorData{ flags, sign_count, credential } )
+}
+
+fn cose_field(key: &[(Value, Value)], label: i128) -> Option<&Value> {
+	key.iter()
+		.find(|(name, _)| name.as_integer().is_some_and(|name| i128::from(name) == label))
+		.map(|(_, value)| value)
+}
+
+fn cose_bytes(key: &[(Value, Value)], label: i128) -> Option<Vec<u8>> {
+	cose_field(key, label)?.as_bytes().cloned()
+}
+
+// the kinds of keys asked for when adding a passkey
+enum PublicKey {
+	Es256{ x: Vec<u8>, y: Vec<u8> },
+	EdDsa{ x: Vec<u8> },
+	Rs256{ n: Vec<u8>, e: Vec<u8> },
+}
+
+impl PublicKey {
+	fn from_cose(cose_key: &[u8]) -> Option<Self> {
+		let key = ciborium::de::from_reader::<Value, _>(cose_key).ok()?;
+		let key = key.as_map()?;
+		match i128::from(cose_field(key, 3)?.as_integer()?) {
+			ES256=>Some( Self::Es256{ x: cose_bytes(key, -2)?, y: cose_bytes(key, -3)? } ),
+			EDDSA=>Some( Self::EdDsa{ x: cose_bytes(key, -2)? } ),
+			RS256=>Some( Self::Rs256{ n: cose_bytes(key, -1)?, e: cose_bytes(key, -2)? } ),
+			_=>None,
+		}
+	}
+
+	fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+		let verified = match self {
+			Self::Es256{ x, y }=>{
+				let point = [&[0x04], x.as_slice(), y.as_slice()].concat();
+				UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, point).verify(message, signature)
+			}
+			Self::EdDsa{ x }=>UnparsedPublicKey::new(&ED25519, x).verify(message, signature),
+			Self::Rs256{ n, e }=>RsaPublicKeyComponents{ n, e }
+				.verify(&RSA_PKCS1_2048_8192_SHA256, message, signature),
+		};
+		verified.is_ok()
+	}
+}
+
+pub async fn display_passkeys(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+) -> Result<Markup, Redirect> {
+	let mut state = stt.lock().await;
+	let acc = read_viewer(&state, &cookies).ok_or(make_redirect(login_url("/passkeys")))?;
+	Ok( view_passkeys(&mut state, &acc, &browser_token(&cookies), "").await )
+}
+
+pub async fn perform_add_passkey(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+	Form(form): Form<PasskeyRegisterForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+	let Some(acc) = read_viewer(&state, &cookies) else {
+		return Ok(make_redirect(login_url("/passkeys")));
+	};
+	let browser = browser_token(&cookies);
+	match add_passkey(&mut state, &acc, &browser, &form).await {
+		Ok(())=>Ok( make_redirect("/passkeys".to_owned()) ),
+		Err(e)=>Err( view_passkeys(&mut state, &acc, &browser, &e).await ),
+	}
+}
+
+async fn add_passkey(state: &mut ServerState, acc: &Account, browser: &str, form: &PasskeyRegisterForm) -> Result<(), String> {
+	let client_data = decode(&form.client_data)?;
+	check_client_data(state, browser, &client_data, "webauthn.create", Some(acc.uid))?;
+
+	let attestation = ciborium::de::from_reader::<Value, _>(decode(&form.attestation)?.as_slice())
+		.map_err(|_| "The passkey's answer isn't right".to_owned())?;
+	let auth_data = attestation.as_map()
+		.and_then(|attestation| attestation.iter().find(|(name, _)| name.as_text() == Some("authData")))
+		.and_then(|(_, auth_data)| auth_data.as_bytes())
+		.ok_or("The passkey's answer isn't right".to_owned())?;
+	let auth_data = parse_authenticator_data(auth_data)?;
+	if auth_data.flags & USER_PRESENT == 0 {
+		return Err("The passkey wasn't confirmed on the device".to_owned());
+	}
+	let (credential_id, public_key) = auth_data.credential
+		.ok_or("The passkey's answer isn't right".to_owned())?;
+	if PublicKey::from_cose(&public_key).is_none() {
+		return Err("That passkey uses a kind of key this library can't check".to_owned());
+	}
+
+	let credential_id = URL_SAFE_NO_PAD.encode(credential_id);
+	let name = match form.name.trim() {
+		""=>"Passkey".to_owned(),
+		name=>name.chars().take(MAX_NAME).collect(),
+	};
+	let (now, sign_count) = (now(), auth_data.sign_count);
+	sqlx::query!(
+		"INSERT INTO passkeys (user_id, credential_id, public_key, sign_count, name, created)
+		VALUES (?, ?, ?, ?, ?, ?)",
+		acc.uid, credential_id, public_key, sign_count, name, now,
+	).execute(&state.db).await.map_err(|e| match e.as_database_error().is_some_and(|e| e.is_unique_violation()) {
+		true=>"That passkey is already added".to_owned(),
+		false=>e.to_string(),
+	})?;
+	Ok(())
+}
+
+pub async fn perform_remove_passkey(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+	Form(form): Form<PasskeyForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+	let Some(acc) = read_viewer(&state, &cookies) else {
+		return Ok(make_redirect(login_url("/passkeys")));
+	};
+	let result = sqlx::query!(
+		"DELETE FROM passkeys WHERE id = ? AND user_id = ?",
+		form.id, acc.uid,
+	).execute(&state.db).await;
+	if let Err(e) = result {
+		return Err(view_passkeys(&mut state, &acc, &browser_token(&cookies), &e.to_string()).await);
+	}
+	Ok( make_redirect("/passkeys".to_owned()) )
+}
+
+pub async fn display_passkey_login(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+	Query(goto): Query<Goto>,
+) -> Markup {
+	let mut state = stt.lock().await;
+	let goto = csrf::safe_goto(goto.goto.as_deref());
+	view_passkey_login(&mut state, &browser_token(&cookies), goto, "")
+}
+
+// a passkey stands in for the password; one that checked who is holding it
+// (a PIN or a fingerprint) is two factors by itself and skips the code step
+pub async fn perform_passkey_login(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+	headers: HeaderMap,
+	Query(goto): Query<Goto>,
+	Form(form): Form<PasskeyLoginForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+	let goto = csrf::safe_goto(goto.goto.as_deref());
+	let browser = browser_token(&cookies);
+
+	let (uid, verified) = match check_passkey_login(&mut state, &browser, &form).await {
+		Ok(login)=>login,
+		Err(e)=>return Err(view_passkey_login(&mut state, &browser, goto, &e)),
+	};
+	let user_agent = sessions::user_agent(&headers);
+	if !verified && two_factor::is_enabled(&state, uid) {
+		cookies.add(two_factor::start_login(&mut state, uid, user_agent));
+		return Ok(make_redirect(with_goto("/login/two_factor", goto)));
+	}
+	let cookie = match sessions::start(&mut state, uid, user_agent).await {
+		Ok(cookie)=>cookie,
+		Err(e)=>return Err(view_passkey_login(&mut state, &browser, goto, &e)),
+	};
+	cookies.add(cookie);
+	if state.uid_to_account.get(&uid).is_some_and(|acc| two_factor::missing(&state, acc)) {
+		return Ok(make_redirect("/two_factor".to_owned()));
+	}
+	Ok( Redirect::to(goto) )
+}
+
+// the account the passkey is for, and whether the device verified its user
+async fn check_passkey_login(state: &mut ServerState, browser: &str, form: &PasskeyLoginForm) -> Result<(Uid, bool), String> {
+	let client_data = decode(&form.client_data)?;
+	check_client_data(state, browser, &client_data, "webauthn.get", None)?;
+
+	let passkey = sqlx::query!(
+		"SELECT id, user_id, public_key, sign_count FROM passkeys WHERE credential_id = ?",
+		form.credential_id,
+	).fetch_optional(&state.db).await.map_err(|e| e.to_string())?
+		.ok_or("That passkey isn't added to any account here".to_owned())?;
+
+	let auth_data_bytes = decode(&form.authenticator_data)?;
+	let auth_data = parse_authenticator_data(&auth_data_bytes)?;
+	if auth_data.flags & USER_PRESENT == 0 {
+		return Err("The passkey wasn't confirmed on the device".to_owned());
+	}
+	let public_key = PublicKey::from_cose(&passkey.public_key)
+		.ok_or("That passkey's stored key is broken".to_owned())?;
+	let message = [auth_data_bytes.as_slice(), &Sha256::digest(&client_data)].concat();
+	if !public_key.verify(&message, &decode(&form.signature)?) {
+		return Err("The passkey's signature isn't right".to_owned());
+	}
+	// a counter that doesn't go up means the key was copied off its device
+	let sign_count = auth_data.sign_count as i64;
+	if (sign_count != 0 || passkey.sign_count != 0) && sign_count <= passkey.sign_count {
+		eprintln!("passkey {} of account {} refused, its counter went back", passkey.id, passkey.user_id);
+		return Err("That passkey was refused, remove it and add it again".to_owned());
+	}
+
+	let now = now();
+	sqlx::query!(
+		"UPDATE passkeys SET sign_count = ?, last_used = ? WHERE id = ?",
+		sign_count, now, passkey.id,
+	).execute(&state.db).await.map_err(|e| e.to_string())?;
+	if !state.uid_to_account.contains_key(&passkey.user_id) {
+		return Err("That account no longer exists".to_owned());
+	}
+	Ok( (passkey.user_id, auth_data.flags & USER_VERIFIED != 0) )
+}
+
+async fn view_passkeys(state: &mut ServerState, acc: &Account, browser: &str, error: &str) -> Markup {
+	let passkeys = sqlx::query!(
+		"SELECT id, credential_id, name, created, last_used FROM passkeys WHERE user_id = ? ORDER BY created",
+		acc.uid,
+	).fetch_all(&state.db).await;
+	let (passkeys, mut error) = match passkeys {
+		Ok(passkeys)=>(passkeys, error.to_owned()),
+		Err(e)=>(Vec::new(), e.to_string()),
+	};
+	let challenge = new_challenge(state, browser, Some(acc.uid))
+		.map_err(|e| error = e)
+		.ok();
+	let exclude = serde_json::to_string(
+		&passkeys.iter().map(|passkey| &passkey.credential_id).collect::<Vec<_>>()
+	).unwrap_or_default();
+
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		script src="/files/js/passkeys.js" defer {}
+		title { "LSYS - Passkeys" }
+	} body {
+		p style="color: red;" { (error) }
+		nav {
+			a href="/dashboard" { "Dashboard" }
+		}
+
+		h1 { "Passkeys" }
+		p { "A passkey logs you in with your phone, a security key or this device, without your password." }
+		@if passkeys.is_empty() {
+			p { "You have no passkeys yet." }
+		}
+		table {
+			tbody {
+				@for passkey in &passkeys { tr {
+					td { (passkey.name) }
+					td { "added " (view_time(passkey.created)) }
+					td { @if let Some(last_used) = passkey.last_used { "last used " (view_time(last_used)) } }
+					td {
+						form method="POST" action="/passkeys/remove" {
+							(csrf::field())
+							input type="hidden" name="id" value=(passkey.id) {}
+							button { "Remove" }
+						}
+					}
+				} }
+			}
+		}
+
+		@if let Some(challenge) = challenge { fieldset {
+			legend { "Add a passkey" }
+			form method="POST" action="/passkeys/add" data-passkey="register"
+				data-challenge=(challenge) data-rp-id=(relying_party().0)
+				data-user-id=(acc.uid) data-user-name=(acc.email) data-user-display=(acc.name)
+				data-exclude=(exclude) {
+				(csrf::field())
+				input type="hidden" name="client_data" {}
+				input type="hidden" name="attestation" {}
+				label for="passkey-name" { "name:" }
+				input id="passkey-name" name="name" type="text" placeholder="e.g. my phone" maxlength=(MAX_NAME) {}
+				br {}
+				button { "Add" }
+				p class="passkey-error" style="color: red;" {}
+			}
+		} }
+	} }
+}
+
+fn view_passkey_login(state: &mut ServerState, browser: &str, goto: &str, error: &str) -> Markup {
+	let mut error = error.to_owned();
+	let challenge = new_challenge(state, browser, None)
+		.map_err(|e| error = e)
+		.ok();
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		script src="/files/js/passkeys.js" defer {}
+		title { "LSYS - Login" }
+	} body {
+		p style="color: red;" { (error) }
+		@if let Some(challenge) = challenge { fieldset {
+			legend { "Log in with a passkey" }
+			form method="POST" action=(with_goto("/login/passkey", goto)) data-passkey="login"
+				data-challenge=(challenge) data-rp-id=(relying_party().0) {
+				(csrf::field())
+				input type="hidden" name="credential_id" {}
+				input type="hidden" name="client_data" {}
+				input type="hidden" name="authenticator_data" {}
+				input type="hidden" name="signature" {}
+				button { "Use a passkey" }
+				p class="passkey-error" style="color: red;" {}
+			}
+		} }
+		a href=(login_url(goto)) { "Log in with a password" }
+	} }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ring::rand::SystemRandom;
+	use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+	const BROWSER: &str = "test-browser";
+	// the first account in schema.sql
+	const UID: Uid = 1;
+
+	// a software authenticator holding a single P-256 passkey
+	struct Authenticator {
+		key: EcdsaKeyPair,
+		rng: SystemRandom,
+	}
+
+	const CREDENTIAL_ID: &[u8] = b"software authenticator";
+
+	impl Authenticator {
+		fn new() -> Self {
+			let rng = SystemRandom::new();
+			let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+			let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+			Self{ key, rng }
+		}
+
+		fn cose_key(&self) -> Vec<u8> {
+			// an uncompressed point, 0x04 then x and y
+			let point = self.key.public_key().as_ref();
+			let key = Value::Map(vec![
+				(Value::from(1), Value::from(2)),
+				(Value::from(3), Value::from(ES256 as i64)),
+				(Value::from(-1), Value::from(1)),
+				(Value::from(-2), Value::Bytes(point[1..33].to_vec())),
+				(Value::from(-3), Value::Bytes(point[33..].to_vec())),
+			]);
+			cbor(&key)
+		}
+
+		fn register(&self, challenge: &str) -> PasskeyRegisterForm {
+			let auth_data = [
+				auth_data(&relying_party().0, USER_PRESENT | ATTESTED_CREDENTIAL, 0),
+				vec![0; 16],
+				(CREDENTIAL_ID.len() as u16).to_be_bytes().to_vec(),
+				CREDENTIAL_ID.to_vec(),
+				self.cose_key(),
+			].concat();
+			let attestation = Value::Map(vec![
+				(Value::from("fmt"), Value::from("none")),
+				(Value::from("attStmt"), Value::Map(Vec::new())),
+				(Value::from("authData"), Value::Bytes(auth_data)),
+			]);
+			PasskeyRegisterForm{
+				name: "software".to_owned(),
+				client_data: URL_SAFE_NO_PAD.encode(client_data("webauthn.create", challenge, &relying_party().1)),
+				attestation: URL_SAFE_NO_PAD.encode(cbor(&attestation)),
+			}
+		}
+
+		fn sign(&self, client_data: &[u8], auth_data: &[u8]) -> PasskeyLoginForm {
+			let message = [auth_data, &Sha256::digest(client_data)].concat();
+			let signature = self.key.sign(&self.rng, &message).unwrap();
+			PasskeyLoginForm{
+				credential_id: URL_SAFE_NO_PAD.encode(CREDENTIAL_ID),
+				client_data: URL_SAFE_NO_PAD.encode(client_data),
+				authenticator_data: URL_SAFE_NO_PAD.encode(auth_data),
+				signature: URL_SAFE_NO_PAD.encode(signature),
+			}
+		}
+	}
+
+	fn cbor(value: &Value) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		ciborium::ser::into_writer(value, &mut bytes).unwrap();
+		bytes
+	}
+
+	fn auth_data(rp_id: &str, flags: u8, sign_count: u32) -> Vec<u8> {
+		[Sha256::digest(rp_id.as_bytes()).as_slice(), &[flags], &sign_count.to_be_bytes()].concat()
+	}
+
+	fn client_data(kind: &str, challenge: &str, origin: &str) -> Vec<u8> {
+		serde_json::to_vec(&serde_json::json!({ "type": kind, "challenge": challenge, "origin": origin })).unwrap()
+	}
+
+	// a fresh db from schema.sql, with the authenticator's passkey added to UID
+	async fn registered(authenticator: &Authenticator) -> SharedState {
+		let stt = crate::new_shared_state(crate::sample_db().await).await;
+
+		let mut state = stt.lock().await;
+		let acc = state.uid_to_account[&UID].clone();
+		let challenge = new_challenge(&mut state, BROWSER, Some(UID)).unwrap();
+		add_passkey(&mut state, &acc, BROWSER, &authenticator.register(&challenge)).await.unwrap();
+		drop(state);
+		stt
+	}
+
+	async fn log_in(
+		state: &mut ServerState,
+		authenticator: &Authenticator,
+		origin: &str,
+		rp_id: &str,
+		sign_count: u32,
+	) -> Result<(Uid, bool), String> {
+		let challenge = new_challenge(state, BROWSER, None).unwrap();
+		let form = authenticator.sign(
+			&client_data("webauthn.get", &challenge, origin),
+			&auth_data(rp_id, USER_PRESENT, sign_count),
+		);
+		check_passkey_login(state, BROWSER, &form).await
+	}
+
+	#[tokio::test]
+	async fn valid_login() {
+		let authenticator = Authenticator::new();
+		let stt = registered(&authenticator).await;
+		let mut state = stt.lock().await;
+		let (id, origin) = relying_party();
+		assert_eq!(log_in(&mut state, &authenticator, &origin, &id, 1).await, Ok((UID, false)));
+	}
+
+	#[tokio::test]
+	async fn wrong_origin_or_rp_id() {
+		let authenticator = Authenticator::new();
+		let stt = registered(&authenticator).await;
+		let mut state = stt.lock().await;
+		let (id, origin) = relying_party();
+		assert!(log_in(&mut state, &authenticator, "https://evil.example", &id, 1).await.is_err());
+		assert!(log_in(&mut state, &authenticator, &origin, "evil.example", 2).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn reused_challenge() {
+		let authenticator = Authenticator::new();
+		let stt = registered(&authenticator).await;
+		let mut state = stt.lock().await;
+		let (id, origin) = relying_party();
+		let challenge = new_challenge(&mut state, BROWSER, None).unwrap();
+		let form = authenticator.sign(
+			&client_data("webauthn.get", &challenge, &origin),
+			&auth_data(&id, USER_PRESENT, 1),
+		);
+		assert!(check_passkey_login(&mut state, BROWSER, &form).await.is_ok());
+		assert!(check_passkey_login(&mut state, BROWSER, &form).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn sign_count_must_increase() {
+		let authenticator = Authenticator::new();
+		let stt = registered(&authenticator).await;
+		let mut state = stt.lock().await;
+		let (id, origin) = relying_party();
+		assert!(log_in(&mut state, &authenticator, &origin, &id, 5).await.is_ok());
+		assert!(log_in(&mut state, &authenticator, &origin, &id, 5).await.is_err());
+		assert!(log_in(&mut state, &authenticator, &origin, &id, 4).await.is_err());
+		assert!(log_in(&mut state, &authenticator, &origin, &id, 6).await.is_ok());
+	}
+
+	#[tokio::test]
+	async fn bad_signature() {
+		let authenticator = Authenticator::new();
+		let stt = registered(&authenticator).await;
+		let mut state = stt.lock().await;
+		let (id, origin) = relying_party();
+		// another key claiming the same credential
+		let impostor = Authenticator::new();
+		assert!(log_in(&mut state, &impostor, &origin, &id, 1).await.is_err());
+	}
+}
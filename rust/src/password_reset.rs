@@ -0,0 +1,182 @@
+// resetting a forgotten password through a single use link sent by mail
+
+use axum::{
+	Form,
+	extract::{Query, State},
+	response::Redirect,
+};
+use maud::{html, Markup, DOCTYPE};
+use crate::types::*;
+use crate::mail::{Mail, public_url};
+use crate::sessions::{new_token, hash_token};
+use crate::{SharedState, make_redirect, api_tokens, csrf, passwords, sessions};
+
+const RESET_MINUTES: i64 = 30;
+
+fn now() -> i64 {
+	chrono::Utc::now().timestamp()
+}
+
+pub async fn display_forgot_password() -> Markup {
+	view_forgot_password("", "")
+}
+
+// the answer is the same whether or not the email has an account,
+// and the mail goes out after answering so the time taken doesn't tell either
+pub async fn perform_forgot_password(
+	State(stt): State<SharedState>,
+	Form(form): Form<ForgotPasswordForm>,
+) -> Markup {
+	let state = stt.lock().await;
+	let sent = "If that email has an account, a link to reset its password is on its way.";
+	let Some(acc) = state.email_to_uid.get(form.email.trim()).and_then(|uid| state.uid_to_account.get(uid)) else {
+		return view_forgot_password(sent, "");
+	};
+
+	let (db, transport, acc) = (state.db.clone(), state.mail.clone(), acc.clone());
+	tokio::spawn(async move {
+		let token = new_token();
+		let token_hash = hash_token(&token);
+		let expires = now() + RESET_MINUTES * 60;
+		let result = sqlx::query!(
+			"INSERT INTO password_resets (token_hash, user_id, expires) VALUES (?, ?, ?)",
+			token_hash, acc.uid, expires,
+		).execute(&db).await;
+		if let Err(e) = result {
+			eprintln!("can't store password reset for account {}: {e}", acc.uid);
+			return;
+		}
+
+		let link = format!("{}/password/reset?token={token}", public_url());
+		let mail = Mail{
+			to: acc.email.clone(),
+			subject: "Reset your LSYS password".to_owned(),
+			body: format!(
+				"Hello {},\r\n\r\nSomeone asked to reset the password of your LSYS account. \
+				To choose a new one, open this link within {RESET_MINUTES} minutes:\r\n\r\n{link}\r\n\r\n\
+				If it wasn't you, ignore this mail and your password stays as it is.",
+				acc.name,
+			),
+		};
+		if let Err(e) = transport.send(&mail).await {
+			eprintln!("can't send password reset mail to account {}: {e}", acc.uid);
+		}
+	});
+	view_forgot_password(sent, "")
+}
+
+pub async fn display_reset_password(
+	State(stt): State<SharedState>,
+	Query(param): Query<ResetParam>,
+) -> Markup {
+	let db = stt.lock().await.db.clone();
+	let token_hash = hash_token(&param.token);
+	let now = now();
+	let reset = sqlx::query!(
+		"SELECT id FROM password_resets WHERE token_hash = ? AND used IS NULL AND expires > ?",
+		token_hash, now,
+	).fetch_optional(&db).await;
+
+	match reset {
+		Ok(Some(_))=>view_reset_password(&param.token, ""),
+		Ok(None)=>view_forgot_password("", "That link has expired or was already used, ask for a new one."),
+		Err(e)=>view_forgot_password("", &e.to_string()),
+	}
+}
+
+// a reset logs out every session and revokes every API token, whoever had the old password can't stay in
+pub async fn perform_reset_password(
+	State(stt): State<SharedState>,
+	Form(form): Form<ResetPasswordForm>,
+) -> Result<Redirect, Markup> {
+	if form.new_pass.is_empty() {
+		return Err(view_reset_password(&form.token, "The new password can't be empty"));
+	}
+	if form.new_pass != form.confirm_pass {
+		return Err(view_reset_password(&form.token, "The new passwords don't match"));
+	}
+	// hashed before locking the state, a wrong token only costs the hashing
+	let pass_hash = passwords::hash(&form.new_pass).await
+		.map_err(|e| view_reset_password(&form.token, &e))?;
+
+	let mut state = stt.lock().await;
+
+	// used up before anything else, so the same link can't be followed twice
+	let token_hash = hash_token(&form.token);
+	let now = now();
+	let reset = sqlx::query!(
+		"UPDATE password_resets SET used = ? WHERE token_hash = ? AND used IS NULL AND expires > ?
+		RETURNING user_id",
+		now, token_hash, now,
+	).fetch_optional(&state.db).await
+		.map_err(|e| view_reset_password(&form.token, &e.to_string()))?;
+	let Some(reset) = reset else {
+		return Err(view_forgot_password("", "That link has expired or was already used, ask for a new one."));
+	};
+	let Some(acc) = state.uid_to_account.get(&reset.user_id).cloned() else {
+		return Err(view_forgot_password("", "That account no longer exists"));
+	};
+
+	passwords::set_pass_hash(&mut state, &acc, pass_hash).await
+		.map_err(|e| view_forgot_password("", &e))?;
+	// other links sent before this one are no good anymore either
+	sqlx::query!(
+		"UPDATE password_resets SET used = ? WHERE user_id = ? AND used IS NULL",
+		now, acc.uid,
+	).execute(&state.db).await
+		.map_err(|e| view_forgot_password("", &e.to_string()))?;
+	sessions::end_sessions(&mut state, acc.uid, None).await
+		.map_err(|e| view_forgot_password("", &e))?;
+	// nor can a login that got past the old password finish its second step, or a script go on
+	state.token_to_pending_login.retain(|_, pending| pending.uid != acc.uid);
+	api_tokens::revoke_all(&mut state, acc.uid).await
+		.map_err(|e| view_forgot_password("", &e))?;
+	Ok( make_redirect("/login".to_owned()) )
+}
+
+fn view_forgot_password(message: &str, error: &str) -> Markup {
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		title { "LSYS - Forgotten password" }
+	} body {
+		p style="color: red;" { (error) }
+		p { (message) }
+		fieldset {
+			legend { "Forgotten password" }
+			form method="POST" action="/password/forgot" {
+				(csrf::field())
+				label for="forgot-email" { "email:" }
+				input id="forgot-email" name="email" type="email" placeholder="email" {}
+				br {}
+				button { "Send me a link" }
+			}
+		}
+		a href="/login" { "Back to logging in" }
+	} }
+}
+
+fn view_reset_password(token: &str, error: &str) -> Markup {
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		title { "LSYS - Reset password" }
+	} body {
+		p style="color: red;" { (error) }
+		fieldset {
+			legend { "Choose a new password" }
+			form method="POST" action="/password/reset" {
+				(csrf::field())
+				input type="hidden" name="token" value=(token) {}
+				label for="reset-new" { "new password:" }
+				input id="reset-new" name="new_pass" type="password" placeholder="password" {}
+				br {}
+				label for="reset-confirm" { "again:" }
+				input id="reset-confirm" name="confirm_pass" type="password" placeholder="password" {}
+				br {}
+				button { "Save" }
+			}
+			p { "Saving it logs you out everywhere." }
+		}
+	} }
+}
@@ -0,0 +1,175 @@
+// password hashes are argon2id in PHC format, each with its own random salt;
+// accounts from before that still have the old djb2 uuid hash until they next log in
+
+use argon2::{
+	Argon2,
+	PasswordHash,
+	PasswordHasher,
+	PasswordVerifier,
+	password_hash::{SaltString, rand_core::OsRng},
+};
+use axum::{
+	Form,
+	extract::State,
+	response::Redirect,
+};
+use maud::{html, Markup};
+use std::sync::{Arc, OnceLock};
+use tower_cookies::Cookies;
+use uuid::Uuid;
+use crate::types::*;
+use crate::{SharedState, ServerState, make_redirect, login_url, account, csrf, sessions};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verified {
+	Wrong,
+	Right,
+	// right, but the stored hash should be replaced with an argon2id one
+	Legacy,
+}
+
+// argon2 takes tens of milliseconds of CPU, so hash and verify run it on the blocking pool,
+// and are awaited without the state locked, or every other request would wait too
+pub async fn hash(pass: &str) -> Result<String, String> {
+	let pass = pass.to_owned();
+	tokio::task::spawn_blocking(move || hash_password(&pass)).await
+		.map_err(|e| format!("can't hash password: {e}"))?
+}
+
+// with no stored hash, for no account, the dummy one is checked instead
+pub async fn verify(pass: &str, stored: Option<&str>) -> Verified {
+	let (pass, stored) = (pass.to_owned(), stored.map(str::to_owned));
+	tokio::task::spawn_blocking(move || match stored {
+		Some(stored)=>verify_password(&pass, &stored),
+		None=>verify_password(&pass, dummy_hash()),
+	}).await.unwrap_or(Verified::Wrong)
+}
+
+fn hash_password(pass: &str) -> Result<String, String> {
+	let salt = SaltString::generate(&mut OsRng);
+	Argon2::default()
+		.hash_password(pass.as_bytes(), &salt)
+		.map(|hash| hash.to_string())
+		.map_err(|e| format!("can't hash password: {e}"))
+}
+
+// checked against when there is no account, so refusing an unknown email takes as long as a wrong password
+fn dummy_hash() -> &'static str {
+	static DUMMY: OnceLock<String> = OnceLock::new();
+	DUMMY.get_or_init(|| hash_password("").expect("can't hash the dummy password"))
+}
+
+fn verify_password(pass: &str, stored: &str) -> Verified {
+	if !is_phc(stored) {
+		return match legacy_hash(pass.as_bytes()) == stored {
+			true=>Verified::Legacy,
+			false=>Verified::Wrong,
+		};
+	}
+	let Ok(hash) = PasswordHash::new(stored) else {
+		return Verified::Wrong;
+	};
+	match Argon2::default().verify_password(pass.as_bytes(), &hash) {
+		Ok(())=>Verified::Right,
+		Err(_)=>Verified::Wrong,
+	}
+}
+
+// PHC strings look like $argon2id$v=19$m=..,t=..,p=..$salt$hash, legacy ones are uuids
+fn is_phc(stored: &str) -> bool {
+	stored.starts_with('$')
+}
+
+// djb2, wrapping like the release builds that made the old hashes did
+fn djb2(st: &[u8]) -> i64 {
+	let mut hash: i64 = 5381;
+	for chr in st {
+		hash = hash.wrapping_mul(33).wrapping_add(*chr as i64);
+	}
+	hash
+}
+
+// password String -> hash i64 -> [u8] -> v3_uuid String
+fn legacy_hash(pass: &[u8]) -> String {
+	let pass_hash = djb2(pass).to_le_bytes();
+	Uuid::new_v3(&Uuid::NAMESPACE_OID, &pass_hash).to_string()
+}
+
+// the hash comes from hash, made before the state was locked
+pub async fn set_pass_hash(state: &mut ServerState, acc: &Account, pass_hash: String) -> Result<(), String> {
+	sqlx::query!(
+		"UPDATE accounts SET pass_hash = ? WHERE id = ?",
+		pass_hash, acc.uid,
+	).execute(&state.db).await.map_err(|e| e.to_string())?;
+	Account::update_maps(state, Account{ pass_hash, ..acc.clone() });
+	Ok(())
+}
+
+// every other session is ended, whoever knew the old password is logged out
+pub async fn perform_change_password(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+	Form(form): Form<PasswordChangeForm>,
+) -> Result<Redirect, Markup> {
+	let Some((session, acc)) = read_session_account(&stt, &cookies).await else {
+		return Ok(make_redirect(login_url("/account")));
+	};
+
+	let error = if verify(&form.pass, Some(&acc.pass_hash)).await == Verified::Wrong {
+		Some("Wrong password")
+	} else if form.new_pass.is_empty() {
+		Some("The new password can't be empty")
+	} else if form.new_pass != form.confirm_pass {
+		Some("The new passwords don't match")
+	} else {
+		None
+	};
+	let pass_hash = match error {
+		Some(error)=>Err(error.to_owned()),
+		None=>hash(&form.new_pass).await,
+	};
+
+	// read again, the account may have changed while the lock was let go
+	let mut state = stt.lock().await;
+	let Some(acc) = sessions::read_session(&state, &cookies)
+		.filter(|current| current.id == session.id)
+		.and_then(|_| state.uid_to_account.get(&acc.uid).cloned())
+	else {
+		return Ok(make_redirect(login_url("/account")));
+	};
+	let pass_hash = pass_hash.map_err(|e| account::view_account(&state, &acc, &e))?;
+	set_pass_hash(&mut state, &acc, pass_hash).await
+		.map_err(|e| account::view_account(&state, &acc, &e))?;
+	sessions::end_sessions(&mut state, acc.uid, Some(session.id)).await
+		.map_err(|e| account::view_account(&state, &acc, &e))?;
+	Ok( make_redirect("/account".to_owned()) )
+}
+
+async fn read_session_account(stt: &SharedState, cookies: &Cookies) -> Option<(Arc<Session>, Arc<Account>)> {
+	let state = stt.lock().await;
+	let session = sessions::read_session(&state, cookies)?;
+	let acc = state.uid_to_account.get(&session.user_id).cloned()?;
+	Some((session, acc))
+}
+
+pub fn view_change_password() -> Markup {
+	html! {
+		fieldset {
+			legend { "Change password" }
+			form method="POST" action="/password" {
+				(csrf::field())
+				label for="password-current" { "current password:" }
+				input id="password-current" name="pass" type="password" placeholder="password" {}
+				br {}
+				label for="password-new" { "new password:" }
+				input id="password-new" name="new_pass" type="password" placeholder="password" {}
+				br {}
+				label for="password-confirm" { "again:" }
+				input id="password-confirm" name="confirm_pass" type="password" placeholder="password" {}
+				br {}
+				button { "Change" }
+			}
+			p { "Changing it logs out every other session." }
+		}
+	}
+}
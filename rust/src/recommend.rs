@@ -0,0 +1,167 @@
+// "people who borrowed this also borrowed", from titles borrowed by the same patrons in borrow_log
+
+use maud::{html, Markup};
+use std::collections::{HashMap, HashSet};
+use crate::types::*;
+use crate::{SharedState, ServerState, title_copy};
+
+const DEFAULT_REFRESH_SECS: u64 = 60 * 60;
+// similar titles kept for each title
+const SIMILAR_PER_TITLE: usize = 20;
+const ALSO_BORROWED_SHOWN: usize = 5;
+const RECOMMENDATIONS_SHOWN: usize = 10;
+
+// cosine similarity between titles, each title being the set of patrons who borrowed it
+async fn compute(db: &sqlx::Pool<sqlx::Sqlite>) -> Result<Recommendations, sqlx::Error> {
+	// the loans of deleted accounts are all one account's, which says nothing about who read what
+	let loans = sqlx::query!(
+		"SELECT DISTINCT borrow_log.user_id, books.ISBN
+		FROM borrow_log
+		INNER JOIN books ON books.id = borrow_log.book_id
+		INNER JOIN accounts ON accounts.id = borrow_log.user_id
+		WHERE accounts.email != ?",
+		crate::account::DELETED_EMAIL,
+	).fetch_all(db).await?;
+
+	let mut borrowed = HashMap::<Uid, HashSet<ISBN>>::new();
+	for loan in loans {
+		borrowed.entry(loan.user_id).or_default().insert(loan.ISBN);
+	}
+
+	let mut readers = HashMap::<ISBN, usize>::new();
+	let mut together = HashMap::<(ISBN, ISBN), usize>::new();
+	for titles in borrowed.values() {
+		for isbn in titles {
+			*readers.entry(*isbn).or_default() += 1;
+			for other in titles.iter().filter(|other| *other != isbn) {
+				*together.entry((*isbn, *other)).or_default() += 1;
+			}
+		}
+	}
+
+	let mut similar = HashMap::<ISBN, Vec<(ISBN, f64)>>::new();
+	for ((isbn, other), count) in together {
+		let score = count as f64 / ((readers[&isbn] * readers[&other]) as f64).sqrt();
+		similar.entry(isbn).or_default().push((other, score));
+	}
+	for titles in similar.values_mut() {
+		titles.sort_by(|(isbn, score), (other, other_score)| {
+			other_score.total_cmp(score).then(isbn.cmp(other))
+		});
+		titles.truncate(SIMILAR_PER_TITLE);
+	}
+
+	Ok(Recommendations{
+		similar,
+		borrowed,
+		computed: Some(chrono::Utc::now()),
+	})
+}
+
+// recomputes every RECOMMEND_REFRESH_SECS, the first time right away;
+// the state is only locked to swap in the result, not while computing it
+pub fn spawn_refresh(stt: SharedState) {
+	let secs = std::env::var("RECOMMEND_REFRESH_SECS")
+		.ok()
+		.and_then(|secs| secs.parse::<u64>().ok())
+		.filter(|secs| *secs > 0)
+		.unwrap_or(DEFAULT_REFRESH_SECS);
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(std::time::Duration::from_secs(secs));
+		loop {
+			interval.tick().await;
+			let db = stt.lock().await.db.clone();
+			match compute(&db).await {
+				Ok(recommendations)=>stt.lock().await.recommendations = recommendations,
+				Err(e)=>eprintln!("can't compute recommendations: {e}"),
+			}
+		}
+	});
+}
+
+// borrowed before, or has a copy right now
+fn has_borrowed(state: &ServerState, uid: Uid, isbn: ISBN) -> bool {
+	let logged = state.recommendations.borrowed
+		.get(&uid)
+		.is_some_and(|titles| titles.contains(&isbn));
+	logged || state.bid_to_book.values()
+		.any(|book| book.ISBN == isbn && book.status.get().is_with_viewer(uid).0)
+}
+
+// titles borrowed by the people who borrowed this one, leaving out what the viewer already read
+pub fn also_borrowed(state: &ServerState, isbn: ISBN, uid: Option<Uid>) -> Vec<&Book> {
+	state.recommendations.similar
+		.get(&isbn)
+		.map(Vec::as_slice)
+		.unwrap_or_default()
+		.iter()
+		.filter(|(other, _)| !uid.is_some_and(|uid| has_borrowed(state, uid, *other)))
+		.filter_map(|(other, _)| title_copy(state, *other))
+		.take(ALSO_BORROWED_SHOWN)
+		.collect()
+}
+
+// every title similar to something the patron borrowed, scored by the sum of those similarities
+pub fn for_patron(state: &ServerState, uid: Uid) -> Vec<&Book> {
+	let Some(borrowed) = state.recommendations.borrowed.get(&uid) else {
+		return Vec::new();
+	};
+	let mut scores = HashMap::<ISBN, f64>::new();
+	for isbn in borrowed {
+		for (other, score) in state.recommendations.similar.get(isbn).map(Vec::as_slice).unwrap_or_default() {
+			*scores.entry(*other).or_default() += score;
+		}
+	}
+
+	let mut scores = scores.into_iter()
+		.filter(|(isbn, _)| !has_borrowed(state, uid, *isbn))
+		.collect::<Vec<_>>();
+	scores.sort_by(|(isbn, score), (other, other_score)| {
+		other_score.total_cmp(score).then(isbn.cmp(other))
+	});
+	scores.into_iter()
+		.filter_map(|(isbn, _)| title_copy(state, isbn))
+		.take(RECOMMENDATIONS_SHOWN)
+		.collect()
+}
+
+fn view_title_list(books: &[&Book]) -> Markup {
+	html! {
+		ul {
+			@for book in books {
+				li {
+					a href={"/book?bid=" (book.bid)} { i { (book.name) } }
+					@if !book.authors.is_empty() {
+						{" by " (book.authors.join(", "))}
+					}
+				}
+			}
+		}
+	}
+}
+
+pub fn view_also_borrowed(state: &ServerState, book: &Book, viewer: Option<&Account>) -> Markup {
+	let books = also_borrowed(state, book.ISBN, viewer.map(|viewer| viewer.uid));
+	html! {
+		@if !books.is_empty() {
+			section id="also-borrowed" {
+				h3 { "People who borrowed this also borrowed" }
+				(view_title_list(&books))
+			}
+		}
+	}
+}
+
+pub fn view_patron_recommendations(state: &ServerState, viewer: &Account) -> Markup {
+	let books = for_patron(state, viewer.uid);
+	html! {
+		section id="recommendations" {
+			h2 { "Recommended for you" }
+			@if books.is_empty() {
+				p { "Nothing yet, recommendations come from the books you borrow." }
+			} @else {
+				(view_title_list(&books))
+			}
+		}
+	}
+}
@@ -0,0 +1,245 @@
+// what each account may do: accounts have one role, roles a set of permissions, both kept in the db
+
+use axum::{
+	Form,
+	async_trait,
+	extract::{FromRequestParts, State},
+	http::{header, request::Parts, Method, StatusCode, Uri},
+	response::{IntoResponse, Redirect, Response},
+};
+use maud::{html, Markup, DOCTYPE};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use tower_cookies::Cookies;
+use crate::types::*;
+use crate::{
+	SharedState, ServerState,
+	read_state, read_viewer, make_redirect, login_url, view_error, csrf, two_factor, verification, api_tokens,
+};
+
+// the role of new accounts, as in the schema
+pub const PATRON: Rid = 1;
+
+pub async fn load(state: &mut ServerState) {
+	let roles = sqlx::query!(
+		"SELECT id, name FROM roles",
+	).fetch_all(&state.db).await.expect("can't parse row from roles");
+
+	let permissions = sqlx::query!(
+		"SELECT role_id, permission FROM role_permissions",
+	).fetch_all(&state.db).await.expect("can't parse row from role_permissions");
+
+	let mut rid_to_permissions = HashMap::<Rid, Vec<Permission>>::new();
+	for permission in permissions {
+		if let Some(known) = Permission::from_db(&permission.permission) {
+			rid_to_permissions.entry(permission.role_id).or_default().push(known);
+		}
+	}
+
+	for role in roles {
+		let permissions = rid_to_permissions.remove(&role.id).unwrap_or_default();
+		state.rid_to_role.insert(role.id, Arc::new(Role{
+			id: role.id,
+			name: role.name,
+			permissions: permissions.into_iter().collect(),
+		}));
+	}
+}
+
+pub fn has_permission(state: &ServerState, acc: &Account, permission: Permission) -> bool {
+	state.rid_to_role
+		.get(&acc.role)
+		.is_some_and(|role| role.permissions.contains(&permission))
+}
+
+// for views shown to anyone, nobody logged in may do nothing
+pub fn can(state: &ServerState, viewer: Option<&Account>, permission: Permission) -> bool {
+	viewer.is_some_and(|viewer| has_permission(state, viewer, permission))
+}
+
+pub trait RequiredPermission {
+	const PERMISSION: Permission;
+}
+
+pub struct Reserve;
+impl RequiredPermission for Reserve {
+	const PERMISSION: Permission = Permission::Reserve;
+}
+
+pub struct Circulate;
+impl RequiredPermission for Circulate {
+	const PERMISSION: Permission = Permission::Circulate;
+}
+
+pub struct Catalog;
+impl RequiredPermission for Catalog {
+	const PERMISSION: Permission = Permission::Catalog;
+}
+
+pub struct ManageAccounts;
+impl RequiredPermission for ManageAccounts {
+	const PERMISSION: Permission = Permission::ManageAccounts;
+}
+
+// the logged in account, or the one whose API token the request bears, when its role has P's permission;
+// otherwise the request goes to the login page, or gets a 403 when already logged in (a 401 for a bad token)
+pub struct Authorized<P>(pub Arc<Account>, pub PhantomData<P>);
+
+#[async_trait]
+impl<P: RequiredPermission> FromRequestParts<SharedState> for Authorized<P> {
+	type Rejection = Response;
+
+	async fn from_request_parts(parts: &mut Parts, stt: &SharedState) -> Result<Self, Self::Rejection> {
+		let cookies = Cookies::from_request_parts(parts, stt).await
+			.map_err(IntoResponse::into_response)?;
+		let state = stt.lock().await;
+
+		let bearer = api_tokens::bearer(&parts.headers);
+		let acc = match bearer {
+			Some(token)=>{
+				let token = api_tokens::read_token(&state, token).ok_or_else(api_tokens::unauthorized)?;
+				if !token.scope.allows(&parts.method) {
+					let error = format!("The API token \"{}\" may {}", token.name, token.scope.describe());
+					return Err((StatusCode::FORBIDDEN, view_error(error)).into_response());
+				}
+				state.uid_to_account.get(&token.user_id).cloned().ok_or_else(api_tokens::unauthorized)?
+			}
+			None=>match read_viewer(&state, &cookies) {
+				Some(acc)=>acc,
+				None=>return Err(make_redirect(login_url(&return_path(parts))).into_response()),
+			},
+		};
+		if !has_permission(&state, &acc, P::PERMISSION) {
+			let error = format!("You aren't allowed to {}", P::PERMISSION.describe());
+			return Err((StatusCode::FORBIDDEN, view_error(error)).into_response());
+		}
+		// a script can't be sent to set up two-factor, it is only told
+		if P::PERMISSION != Permission::Reserve && two_factor::missing(&state, &acc) {
+			if bearer.is_some() {
+				let error = "Your account needs two-factor set up before it can be used".to_owned();
+				return Err((StatusCode::FORBIDDEN, view_error(error)).into_response());
+			}
+			return Err(make_redirect("/two_factor".to_owned()).into_response());
+		}
+		// patrons are reached by mail about what they reserve
+		if P::PERMISSION == Permission::Reserve && !acc.verified {
+			return Err((StatusCode::FORBIDDEN, verification::view_verify_first()).into_response());
+		}
+		Ok( Self(acc, PhantomData) )
+	}
+}
+
+// where to come back to after logging in: the page itself, or for a form the page it was on
+fn return_path(parts: &Parts) -> String {
+	let uri = match parts.method {
+		Method::GET=>Some(parts.uri.clone()),
+		_=>parts.headers.get(header::REFERER)
+			.and_then(|referer| referer.to_str().ok())
+			.and_then(|referer| referer.parse::<Uri>().ok()),
+	};
+	uri.as_ref()
+		.and_then(Uri::path_and_query)
+		.map_or("/".to_owned(), |path| path.to_string())
+}
+
+pub async fn display_roles(
+	State(stt): State<SharedState>,
+	Authorized(acc, _): Authorized<ManageAccounts>,
+) -> Markup {
+	let state = read_state(stt).await;
+	view_roles(&state, &acc, "")
+}
+
+pub async fn perform_assign_role(
+	State(stt): State<SharedState>,
+	Authorized(acc, _): Authorized<ManageAccounts>,
+	Form(form): Form<RoleForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+	if !state.rid_to_role.contains_key(&form.rid) {
+		return Err(view_roles(&state, &acc, "No such role"));
+	}
+	let Some(target) = state.uid_to_account.get(&form.uid).cloned() else {
+		return Err(view_roles(&state, &acc, "No such account"));
+	};
+	// someone has to be left able to hand out roles
+	if target.uid == acc.uid && !state.rid_to_role[&form.rid].permissions.contains(&Permission::ManageAccounts) {
+		return Err(view_roles(&state, &acc, "You can't take away your own administrator role"));
+	}
+
+	sqlx::query!(
+		"UPDATE accounts SET role_id = ? WHERE id = ?",
+		form.rid, target.uid,
+	).execute(&state.db).await
+		.map_err(|e| view_roles(&state, &acc, &e.to_string()))?;
+	Account::update_maps(&mut state, Account{ role: form.rid, ..(*target).clone() });
+	Ok( make_redirect("/admin/roles".to_owned()) )
+}
+
+fn view_roles(state: &ServerState, viewer: &Account, error: &str) -> Markup {
+	let mut roles = state.rid_to_role.values().collect::<Vec<_>>();
+	roles.sort_by_key(|role| role.id);
+	let mut accounts = state.uid_to_account.values().collect::<Vec<_>>();
+	accounts.sort_by_key(|acc| acc.uid);
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		title { "LSYS - Roles" }
+	} body {
+		p style="color: red;" { (error) }
+		nav {
+			a href="/dashboard" { "Dashboard" }
+		}
+
+		h1 { "Roles" }
+		table {
+			thead { tr {
+				td { "Role" }
+				@for permission in Permission::ALL {
+					td { (permission.describe()) }
+				}
+			} }
+			tbody {
+				@for role in &roles { tr {
+					th { (role.name) }
+					@for permission in Permission::ALL {
+						td { @if role.permissions.contains(&permission) { "yes" } }
+					}
+				} }
+			}
+		}
+
+		h1 { "Two-factor authentication" }
+		(two_factor::view_policy(state))
+
+		h1 { "Accounts" }
+		table {
+			thead { tr {
+				td { "Name" }
+				td { "Email" }
+				td { "Role" }
+				td { "Two-factor" }
+			} }
+			tbody {
+				@for acc in &accounts { tr {
+					td { (acc.name) @if acc.uid == viewer.uid { b { " (you)" } } }
+					td { (acc.email) }
+					td {
+						form method="POST" action="/admin/roles/assign" {
+							(csrf::field())
+							input type="hidden" name="uid" value=(acc.uid) {}
+							select name="rid" {
+								@for role in &roles {
+									option value=(role.id) selected[role.id == acc.role] { (role.name) }
+								}
+							}
+							button { "Assign" }
+						}
+					}
+					td { @if two_factor::is_enabled(state, acc.uid) { "on" } }
+				} }
+			}
+		}
+	} }
+}
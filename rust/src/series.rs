@@ -0,0 +1,331 @@
+// series of titles, each book_info ISBN being at most one numbered volume
+
+use axum::{
+	Form,
+	extract::State,
+	extract::Query,
+	response::Redirect,
+};
+use maud::{html, Markup, DOCTYPE};
+use std::sync::Arc;
+use tower_cookies::Cookies;
+use crate::types::*;
+use crate::roles::{self, Authorized, Catalog};
+use crate::{
+	SharedState, ServerState,
+	read_state, read_viewer, make_redirect,
+	view_404, title_copy, availability, csrf,
+};
+
+pub async fn load(state: &mut ServerState) {
+	let series = sqlx::query_as!(
+		Series,
+		"SELECT id, name FROM series",
+	).fetch_all(&state.db).await.expect("can't parse row from series into Series");
+
+	let volumes = sqlx::query_as!(
+		SeriesVolume,
+		"SELECT series_id, ISBN, volume FROM series_volumes",
+	).fetch_all(&state.db).await.expect("can't parse row from series_volumes into SeriesVolume");
+
+	series.into_iter().for_each(|series| Series::update_maps(state, series));
+	volumes.into_iter().for_each(|volume| volume.update_maps(state));
+}
+
+impl Series {
+	fn update_maps(state: &mut ServerState, series: Self) {
+		state.srid_to_volumes.entry(series.id).or_default();
+		state.srid_to_series.insert(series.id, Arc::new(series));
+	}
+}
+
+impl SeriesVolume {
+	fn update_maps(self, state: &mut ServerState) {
+		state.ISBN_to_series.insert(self.ISBN, self.series_id);
+		let volumes = state.srid_to_volumes.entry(self.series_id).or_default();
+		volumes.push(self);
+		volumes.sort_by_key(|volume| volume.volume);
+	}
+}
+
+pub fn series_volumes(state: &ServerState, srid: Srid) -> &[SeriesVolume] {
+	state.srid_to_volumes
+		.get(&srid)
+		.map(Vec::as_slice)
+		.unwrap_or_default()
+}
+
+// the volume of this title, and the ones right before and after it
+pub fn series_neighbours(
+	state: &ServerState,
+	isbn: ISBN,
+) -> Option<(&SeriesVolume, Option<&SeriesVolume>, Option<&SeriesVolume>)> {
+	let srid = state.ISBN_to_series.get(&isbn)?;
+	let volumes = series_volumes(state, *srid);
+	let at = volumes.iter().position(|volume| volume.ISBN == isbn)?;
+	let prev = at.checked_sub(1).and_then(|at| volumes.get(at));
+	Some((&volumes[at], prev, volumes.get(at+1)))
+}
+
+pub async fn display_all_series(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+) -> Result<Markup, Redirect> {
+	let state = read_state(stt).await;
+	let acc = read_viewer(&state, &cookies);
+
+	Ok( view_all_series(&state, roles::can(&state, acc.as_deref(), Permission::Catalog)) )
+}
+
+pub async fn display_series(
+	State(stt): State<SharedState>,
+	Query(srid): Query<SeriesParam>,
+) -> Result<Markup, Redirect> {
+	let state = read_state(stt).await;
+	Ok( match state.srid_to_series.get(&srid.srid) {
+		Some(series)=>view_series(&state, series),
+		None=>view_404(format!("/series/show?srid={}", srid.srid)),
+	} )
+}
+
+pub async fn display_manage_series(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+) -> Result<Markup, Redirect> {
+	let state = read_state(stt).await;
+
+	Ok( view_manage_series(&state, "") )
+}
+
+pub async fn perform_new_series(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<NewSeriesForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	let name = form.name.trim();
+	if name.is_empty() {
+		return Err(view_manage_series(&state, "Series needs a name"));
+	}
+
+	let result = sqlx::query!(
+		"INSERT INTO series (name) VALUES (?)", name,
+	).execute(&state.db).await;
+	let result = result.map_err(|e| view_manage_series(&state, &e.to_string()))?;
+
+	let series = Series{
+		id: result.last_insert_rowid() as Srid,
+		name: name.to_owned(),
+	};
+	Series::update_maps(&mut state, series);
+	Ok( make_redirect("/series/manage".to_owned()) )
+}
+
+pub async fn perform_add_volume(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<SeriesVolumeForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	if !state.srid_to_series.contains_key(&form.srid) {
+		return Err(view_manage_series(&state, "No such series"));
+	}
+	if title_copy(&state, form.ISBN).is_none() {
+		return Err(view_manage_series(&state, "No such ISBN"));
+	}
+	if state.ISBN_to_series.contains_key(&form.ISBN) {
+		return Err(view_manage_series(&state, "Title is already part of a series"));
+	}
+	let volumes = series_volumes(&state, form.srid);
+	// without a number the title goes after the current last volume
+	let volume = form.volume.unwrap_or_else(|| {
+		volumes.last().map_or(1, |last| last.volume+1)
+	});
+	if volumes.iter().any(|taken| taken.volume == volume) {
+		return Err(view_manage_series(&state, "Volume number already taken"));
+	}
+
+	sqlx::query!(
+		"INSERT INTO series_volumes (series_id, ISBN, volume) VALUES (?, ?, ?)",
+		form.srid, form.ISBN, volume,
+	).execute(&state.db).await
+		.map_err(|e| view_manage_series(&state, &e.to_string()))?;
+
+	SeriesVolume{
+		series_id: form.srid,
+		ISBN: form.ISBN,
+		volume,
+	}.update_maps(&mut state);
+	Ok( make_redirect(format!("/series/show?srid={}", form.srid)) )
+}
+
+pub async fn perform_remove_volume(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<SeriesVolumeForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	sqlx::query!(
+		"DELETE FROM series_volumes WHERE series_id = ? AND ISBN = ?",
+		form.srid, form.ISBN,
+	).execute(&state.db).await
+		.map_err(|e| view_manage_series(&state, &e.to_string()))?;
+
+	if state.ISBN_to_series.get(&form.ISBN) == Some(&form.srid) {
+		state.ISBN_to_series.remove(&form.ISBN);
+	}
+	if let Some(volumes) = state.srid_to_volumes.get_mut(&form.srid) {
+		volumes.retain(|volume| volume.ISBN != form.ISBN);
+	}
+	Ok( make_redirect(format!("/series/show?srid={}", form.srid)) )
+}
+
+fn view_volume_link(state: &ServerState, volume: &SeriesVolume) -> Markup {
+	html! {
+		@match title_copy(state, volume.ISBN) {
+			Some(book)=>{
+				a href={"/book?bid="(book.bid)} { {"Vol. " (volume.volume) ": "} i { (book.name) } }
+			},
+			None=>{ {"Vol. " (volume.volume) ": " (volume.ISBN)} },
+		}
+	}
+}
+
+// "volume N of series", with previous/next links
+pub fn view_book_series(state: &ServerState, book: &Book) -> Markup {
+	let Some((this, prev, next)) = series_neighbours(state, book.ISBN) else {
+		return html! {};
+	};
+	let Some(series) = state.srid_to_series.get(&this.series_id) else {
+		return html! {};
+	};
+	html! {
+		section id="series" {
+			p {
+				{"Volume " (this.volume) " of "}
+				a href={"/series/show?srid="(series.id)} { (series.name) }
+			}
+			@if let Some(prev) = prev {
+				p { "Previous: " (view_volume_link(state, prev)) }
+			}
+			@if let Some(next) = next {
+				p { "Next: " (view_volume_link(state, next)) }
+			}
+		}
+	}
+}
+
+fn view_all_series(state: &ServerState, can_catalog: bool) -> Markup {
+	let mut series = state.srid_to_series.values().collect::<Vec<_>>();
+	series.sort_by(|a, b| a.name.cmp(&b.name));
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		title { "LSYS - Series" }
+	} body {
+		h1 { "Series" }
+		a href="/" { "All books" }
+		@if can_catalog {
+			" | " a href="/series/manage" { "Manage series" }
+		}
+		ul {
+			@for series in series {
+				li {
+					a href={"/series/show?srid="(series.id)} { (series.name) }
+					" (" (series_volumes(state, series.id).len()) " volumes)"
+				}
+			}
+		}
+	} }
+}
+
+fn view_series(state: &ServerState, series: &Series) -> Markup {
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		title { {"LSYS - " (series.name)} }
+	} body {
+		h1 { (series.name) }
+		a href="/series" { "All series" }
+		table {
+			thead { tr {
+				td { "Volume" }
+				td { "Name" }
+				td { "ISBN" }
+				td { "Avaliable copies" }
+			} }
+			tbody {
+				@for volume in series_volumes(state, series.id) { tr {
+					@let (avaliable, total) = availability(state, volume.ISBN);
+					th { (volume.volume) }
+					td { (view_volume_link(state, volume)) }
+					td { (volume.ISBN) }
+					td { (avaliable) "/" (total) }
+				} }
+			}
+		}
+	} }
+}
+
+fn view_manage_series(state: &ServerState, error: &str) -> Markup {
+	let mut series = state.srid_to_series.values().collect::<Vec<_>>();
+	series.sort_by(|a, b| a.name.cmp(&b.name));
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		title { "LSYS - Manage series" }
+	} body {
+		p style="color: red;" { (error) }
+		a href="/series" { "Browse series" }
+
+		fieldset {
+			legend { "New series" }
+			form method="POST" action="/series/new" {
+				(csrf::field())
+				label for="series-name" { "name:" }
+				input id="series-name" name="name" type="text" placeholder="name" {}
+				br {}
+				button { "Create" }
+			}
+		}
+
+		fieldset {
+			legend { "Add volume" }
+			form method="POST" action="/series/add" {
+				(csrf::field())
+				label for="volume-series" { "series:" }
+				select id="volume-series" name="srid" {
+					@for series in &series {
+						option value=(series.id) { (series.name) }
+					}
+				}
+				br {}
+				label for="volume-isbn" { "ISBN:" }
+				input id="volume-isbn" name="ISBN" type="number" placeholder="ISBN" {}
+				br {}
+				label for="volume-number" { "volume:" }
+				input id="volume-number" name="volume" type="number" placeholder="next" {}
+				br {}
+				button { "Add" }
+			}
+		}
+
+		@for series in &series {
+			h3 { (series.name) }
+			ul {
+				@for volume in series_volumes(state, series.id) {
+					li {
+						(view_volume_link(state, volume))
+						form method="POST" action="/series/remove" style="display: inline;" {
+							(csrf::field())
+							input type="hidden" name="srid" value=(series.id) {}
+							input type="hidden" name="ISBN" value=(volume.ISBN) {}
+							button { "remove" }
+						}
+					}
+				}
+			}
+		}
+	} }
+}
@@ -0,0 +1,315 @@
+// logins, kept in the sessions table so they outlive restarts; the cookie only holds a random token,
+// and the table only its hash
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{
+	Form,
+	extract::State,
+	http::{header, HeaderMap},
+	response::Redirect,
+};
+use maud::{html, Markup, DOCTYPE};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use tower_cookies::{Cookie, Cookies, cookie::SameSite};
+use crate::types::*;
+use crate::{
+	SharedState, ServerState,
+	read_state, make_redirect, login_url, view_error, csrf, tls,
+};
+
+pub const COOKIE_SESSION_NAME: &str = "lsys-session";
+const DEFAULT_IDLE_SECS: i64 = 7 * 24 * 60 * 60;
+const DEFAULT_MAX_SECS: i64 = 30 * 24 * 60 * 60;
+// last_seen is only written back when it moved at least this much
+const TOUCH_SECS: i64 = 60;
+const CLEANUP_SECS: u64 = 60 * 60;
+const TOKEN_BYTES: usize = 32;
+const MAX_USER_AGENT: usize = 256;
+
+// SESSION_IDLE_SECS and SESSION_MAX_SECS override the defaults
+pub fn limits_from_env() -> SessionLimits {
+	let secs = |name: &str, default: i64| std::env::var(name)
+		.ok()
+		.and_then(|secs| secs.parse::<i64>().ok())
+		.filter(|secs| *secs > 0)
+		.unwrap_or(default);
+	SessionLimits{
+		idle: secs("SESSION_IDLE_SECS", DEFAULT_IDLE_SECS),
+		max: secs("SESSION_MAX_SECS", DEFAULT_MAX_SECS),
+	}
+}
+
+fn now() -> i64 {
+	chrono::Utc::now().timestamp()
+}
+
+pub async fn load(state: &mut ServerState) {
+	delete_expired(&state.db, state.session_limits).await.expect("can't delete expired sessions");
+	let sessions = sqlx::query!(
+		"SELECT id, token_hash, user_id, created, last_seen, user_agent FROM sessions",
+	).fetch_all(&state.db).await.expect("can't parse row from sessions");
+
+	for session in sessions {
+		Session{
+			id: session.id,
+			token_hash: session.token_hash,
+			user_id: session.user_id,
+			created: session.created,
+			last_seen: AtomicI64::new(session.last_seen),
+			user_agent: session.user_agent,
+		}.update_maps(state);
+	}
+}
+
+async fn delete_expired(db: &sqlx::Pool<sqlx::Sqlite>, limits: SessionLimits) -> Result<(), sqlx::Error> {
+	let now = now();
+	let idle_since = now - limits.idle;
+	let created_since = now - limits.max;
+	sqlx::query!(
+		"DELETE FROM sessions WHERE last_seen < ? OR created < ?",
+		idle_since, created_since,
+	).execute(db).await?;
+	Ok(())
+}
+
+// drops the ended sessions every CLEANUP_SECS, requests already refuse them before that
+pub fn spawn_cleanup(stt: SharedState) {
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(std::time::Duration::from_secs(CLEANUP_SECS));
+		loop {
+			interval.tick().await;
+			let mut state = stt.lock().await;
+			let limits = state.session_limits;
+			if let Err(e) = delete_expired(&state.db, limits).await {
+				eprintln!("can't delete expired sessions: {e}");
+				continue;
+			}
+			let now = now();
+			state.hash_to_session.retain(|_, session| !session.is_expired(limits, now));
+		}
+	});
+}
+
+impl Session {
+	fn update_maps(self, state: &mut ServerState) {
+		state.hash_to_session.insert(self.token_hash.clone(), Arc::new(self));
+	}
+
+	fn is_expired(&self, limits: SessionLimits, now: i64) -> bool {
+		now - self.last_seen.load(Ordering::Relaxed) > limits.idle
+			|| now - self.created > limits.max
+	}
+}
+
+pub fn new_token() -> String {
+	let mut bytes = [0u8; TOKEN_BYTES];
+	OsRng.fill_bytes(&mut bytes);
+	bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// tokens that must not be usable straight from a copy of the db are kept as this
+pub fn hash_token(token: &str) -> String {
+	Sha256::digest(token.as_bytes()).iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn user_agent(headers: &HeaderMap) -> String {
+	let agent = headers.get(header::USER_AGENT)
+		.and_then(|agent| agent.to_str().ok())
+		.unwrap_or_default();
+	agent.chars().take(MAX_USER_AGENT).collect()
+}
+
+// logs the account in, the returned cookie carries the new session
+pub async fn start(state: &mut ServerState, uid: Uid, user_agent: String) -> Result<Cookie<'static>, String> {
+	let token = new_token();
+	let token_hash = hash_token(&token);
+	let created = now();
+	let result = sqlx::query!(
+		"INSERT INTO sessions (token_hash, user_id, created, last_seen, user_agent) VALUES (?, ?, ?, ?, ?)",
+		token_hash, uid, created, created, user_agent,
+	).execute(&state.db).await.map_err(|e| e.to_string())?;
+
+	Session{
+		id: result.last_insert_rowid(),
+		token_hash,
+		user_id: uid,
+		created,
+		last_seen: AtomicI64::new(created),
+		user_agent,
+	}.update_maps(state);
+	Ok( session_cookie(token) )
+}
+
+fn session_cookie(token: String) -> Cookie<'static> {
+	Cookie::build((COOKIE_SESSION_NAME, token))
+		.path("/")
+		.secure(tls::enabled())
+		.same_site(SameSite::Lax)
+		.http_only(true)
+		.into()
+}
+
+// the session the request's cookie belongs to, if it is still going;
+// seeing it counts as activity for the idle limit
+pub fn read_session(state: &ServerState, cookies: &Cookies) -> Option<Arc<Session>> {
+	let cookie = cookies.get(COOKIE_SESSION_NAME)?;
+	let session = state.hash_to_session.get(&hash_token(cookie.value()))?;
+	let now = now();
+	if session.is_expired(state.session_limits, now) {
+		return None;
+	}
+
+	let last_seen = session.last_seen.load(Ordering::Relaxed);
+	if now - last_seen >= TOUCH_SECS
+		&& session.last_seen.compare_exchange(last_seen, now, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+	{
+		let (db, id) = (state.db.clone(), session.id);
+		tokio::spawn(async move {
+			let result = sqlx::query!(
+				"UPDATE sessions SET last_seen = ? WHERE id = ?",
+				now, id,
+			).execute(&db).await;
+			if let Err(e) = result {
+				eprintln!("can't update session {id}: {e}");
+			}
+		});
+	}
+	Some(session.clone())
+}
+
+// ends every session of the account but keep, the one with that id when given
+pub async fn end_sessions(state: &mut ServerState, uid: Uid, keep: Option<i64>) -> Result<(), String> {
+	sqlx::query!(
+		"DELETE FROM sessions WHERE user_id = ? AND id IS NOT ?",
+		uid, keep,
+	).execute(&state.db).await.map_err(|e| e.to_string())?;
+	state.hash_to_session.retain(|_, session| session.user_id != uid || Some(session.id) == keep);
+	Ok(())
+}
+
+// only ends sessions of the account, the id comes from a form
+async fn end_session(state: &mut ServerState, uid: Uid, id: i64) -> Result<(), String> {
+	sqlx::query!(
+		"DELETE FROM sessions WHERE user_id = ? AND id = ?",
+		uid, id,
+	).execute(&state.db).await.map_err(|e| e.to_string())?;
+	state.hash_to_session.retain(|_, session| session.user_id != uid || session.id != id);
+	Ok(())
+}
+
+fn removal_cookie() -> Cookie<'static> {
+	Cookie::build((COOKIE_SESSION_NAME, "")).path("/").into()
+}
+
+pub async fn perform_logout(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+	if let Some(session) = read_session(&state, &cookies) {
+		end_session(&mut state, session.user_id, session.id).await.map_err(view_error)?;
+	}
+	cookies.remove(removal_cookie());
+	Ok( make_redirect("/".to_owned()) )
+}
+
+pub async fn display_sessions(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+) -> Result<Markup, Redirect> {
+	let state = read_state(stt).await;
+	let session = read_session(&state, &cookies).ok_or(make_redirect(login_url("/sessions")))?;
+
+	Ok( view_sessions(&state, &session, "") )
+}
+
+pub async fn perform_end_session(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+	Form(form): Form<SessionForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+	let Some(session) = read_session(&state, &cookies) else {
+		return Ok(make_redirect(login_url("/sessions")));
+	};
+
+	end_session(&mut state, session.user_id, form.id).await
+		.map_err(|e| view_sessions(&state, &session, &e))?;
+	if form.id == session.id {
+		cookies.remove(removal_cookie());
+		return Ok( make_redirect("/".to_owned()) );
+	}
+	Ok( make_redirect("/sessions".to_owned()) )
+}
+
+// logging out everywhere includes this session
+pub async fn perform_end_all_sessions(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+	let Some(session) = read_session(&state, &cookies) else {
+		return Ok(make_redirect(login_url("/sessions")));
+	};
+
+	end_sessions(&mut state, session.user_id, None).await
+		.map_err(|e| view_sessions(&state, &session, &e))?;
+	cookies.remove(removal_cookie());
+	Ok( make_redirect("/".to_owned()) )
+}
+
+pub fn view_time(secs: i64) -> String {
+	chrono::DateTime::from_timestamp(secs, 0)
+		.map(|time| time.format("%Y-%m-%d %H:%M UTC").to_string())
+		.unwrap_or_default()
+}
+
+pub fn view_sessions(state: &ServerState, current: &Session, error: &str) -> Markup {
+	let mut sessions = state.hash_to_session.values()
+		.filter(|session| session.user_id == current.user_id)
+		.collect::<Vec<_>>();
+	sessions.sort_by_key(|session| std::cmp::Reverse(session.last_seen.load(Ordering::Relaxed)));
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		title { "LSYS - Sessions" }
+	} body {
+		p style="color: red;" { (error) }
+		nav {
+			a href="/dashboard" { "Dashboard" }
+		}
+
+		h1 { "Where you are logged in" }
+		table {
+			thead { tr {
+				td { "Browser" }
+				td { "Logged in" }
+				td { "Last seen" }
+				td {}
+			} }
+			tbody {
+				@for session in &sessions { tr {
+					td {
+						@if session.user_agent.is_empty() { "(unknown)" } @else { (session.user_agent) }
+						@if session.id == current.id { b { " (this session)" } }
+					}
+					td { (view_time(session.created)) }
+					td { (view_time(session.last_seen.load(Ordering::Relaxed))) }
+					td {
+						form method="POST" action="/sessions/end" {
+							(csrf::field())
+							input type="hidden" name="id" value=(session.id) {}
+							button { "Log out" }
+						}
+					}
+				} }
+			}
+		}
+		form method="POST" action="/sessions/end_all" {
+			(csrf::field())
+			button { "Log out everywhere" }
+		}
+	} }
+}
@@ -0,0 +1,18 @@
+// values kept in the settings table, read and written straight through to the db
+
+pub async fn get(db: &sqlx::Pool<sqlx::Sqlite>, key: &str) -> Result<Option<String>, sqlx::Error> {
+	let setting = sqlx::query!(
+		"SELECT value FROM settings WHERE key = ?",
+		key,
+	).fetch_optional(db).await?;
+	Ok( setting.map(|setting| setting.value) )
+}
+
+pub async fn set(db: &sqlx::Pool<sqlx::Sqlite>, key: &str, value: &str) -> Result<(), sqlx::Error> {
+	sqlx::query!(
+		"INSERT INTO settings (key, value) VALUES (?, ?)
+		ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+		key, value,
+	).execute(db).await?;
+	Ok(())
+}
@@ -0,0 +1,521 @@
+// subject hierarchy and free-form tags, both linked to book_info by ISBN
+
+use axum::{
+	Form,
+	extract::State,
+	extract::Query,
+	response::Redirect,
+};
+use maud::{html, Markup, DOCTYPE};
+use std::{
+	sync::Arc,
+	collections::HashSet,
+};
+use tower_cookies::Cookies;
+use crate::types::*;
+use crate::roles::{self, Authorized, Catalog};
+use crate::{
+	SharedState, ServerState,
+	read_state, read_viewer, make_redirect,
+	view_books_table, view_404, csrf,
+};
+
+pub async fn load(state: &mut ServerState) {
+	let subjects = sqlx::query_as!(
+		Subject,
+		"SELECT id, name, parent_id FROM subjects",
+	).fetch_all(&state.db).await.expect("can't parse row from subjects into Subject");
+
+	let links = sqlx::query!(
+		"SELECT subject_id, ISBN FROM book_subjects",
+	).fetch_all(&state.db).await.expect("can't parse row from book_subjects");
+
+	let tags = sqlx::query!(
+		"SELECT ISBN, tag FROM book_tags",
+	).fetch_all(&state.db).await.expect("can't parse row from book_tags");
+
+	subjects.into_iter().for_each(|subject| Subject::update_maps(state, subject));
+	for link in links {
+		state.ISBN_to_subjects.entry(link.ISBN).or_default().push(link.subject_id);
+	}
+	for tag in tags {
+		state.ISBN_to_tags.entry(tag.ISBN).or_default().push(tag.tag);
+	}
+}
+
+impl Subject {
+	fn update_maps(state: &mut ServerState, subject: Self) {
+		state.sid_to_subject.insert(subject.id, Arc::new(subject));
+	}
+}
+
+// "Fiction / Mystery", root first
+pub fn subject_path(state: &ServerState, sid: Sid) -> String {
+	let mut names = Vec::new();
+	let mut next = Some(sid);
+	// a parent cycle can't be created through the handlers, but don't hang if the db has one
+	while let Some(sid) = next {
+		if names.len() > state.sid_to_subject.len() {
+			break;
+		}
+		match state.sid_to_subject.get(&sid) {
+			Some(subject)=>{
+				names.push(subject.name.as_str());
+				next = subject.parent_id;
+			}
+			None=>break,
+		}
+	}
+	names.reverse();
+	names.join(" / ")
+}
+
+pub fn subject_children(state: &ServerState, parent: Option<Sid>) -> Vec<Arc<Subject>> {
+	let mut children = state.sid_to_subject
+		.values()
+		.filter(|subject| subject.parent_id == parent)
+		.cloned()
+		.collect::<Vec<_>>();
+	children.sort_by(|a, b| a.name.cmp(&b.name));
+	children
+}
+
+// the subject itself and everything under it
+pub fn subject_descendants(state: &ServerState, sid: Sid) -> HashSet<Sid> {
+	let mut found = HashSet::from([sid]);
+	let mut pending = vec![sid];
+	while let Some(parent) = pending.pop() {
+		for child in subject_children(state, Some(parent)) {
+			if found.insert(child.id) {
+				pending.push(child.id);
+			}
+		}
+	}
+	found
+}
+
+pub fn book_in_subjects(state: &ServerState, isbn: ISBN, subjects: &HashSet<Sid>) -> bool {
+	state.ISBN_to_subjects
+		.get(&isbn)
+		.is_some_and(|sids| sids.iter().any(|sid| subjects.contains(sid)))
+}
+
+pub fn book_has_tag(state: &ServerState, isbn: ISBN, tag: &str) -> bool {
+	state.ISBN_to_tags
+		.get(&isbn)
+		.is_some_and(|tags| tags.iter().any(|t| t.eq_ignore_ascii_case(tag)))
+}
+
+fn normalize_tag(tag: &str) -> String {
+	tag.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+// tags are free text, "&" or "#" in one would otherwise end the query
+fn tag_url(tag: &str) -> String {
+	let query = serde_urlencoded::to_string([("tag", tag)]).unwrap_or_default();
+	format!("/?{query}")
+}
+
+fn book_redirect(bid: Option<Bid>) -> Redirect {
+	match bid {
+		Some(bid)=>make_redirect(format!("/book?bid={bid}")),
+		None=>make_redirect("/subjects/manage".to_owned()),
+	}
+}
+
+pub async fn display_subjects(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+) -> Result<Markup, Redirect> {
+	let state = read_state(stt).await;
+	let acc = read_viewer(&state, &cookies);
+
+	Ok( view_subjects(&state, roles::can(&state, acc.as_deref(), Permission::Catalog)) )
+}
+
+pub async fn display_subject(
+	State(stt): State<SharedState>,
+	Query(sid): Query<SubjectParam>,
+) -> Result<Markup, Redirect> {
+	let state = read_state(stt).await;
+	if !state.sid_to_subject.contains_key(&sid.sid) {
+		return Ok(view_404(format!("/subject?sid={}", sid.sid)));
+	}
+
+	let filter = CatalogFilter{ subject: Some(sid.sid), ..Default::default() };
+	let books = crate::filter_books(&state, &filter);
+	Ok( view_subject(&state, sid.sid, &books) )
+}
+
+pub async fn display_manage_subjects(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+) -> Result<Markup, Redirect> {
+	let state = read_state(stt).await;
+
+	Ok( view_manage_subjects(&state, "") )
+}
+
+pub async fn perform_new_subject(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<NewSubjectForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	let name = form.name.trim();
+	if name.is_empty() {
+		return Err(view_manage_subjects(&state, "Subject needs a name"));
+	}
+	if let Some(parent) = form.parent_id {
+		if !state.sid_to_subject.contains_key(&parent) {
+			return Err(view_manage_subjects(&state, "No such parent subject"));
+		}
+	}
+	let taken = subject_children(&state, form.parent_id)
+		.iter()
+		.any(|subject| subject.name.eq_ignore_ascii_case(name));
+	if taken {
+		return Err(view_manage_subjects(&state, "Subject already exists under that parent"));
+	}
+
+	let result = sqlx::query!(
+		"INSERT INTO subjects (name, parent_id) VALUES (?, ?)",
+		name, form.parent_id,
+	).execute(&state.db).await;
+	let result = result.map_err(|e| view_manage_subjects(&state, &e.to_string()))?;
+
+	let subject = Subject{
+		id: result.last_insert_rowid() as Sid,
+		name: name.to_owned(),
+		parent_id: form.parent_id,
+	};
+	Subject::update_maps(&mut state, subject);
+	Ok( make_redirect("/subjects/manage".to_owned()) )
+}
+
+pub async fn perform_delete_subject(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(sid): Form<SubjectParam>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	if !subject_children(&state, Some(sid.sid)).is_empty() {
+		return Err(view_manage_subjects(&state, "Subject still has sub-subjects"));
+	}
+	let in_use = state.ISBN_to_subjects
+		.values()
+		.any(|sids| sids.contains(&sid.sid));
+	if in_use {
+		return Err(view_manage_subjects(&state, "Subject is still assigned to books"));
+	}
+
+	sqlx::query!(
+		"DELETE FROM subjects WHERE id = ?", sid.sid,
+	).execute(&state.db).await
+		.map_err(|e| view_manage_subjects(&state, &e.to_string()))?;
+
+	state.sid_to_subject.remove(&sid.sid);
+	Ok( make_redirect("/subjects/manage".to_owned()) )
+}
+
+pub async fn perform_assign_subject(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<BookSubjectForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	if !state.sid_to_subject.contains_key(&form.sid) {
+		return Err(view_manage_subjects(&state, "No such subject"));
+	}
+	if !state.bid_to_book.values().any(|book| book.ISBN == form.ISBN) {
+		return Err(view_manage_subjects(&state, "No such ISBN"));
+	}
+	let assigned = state.ISBN_to_subjects
+		.get(&form.ISBN)
+		.is_some_and(|sids| sids.contains(&form.sid));
+	if !assigned {
+		sqlx::query!(
+			"INSERT INTO book_subjects (subject_id, ISBN) VALUES (?, ?)",
+			form.sid, form.ISBN,
+		).execute(&state.db).await
+			.map_err(|e| view_manage_subjects(&state, &e.to_string()))?;
+		state.ISBN_to_subjects.entry(form.ISBN).or_default().push(form.sid);
+	}
+	Ok( book_redirect(form.bid) )
+}
+
+pub async fn perform_unassign_subject(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<BookSubjectForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	sqlx::query!(
+		"DELETE FROM book_subjects WHERE subject_id = ? AND ISBN = ?",
+		form.sid, form.ISBN,
+	).execute(&state.db).await
+		.map_err(|e| view_manage_subjects(&state, &e.to_string()))?;
+	if let Some(sids) = state.ISBN_to_subjects.get_mut(&form.ISBN) {
+		sids.retain(|sid| *sid != form.sid);
+	}
+	Ok( book_redirect(form.bid) )
+}
+
+pub async fn perform_add_tag(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<BookTagForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	let tag = normalize_tag(&form.tag);
+	if tag.is_empty() {
+		return Err(view_manage_subjects(&state, "Tag can't be empty"));
+	}
+	if !state.bid_to_book.values().any(|book| book.ISBN == form.ISBN) {
+		return Err(view_manage_subjects(&state, "No such ISBN"));
+	}
+	if !book_has_tag(&state, form.ISBN, &tag) {
+		sqlx::query!(
+			"INSERT INTO book_tags (ISBN, tag) VALUES (?, ?)",
+			form.ISBN, tag,
+		).execute(&state.db).await
+			.map_err(|e| view_manage_subjects(&state, &e.to_string()))?;
+		state.ISBN_to_tags.entry(form.ISBN).or_default().push(tag);
+	}
+	Ok( book_redirect(form.bid) )
+}
+
+pub async fn perform_remove_tag(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<BookTagForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	let tag = normalize_tag(&form.tag);
+	sqlx::query!(
+		"DELETE FROM book_tags WHERE ISBN = ? AND tag = ?",
+		form.ISBN, tag,
+	).execute(&state.db).await
+		.map_err(|e| view_manage_subjects(&state, &e.to_string()))?;
+	if let Some(tags) = state.ISBN_to_tags.get_mut(&form.ISBN) {
+		tags.retain(|t| *t != tag);
+	}
+	Ok( book_redirect(form.bid) )
+}
+
+fn count_titles(state: &ServerState, sid: Sid) -> usize {
+	let subjects = subject_descendants(state, sid);
+	state.ISBN_to_subjects
+		.iter()
+		.filter(|(_, sids)| sids.iter().any(|sid| subjects.contains(sid)))
+		.count()
+}
+
+fn view_subject_tree(state: &ServerState, parent: Option<Sid>) -> Markup {
+	let children = subject_children(state, parent);
+	if children.is_empty() {
+		return html! {};
+	}
+	html! {
+		ul {
+			@for subject in children {
+				li {
+					a href={"/subject?sid="(subject.id)} { (subject.name) }
+					" (" (count_titles(state, subject.id)) ")"
+					(view_subject_tree(state, Some(subject.id)))
+				}
+			}
+		}
+	}
+}
+
+// <option>s for every subject, labeled with its full path
+pub fn view_subject_options(state: &ServerState, selected: Option<Sid>) -> Markup {
+	let mut subjects = state.sid_to_subject
+		.keys()
+		.map(|sid| (subject_path(state, *sid), *sid))
+		.collect::<Vec<_>>();
+	subjects.sort();
+	html! {
+		@for (path, sid) in subjects {
+			option value=(sid) selected[selected == Some(sid)] { (path) }
+		}
+	}
+}
+
+// subjects and tags of a title, with editing controls for workers
+pub fn view_book_taxonomy(state: &ServerState, book: &Book, can_catalog: bool) -> Markup {
+	let sids = state.ISBN_to_subjects.get(&book.ISBN).cloned().unwrap_or_default();
+	let tags = state.ISBN_to_tags.get(&book.ISBN).cloned().unwrap_or_default();
+	html! {
+		section id="taxonomy" {
+			h3 { "Subjects" }
+			ul {
+				@for sid in &sids {
+					li {
+						a href={"/subject?sid="(sid)} { (subject_path(state, *sid)) }
+						@if can_catalog {
+							form method="POST" action="/subjects/unassign" style="display: inline;" {
+								(csrf::field())
+								input type="hidden" name="ISBN" value=(book.ISBN) {}
+								input type="hidden" name="sid" value=(sid) {}
+								input type="hidden" name="bid" value=(book.bid) {}
+								button { "remove" }
+							}
+						}
+					}
+				}
+			}
+			h3 { "Tags" }
+			p {
+				@for tag in &tags {
+					a href=(tag_url(tag)) { (tag) } " "
+					@if can_catalog {
+						form method="POST" action="/tags/remove" style="display: inline;" {
+							(csrf::field())
+							input type="hidden" name="ISBN" value=(book.ISBN) {}
+							input type="hidden" name="tag" value=(tag) {}
+							input type="hidden" name="bid" value=(book.bid) {}
+							button { "x" }
+						}
+						" "
+					}
+				}
+			}
+			@if can_catalog {
+				form method="POST" action="/subjects/assign" {
+					(csrf::field())
+					input type="hidden" name="ISBN" value=(book.ISBN) {}
+					input type="hidden" name="bid" value=(book.bid) {}
+					select name="sid" { (view_subject_options(state, None)) }
+					button { "Add subject" }
+				}
+				form method="POST" action="/tags/add" {
+					(csrf::field())
+					input type="hidden" name="ISBN" value=(book.ISBN) {}
+					input type="hidden" name="bid" value=(book.bid) {}
+					input name="tag" type="text" placeholder="tag" {}
+					button { "Add tag" }
+				}
+			}
+		}
+	}
+}
+
+fn view_subjects(state: &ServerState, can_catalog: bool) -> Markup {
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		title { "LSYS - Subjects" }
+	} body {
+		h1 { "Subjects" }
+		a href="/" { "All books" }
+		@if can_catalog {
+			" | " a href="/subjects/manage" { "Manage subjects" }
+		}
+		(view_subject_tree(state, None))
+	} }
+}
+
+fn view_subject(state: &ServerState, sid: Sid, books: &[Book]) -> Markup {
+	let parent = state.sid_to_subject.get(&sid).and_then(|subject| subject.parent_id);
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		title { {"LSYS - " (subject_path(state, sid))} }
+	} body {
+		h1 { (subject_path(state, sid)) }
+		a href="/subjects" { "All subjects" }
+		@if let Some(parent) = parent {
+			" | " a href={"/subject?sid="(parent)} { "Up" }
+		}
+		(view_subject_tree(state, Some(sid)))
+		(view_books_table(state, books))
+	} }
+}
+
+fn view_manage_subjects(state: &ServerState, error: &str) -> Markup {
+	let mut subjects = state.sid_to_subject
+		.keys()
+		.map(|sid| (subject_path(state, *sid), *sid))
+		.collect::<Vec<_>>();
+	subjects.sort();
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		title { "LSYS - Manage subjects" }
+	} body {
+		p style="color: red;" { (error) }
+		a href="/subjects" { "Browse subjects" }
+
+		fieldset {
+			legend { "New subject" }
+			form method="POST" action="/subjects/new" {
+				(csrf::field())
+				label for="subject-name" { "name:" }
+				input id="subject-name" name="name" type="text" placeholder="name" {}
+				br {}
+				label for="subject-parent" { "parent:" }
+				select id="subject-parent" name="parent_id" {
+					option value="" { "(top level)" }
+					(view_subject_options(state, None))
+				}
+				br {}
+				button { "Create" }
+			}
+		}
+
+		fieldset {
+			legend { "Assign subject" }
+			form method="POST" action="/subjects/assign" {
+				(csrf::field())
+				label for="assign-isbn" { "ISBN:" }
+				input id="assign-isbn" name="ISBN" type="number" placeholder="ISBN" {}
+				br {}
+				label for="assign-subject" { "subject:" }
+				select id="assign-subject" name="sid" { (view_subject_options(state, None)) }
+				br {}
+				button { "Assign" }
+			}
+		}
+
+		fieldset {
+			legend { "Tag a title" }
+			form method="POST" action="/tags/add" {
+				(csrf::field())
+				label for="tag-isbn" { "ISBN:" }
+				input id="tag-isbn" name="ISBN" type="number" placeholder="ISBN" {}
+				br {}
+				label for="tag-name" { "tag:" }
+				input id="tag-name" name="tag" type="text" placeholder="tag" {}
+				br {}
+				button { "Tag" }
+			}
+		}
+
+		table {
+			thead { tr {
+				td { "Subject" }
+				td { "Titles" }
+				td {}
+			} }
+			tbody {
+				@for (path, sid) in subjects { tr {
+					td { a href={"/subject?sid="(sid)} { (path) } }
+					td { (count_titles(state, sid)) }
+					td {
+						form method="POST" action="/subjects/delete" {
+							(csrf::field())
+							input type="hidden" name="sid" value=(sid) {}
+							button { "Delete" }
+						}
+					}
+				} }
+			}
+		}
+	} }
+}
@@ -0,0 +1,252 @@
+// slowing down password guessing: past a few wrong passwords from one address or against one email,
+// each next try has to wait twice as long, and too many lock that address or email out for a while
+
+use axum::{
+	Form,
+	extract::State,
+	response::Redirect,
+};
+use maud::{html, Markup, DOCTYPE};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use crate::types::*;
+use crate::roles::{Authorized, ManageAccounts};
+use crate::sessions::view_time;
+use crate::{SharedState, ServerState, make_redirect, csrf};
+
+struct Limits {
+	// failures allowed before having to wait
+	free: u32,
+	// failures that lock out
+	lockout: u32,
+}
+
+// an address can be shared by many people, so it gets more leeway than an email
+const EMAIL_LIMITS: Limits = Limits{ free: 3, lockout: 10 };
+const IP_LIMITS: Limits = Limits{ free: 10, lockout: 50 };
+const BASE_DELAY_SECS: i64 = 1;
+const MAX_DELAY_SECS: i64 = 5 * 60;
+const LOCKOUT_SECS: i64 = 15 * 60;
+// failures are forgotten after this long without another
+const FORGET_SECS: i64 = 60 * 60;
+const SHOWN_DAYS: i64 = 30;
+
+fn now() -> i64 {
+	chrono::Utc::now().timestamp()
+}
+
+// emails are throttled whether or not they have an account, so being throttled doesn't tell either
+fn email_key(email: &str) -> String {
+	email.trim().to_lowercase()
+}
+
+// lockouts still going when the server stopped carry on
+pub async fn load(state: &mut ServerState) {
+	let now = now();
+	let lockouts = sqlx::query!(
+		"SELECT kind, subject, failures, until FROM lockouts WHERE until > ? AND lifted IS NULL",
+		now,
+	).fetch_all(&state.db).await.expect("can't parse row from lockouts");
+
+	for lockout in lockouts {
+		let failures = LoginFailures{
+			count: lockout.failures as u32,
+			last: now,
+			locked_until: lockout.until,
+		};
+		match lockout.kind.as_str() {
+			"ip"=>if let Ok(ip) = lockout.subject.parse::<IpAddr>() {
+				state.ip_to_failures.insert(ip, failures);
+			},
+			_=>{
+				state.email_to_failures.insert(lockout.subject, failures);
+			}
+		}
+	}
+}
+
+// seconds until another try is allowed
+fn wait(failures: &LoginFailures, limits: &Limits, now: i64) -> i64 {
+	if failures.locked_until > now {
+		return failures.locked_until - now;
+	}
+	if failures.count < limits.free {
+		return 0;
+	}
+	let doublings = (failures.count - limits.free).min(16);
+	let delay = (BASE_DELAY_SECS << doublings).min(MAX_DELAY_SECS);
+	(failures.last + delay - now).max(0)
+}
+
+// Err holds the seconds to wait before trying again
+pub fn check(state: &ServerState, ip: IpAddr, email: &str) -> Result<(), i64> {
+	let now = now();
+	let by_ip = state.ip_to_failures.get(&ip)
+		.map_or(0, |failures| wait(failures, &IP_LIMITS, now));
+	let by_email = state.email_to_failures.get(&email_key(email))
+		.map_or(0, |failures| wait(failures, &EMAIL_LIMITS, now));
+	match by_ip.max(by_email) {
+		0=>Ok(()),
+		secs=>Err(secs),
+	}
+}
+
+pub fn view_wait(secs: i64) -> String {
+	match secs {
+		..=60=>"Too many failed logins, wait a moment before trying again".to_owned(),
+		_=>format!("Too many failed logins, try again in {} minutes", (secs + 59) / 60),
+	}
+}
+
+// counts one failure, Some(failures) when that reached the lockout
+fn count_failure<K: Hash + Eq>(
+	map: &mut HashMap<K, LoginFailures>,
+	key: K,
+	limits: &Limits,
+	now: i64,
+) -> Option<u32> {
+	map.retain(|_, failures| failures.locked_until > now || now - failures.last < FORGET_SECS);
+	let failures = map.entry(key).or_default();
+	// a lockout that ran out starts the count over
+	if failures.locked_until != 0 && failures.locked_until <= now {
+		*failures = LoginFailures::default();
+	}
+	failures.count += 1;
+	failures.last = now;
+	if failures.count < limits.lockout {
+		return None;
+	}
+	failures.locked_until = now + LOCKOUT_SECS;
+	Some(failures.count)
+}
+
+pub async fn record_failure(state: &mut ServerState, ip: IpAddr, email: &str) {
+	let now = now();
+	let email = email_key(email);
+	let mut lockouts = Vec::new();
+	if let Some(count) = count_failure(&mut state.ip_to_failures, ip, &IP_LIMITS, now) {
+		lockouts.push(("ip", ip.to_string(), count));
+	}
+	if let Some(count) = count_failure(&mut state.email_to_failures, email.clone(), &EMAIL_LIMITS, now) {
+		lockouts.push(("email", email, count));
+	}
+
+	for (kind, subject, count) in lockouts {
+		let until = now + LOCKOUT_SECS;
+		eprintln!("login lockout: {kind} {subject} after {count} failures, until {}", view_time(until));
+		let result = sqlx::query!(
+			"INSERT INTO lockouts (kind, subject, failures, created, until) VALUES (?, ?, ?, ?, ?)",
+			kind, subject, count, now, until,
+		).execute(&state.db).await;
+		if let Err(e) = result {
+			eprintln!("can't store lockout of {kind} {subject}: {e}");
+		}
+	}
+}
+
+// the address keeps its count, one right password shouldn't clear the way for guessing others
+pub fn record_success(state: &mut ServerState, email: &str) {
+	state.email_to_failures.remove(&email_key(email));
+}
+
+pub async fn display_lockouts(
+	State(stt): State<SharedState>,
+	_: Authorized<ManageAccounts>,
+) -> Markup {
+	let db = stt.lock().await.db.clone();
+	view_lockouts(&db, "").await
+}
+
+pub async fn perform_lift_lockout(
+	State(stt): State<SharedState>,
+	_: Authorized<ManageAccounts>,
+	Form(form): Form<LockoutForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+	let now = now();
+	let lockout = sqlx::query!(
+		"UPDATE lockouts SET lifted = ? WHERE id = ? AND lifted IS NULL RETURNING kind, subject",
+		now, form.id,
+	).fetch_optional(&state.db).await;
+	let lockout = match lockout {
+		Ok(Some(lockout))=>lockout,
+		Ok(None)=>return Err(view_lockouts(&state.db, "No such lockout").await),
+		Err(e)=>return Err(view_lockouts(&state.db, &e.to_string()).await),
+	};
+
+	match lockout.kind.as_str() {
+		"ip"=>if let Ok(ip) = lockout.subject.parse::<IpAddr>() {
+			state.ip_to_failures.remove(&ip);
+		},
+		_=>{
+			state.email_to_failures.remove(&lockout.subject);
+		}
+	}
+	eprintln!("login lockout lifted: {} {}", lockout.kind, lockout.subject);
+	Ok( make_redirect("/admin/lockouts".to_owned()) )
+}
+
+async fn view_lockouts(db: &sqlx::Pool<sqlx::Sqlite>, error: &str) -> Markup {
+	let now = now();
+	let since = now - SHOWN_DAYS * 24 * 60 * 60;
+	let lockouts = sqlx::query!(
+		"SELECT id, kind, subject, failures, created, until, lifted FROM lockouts
+		WHERE created > ? ORDER BY created DESC",
+		since,
+	).fetch_all(db).await;
+	let (lockouts, error) = match lockouts {
+		Ok(lockouts)=>(lockouts, error.to_owned()),
+		Err(e)=>(Vec::new(), e.to_string()),
+	};
+
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		title { "LSYS - Lockouts" }
+	} body {
+		p style="color: red;" { (error) }
+		nav {
+			a href="/dashboard" { "Dashboard" }
+		}
+
+		h1 { "Login lockouts" }
+		p { "Logins locked out after too many wrong passwords, in the last " (SHOWN_DAYS) " days." }
+		@if lockouts.is_empty() {
+			p { "None." }
+		}
+		table {
+			thead { tr {
+				td { "Locked out" }
+				td { "Failures" }
+				td { "From" }
+				td { "Until" }
+				td {}
+			} }
+			tbody {
+				@for lockout in &lockouts { tr {
+					td {
+						@if lockout.kind == "ip" { "address " } @else { "email " }
+						(lockout.subject)
+					}
+					td { (lockout.failures) }
+					td { (view_time(lockout.created)) }
+					td { (view_time(lockout.until)) }
+					td {
+						@if let Some(lifted) = lockout.lifted {
+							"lifted " (view_time(lifted))
+						} @else if lockout.until > now {
+							form method="POST" action="/admin/lockouts/lift" {
+								(csrf::field())
+								input type="hidden" name="id" value=(lockout.id) {}
+								button { "Lift" }
+							}
+						} @else {
+							"over"
+						}
+					}
+				} }
+			}
+		}
+	} }
+}
@@ -0,0 +1,94 @@
+// serving over https when TLS_CERT and TLS_KEY point to PEM files, plain http otherwise;
+// with https on, HTTP_ADDR only sends browsers over to HTTPS_ADDR
+
+use axum::{
+	Router,
+	http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+	http::uri::Authority,
+	response::{Redirect, Response},
+};
+use axum_server::tls_rustls::RustlsConfig;
+use std::net::SocketAddr;
+use tokio::signal::unix::{signal, SignalKind};
+
+const DEFAULT_HTTP_ADDR: &str = "0.0.0.0:8080";
+const DEFAULT_HTTPS_ADDR: &str = "0.0.0.0:8443";
+// a year, browsers then refuse plain http for the site by themselves
+const HSTS: &str = "max-age=31536000";
+
+fn cert_paths() -> Option<(String, String)> {
+	let cert = std::env::var("TLS_CERT").ok().filter(|cert| !cert.is_empty())?;
+	let key = std::env::var("TLS_KEY").ok().filter(|key| !key.is_empty())?;
+	Some( (cert, key) )
+}
+
+// cookies are only sent over https when it is on
+pub fn enabled() -> bool {
+	cert_paths().is_some()
+}
+
+fn addr_from_env(name: &str, default: &str) -> SocketAddr {
+	std::env::var(name)
+		.unwrap_or(default.to_owned())
+		.parse()
+		.unwrap_or_else(|e| panic!("{name} isn't an address like {default}: {e}"))
+}
+
+pub async fn serve(app: Router) {
+	let http_addr = addr_from_env("HTTP_ADDR", DEFAULT_HTTP_ADDR);
+	let Some((cert, key)) = cert_paths() else {
+		let listener = tokio::net::TcpListener::bind(http_addr).await.unwrap();
+		axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+		return;
+	};
+
+	let config = RustlsConfig::from_pem_file(&cert, &key).await
+		.expect("can't load TLS_CERT and TLS_KEY");
+	spawn_reload(config.clone(), cert, key);
+
+	let https_addr = addr_from_env("HTTPS_ADDR", DEFAULT_HTTPS_ADDR);
+	let redirects = Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move {
+		redirect_to_https(&headers, &uri, https_addr.port())
+	});
+	let listener = tokio::net::TcpListener::bind(http_addr).await.unwrap();
+	tokio::spawn(async move {
+		axum::serve(listener, redirects).await.unwrap();
+	});
+
+	let app = app.layer(axum::middleware::map_response(add_hsts));
+	axum_server::bind_rustls(https_addr, config)
+		.serve(app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+}
+
+async fn add_hsts(mut response: Response) -> Response {
+	response.headers_mut().insert(header::STRICT_TRANSPORT_SECURITY, HeaderValue::from_static(HSTS));
+	response
+}
+
+// the same host and path, on the https port
+fn redirect_to_https(headers: &HeaderMap, uri: &Uri, port: u16) -> Result<Redirect, StatusCode> {
+	let host = headers.get(header::HOST)
+		.and_then(|host| host.to_str().ok())
+		.and_then(|host| host.parse::<Authority>().ok())
+		.ok_or(StatusCode::BAD_REQUEST)?;
+	let port = match port {
+		443=>String::new(),
+		port=>format!(":{port}"),
+	};
+	let path = uri.path_and_query().map_or("/", |path| path.as_str());
+	Ok( Redirect::permanent(&format!("https://{}{port}{path}", host.host())) )
+}
+
+// SIGHUP rereads the certificate, so it can be renewed without a restart;
+// a broken one is only logged and the old one stays in use
+fn spawn_reload(config: RustlsConfig, cert: String, key: String) {
+	tokio::spawn(async move {
+		let mut hangups = signal(SignalKind::hangup()).expect("can't listen for SIGHUP");
+		while hangups.recv().await.is_some() {
+			match config.reload_from_pem_file(&cert, &key).await {
+				Ok(())=>eprintln!("reloaded TLS certificate {cert}"),
+				Err(e)=>eprintln!("can't reload TLS certificate {cert}: {e}"),
+			}
+		}
+	});
+}
@@ -0,0 +1,554 @@
+// a second login step with codes from an authenticator app (RFC 6238 TOTP), or a single use recovery code;
+// administrators can require it of every account whose role does more than reserve
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{
+	Form,
+	extract::{ConnectInfo, Query, State},
+	response::Redirect,
+};
+use hmac::{Hmac, Mac};
+use maud::{html, Markup, PreEscaped, DOCTYPE};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use sha1::Sha1;
+use std::net::SocketAddr;
+use tower_cookies::{Cookie, Cookies, cookie::SameSite};
+use crate::types::*;
+use crate::roles::{Authorized, ManageAccounts};
+use crate::sessions::{new_token, hash_token};
+use crate::verification::decode_hex;
+use crate::{
+	SharedState, ServerState, Goto,
+	read_state, read_viewer, make_redirect, login_url, with_goto, view_login,
+	csrf, sessions, settings, throttle, tls,
+};
+
+const COOKIE_LOGIN_NAME: &str = "lsys-login";
+const POLICY_SETTING: &str = "staff_two_factor";
+const ISSUER: &str = "LSYS";
+const SECRET_BYTES: usize = 20;
+const STEP_SECS: i64 = 30;
+const DIGITS: usize = 6;
+const RECOVERY_CODES: usize = 10;
+// the second step has to follow the password soon, and gets a few tries
+const PENDING_SECS: i64 = 5 * 60;
+const MAX_ATTEMPTS: u32 = 5;
+
+fn now() -> i64 {
+	chrono::Utc::now().timestamp()
+}
+
+pub async fn load(state: &mut ServerState) {
+	let enabled = sqlx::query!(
+		"SELECT user_id FROM totp WHERE enabled IS NOT NULL",
+	).fetch_all(&state.db).await.expect("can't parse row from totp");
+	state.two_factor_uids = enabled.into_iter().map(|row| row.user_id).collect();
+
+	let policy = settings::get(&state.db, POLICY_SETTING).await
+		.expect("can't read the two-factor policy");
+	state.staff_two_factor = policy.as_deref() == Some("on");
+}
+
+// staff is anyone whose role can do more than reserve
+fn is_staff(state: &ServerState, acc: &Account) -> bool {
+	state.rid_to_role
+		.get(&acc.role)
+		.is_some_and(|role| role.permissions.iter().any(|permission| *permission != Permission::Reserve))
+}
+
+fn is_required(state: &ServerState, acc: &Account) -> bool {
+	state.staff_two_factor && is_staff(state, acc)
+}
+
+// when this is true the account can't use its staff permissions until it sets two-factor up
+pub fn missing(state: &ServerState, acc: &Account) -> bool {
+	is_required(state, acc) && !state.two_factor_uids.contains(&acc.uid)
+}
+
+pub fn is_enabled(state: &ServerState, uid: Uid) -> bool {
+	state.two_factor_uids.contains(&uid)
+}
+
+fn code_at(secret: &[u8], step: i64) -> String {
+	let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("hmac takes keys of any size");
+	mac.update(&step.to_be_bytes());
+	let hash = mac.finalize().into_bytes();
+	// dynamic truncation, RFC 4226 section 5.3
+	let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+	let binary = u32::from_be_bytes([hash[offset], hash[offset+1], hash[offset+2], hash[offset+3]]) & 0x7fff_ffff;
+	format!("{:0width$}", binary % 10u32.pow(DIGITS as u32), width = DIGITS)
+}
+
+// RFC 4648 base32 without padding, how authenticator apps take secrets
+fn base32(bytes: &[u8]) -> String {
+	const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+	let (mut buffer, mut bits) = (0u32, 0u32);
+	let mut encoded = String::new();
+	for byte in bytes {
+		buffer = (buffer << 8) | *byte as u32;
+		bits += 8;
+		while bits >= 5 {
+			bits -= 5;
+			encoded.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+		}
+		buffer &= (1 << bits) - 1;
+	}
+	if bits > 0 {
+		encoded.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+	}
+	encoded
+}
+
+// codes are typed with spaces or dashes now and then
+fn normalize_code(code: &str) -> String {
+	code.chars()
+		.filter(char::is_ascii_alphanumeric)
+		.collect::<String>()
+		.to_lowercase()
+}
+
+// a code from the app, for the enabled secret or the one being set up;
+// its step is claimed in the db first, so the same code can't be used twice
+async fn check_totp(db: &sqlx::Pool<sqlx::Sqlite>, uid: Uid, code: &str, enabled: bool) -> Result<bool, String> {
+	let totp = sqlx::query!(
+		"SELECT secret, enabled, last_step FROM totp WHERE user_id = ?",
+		uid,
+	).fetch_optional(db).await.map_err(|e| e.to_string())?;
+	let Some(totp) = totp.filter(|totp| totp.enabled.is_some() == enabled) else {
+		return Ok(false);
+	};
+	let secret = decode_hex(&totp.secret).ok_or("The stored secret is broken".to_owned())?;
+
+	// one step either way, for clocks that are a little off
+	let current = now() / STEP_SECS;
+	let Some(step) = (current-1..=current+1)
+		.filter(|step| *step > totp.last_step)
+		.find(|step| code_at(&secret, *step) == code)
+	else {
+		return Ok(false);
+	};
+	let claimed = sqlx::query!(
+		"UPDATE totp SET last_step = ? WHERE user_id = ? AND last_step < ?",
+		step, uid, step,
+	).execute(db).await.map_err(|e| e.to_string())?;
+	Ok( claimed.rows_affected() == 1 )
+}
+
+async fn use_recovery_code(db: &sqlx::Pool<sqlx::Sqlite>, uid: Uid, code: &str) -> Result<bool, String> {
+	let code_hash = hash_token(code);
+	let now = now();
+	let used = sqlx::query!(
+		"UPDATE recovery_codes SET used = ? WHERE user_id = ? AND code_hash = ? AND used IS NULL
+		RETURNING id",
+		now, uid, code_hash,
+	).fetch_optional(db).await.map_err(|e| e.to_string())?;
+	Ok( used.is_some() )
+}
+
+// a code from the app, or when recovery is allowed one of the recovery codes
+async fn check_code(db: &sqlx::Pool<sqlx::Sqlite>, uid: Uid, code: &str, recovery: bool) -> Result<bool, String> {
+	let code = normalize_code(code);
+	if code.len() == DIGITS && code.chars().all(|chr| chr.is_ascii_digit()) {
+		return check_totp(db, uid, &code, true).await;
+	}
+	match recovery {
+		true=>use_recovery_code(db, uid, &code).await,
+		false=>Ok(false),
+	}
+}
+
+// replaces any codes made before, returns them to be shown once
+async fn new_recovery_codes(db: &sqlx::Pool<sqlx::Sqlite>, uid: Uid) -> Result<Vec<String>, String> {
+	sqlx::query!(
+		"DELETE FROM recovery_codes WHERE user_id = ?",
+		uid,
+	).execute(db).await.map_err(|e| e.to_string())?;
+
+	let mut codes = Vec::new();
+	for _ in 0..RECOVERY_CODES {
+		let code = new_token()[..10].to_owned();
+		let code_hash = hash_token(&code);
+		sqlx::query!(
+			"INSERT INTO recovery_codes (user_id, code_hash) VALUES (?, ?)",
+			uid, code_hash,
+		).execute(db).await.map_err(|e| e.to_string())?;
+		codes.push(format!("{}-{}", &code[..5], &code[5..]));
+	}
+	Ok(codes)
+}
+
+fn login_cookie(token: String) -> Cookie<'static> {
+	Cookie::build((COOKIE_LOGIN_NAME, token))
+		.path("/")
+		.secure(tls::enabled())
+		.same_site(SameSite::Lax)
+		.http_only(true)
+		.into()
+}
+
+// the password was right, the returned cookie carries the login on to its second step
+pub fn start_login(state: &mut ServerState, uid: Uid, user_agent: String) -> Cookie<'static> {
+	let now = now();
+	state.token_to_pending_login.retain(|_, pending| pending.expires > now);
+	let token = new_token();
+	state.token_to_pending_login.insert(token.clone(), PendingLogin{
+		uid,
+		user_agent,
+		expires: now + PENDING_SECS,
+		attempts: 0,
+	});
+	login_cookie(token)
+}
+
+pub async fn display_second_step(
+	Query(goto): Query<Goto>,
+) -> Markup {
+	view_second_step(csrf::safe_goto(goto.goto.as_deref()), "")
+}
+
+pub async fn perform_second_step(
+	State(stt): State<SharedState>,
+	ConnectInfo(addr): ConnectInfo<SocketAddr>,
+	cookies: Cookies,
+	Query(goto): Query<Goto>,
+	Form(form): Form<CodeForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+	let goto = csrf::safe_goto(goto.goto.as_deref());
+	let now = now();
+
+	let token = cookies.get(COOKIE_LOGIN_NAME).map(|cookie| cookie.value().to_owned()).unwrap_or_default();
+	let pending = state.token_to_pending_login.get(&token)
+		.filter(|pending| pending.expires > now)
+		.cloned();
+	let Some((pending, acc)) = pending.and_then(|pending| {
+		let acc = state.uid_to_account.get(&pending.uid).cloned()?;
+		Some( (pending, acc) )
+	}) else {
+		return Err(view_login("Your login took too long, log in again", "", goto));
+	};
+
+	let ip = addr.ip();
+	if let Err(secs) = throttle::check(&state, ip, &acc.email) {
+		return Err(view_second_step(goto, &throttle::view_wait(secs)));
+	}
+	let right = check_code(&state.db, acc.uid, &form.code, true).await
+		.map_err(|e| view_second_step(goto, &e))?;
+	if !right {
+		throttle::record_failure(&mut state, ip, &acc.email).await;
+		let attempts = pending.attempts + 1;
+		if attempts >= MAX_ATTEMPTS {
+			state.token_to_pending_login.remove(&token);
+			cookies.remove(login_cookie(String::new()));
+			return Err(view_login("Too many wrong codes, log in again", "", goto));
+		}
+		state.token_to_pending_login.insert(token, PendingLogin{ attempts, ..pending });
+		return Err(view_second_step(goto, "Wrong code"));
+	}
+
+	state.token_to_pending_login.remove(&token);
+	cookies.remove(login_cookie(String::new()));
+	let cookie = sessions::start(&mut state, acc.uid, pending.user_agent).await
+		.map_err(|e| view_second_step(goto, &e))?;
+	cookies.add(cookie);
+	Ok( Redirect::to(goto) )
+}
+
+pub async fn display_two_factor(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+) -> Result<Markup, Redirect> {
+	let state = read_state(stt).await;
+	let acc = read_viewer(&state, &cookies).ok_or(make_redirect(login_url("/two_factor")))?;
+	Ok( view_two_factor(&state.db, &acc, requirement(&state, &acc), "").await )
+}
+
+// a new secret to scan, replacing one that was never confirmed
+pub async fn perform_setup(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+) -> Result<Redirect, Markup> {
+	let state = read_state(stt).await;
+	let Some(acc) = read_viewer(&state, &cookies) else {
+		return Ok(make_redirect(login_url("/two_factor")));
+	};
+	if is_enabled(&state, acc.uid) {
+		return Err(view_two_factor(&state.db, &acc, requirement(&state, &acc), "Two-factor authentication is already on").await);
+	}
+
+	let mut secret = [0u8; SECRET_BYTES];
+	OsRng.fill_bytes(&mut secret);
+	let secret = secret.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+	let result = sqlx::query!(
+		"INSERT INTO totp (user_id, secret) VALUES (?, ?)
+		ON CONFLICT(user_id) DO UPDATE SET secret = excluded.secret, last_step = 0 WHERE enabled IS NULL",
+		acc.uid, secret,
+	).execute(&state.db).await;
+	if let Err(e) = result {
+		return Err(view_two_factor(&state.db, &acc, requirement(&state, &acc), &e.to_string()).await);
+	}
+	Ok( make_redirect("/two_factor".to_owned()) )
+}
+
+// the first right code turns it on
+pub async fn perform_enable(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+	Form(form): Form<CodeForm>,
+) -> Result<Markup, Redirect> {
+	let mut state = stt.lock().await;
+	let acc = read_viewer(&state, &cookies).ok_or(make_redirect(login_url("/two_factor")))?;
+
+	let right = check_totp(&state.db, acc.uid, &normalize_code(&form.code), false).await;
+	match right {
+		Ok(true)=>(),
+		Ok(false)=>return Ok(view_two_factor(&state.db, &acc, requirement(&state, &acc), "Wrong code, check the time on your phone").await),
+		Err(e)=>return Ok(view_two_factor(&state.db, &acc, requirement(&state, &acc), &e).await),
+	}
+	let now = now();
+	let result = sqlx::query!(
+		"UPDATE totp SET enabled = ? WHERE user_id = ?",
+		now, acc.uid,
+	).execute(&state.db).await;
+	if let Err(e) = result {
+		return Ok(view_two_factor(&state.db, &acc, requirement(&state, &acc), &e.to_string()).await);
+	}
+	state.two_factor_uids.insert(acc.uid);
+
+	match new_recovery_codes(&state.db, acc.uid).await {
+		Ok(codes)=>Ok(view_recovery_codes(&codes)),
+		Err(e)=>Ok(view_two_factor(&state.db, &acc, requirement(&state, &acc), &e).await),
+	}
+}
+
+pub async fn perform_new_recovery_codes(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+	Form(form): Form<CodeForm>,
+) -> Result<Markup, Redirect> {
+	let state = read_state(stt).await;
+	let acc = read_viewer(&state, &cookies).ok_or(make_redirect(login_url("/two_factor")))?;
+
+	let codes = match check_code(&state.db, acc.uid, &form.code, false).await {
+		Ok(true)=>new_recovery_codes(&state.db, acc.uid).await,
+		Ok(false)=>Err("Wrong code".to_owned()),
+		Err(e)=>Err(e),
+	};
+	match codes {
+		Ok(codes)=>Ok(view_recovery_codes(&codes)),
+		Err(e)=>Ok(view_two_factor(&state.db, &acc, requirement(&state, &acc), &e).await),
+	}
+}
+
+pub async fn perform_disable(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+	Form(form): Form<CodeForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+	let Some(acc) = read_viewer(&state, &cookies) else {
+		return Ok(make_redirect(login_url("/two_factor")));
+	};
+	if is_required(&state, &acc) {
+		return Err(view_two_factor(&state.db, &acc, requirement(&state, &acc), "Your role requires two-factor authentication").await);
+	}
+	match check_code(&state.db, acc.uid, &form.code, true).await {
+		Ok(true)=>(),
+		Ok(false)=>return Err(view_two_factor(&state.db, &acc, requirement(&state, &acc), "Wrong code").await),
+		Err(e)=>return Err(view_two_factor(&state.db, &acc, requirement(&state, &acc), &e).await),
+	}
+
+	let result = sqlx::query!(
+		"DELETE FROM recovery_codes WHERE user_id = ?",
+		acc.uid,
+	).execute(&state.db).await;
+	let result = match result {
+		Ok(_)=>sqlx::query!(
+			"DELETE FROM totp WHERE user_id = ?",
+			acc.uid,
+		).execute(&state.db).await,
+		Err(e)=>Err(e),
+	};
+	if let Err(e) = result {
+		return Err(view_two_factor(&state.db, &acc, requirement(&state, &acc), &e.to_string()).await);
+	}
+	state.two_factor_uids.remove(&acc.uid);
+	Ok( make_redirect("/two_factor".to_owned()) )
+}
+
+pub async fn perform_policy(
+	State(stt): State<SharedState>,
+	_: Authorized<ManageAccounts>,
+	Form(form): Form<TwoFactorPolicyForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+	let required = form.required.is_some();
+	settings::set(&state.db, POLICY_SETTING, if required { "on" } else { "off" }).await
+		.map_err(|e| crate::view_error(e.to_string()))?;
+	state.staff_two_factor = required;
+	Ok( make_redirect("/admin/roles".to_owned()) )
+}
+
+// for the roles page
+pub fn view_policy(state: &ServerState) -> Markup {
+	html! {
+		form method="POST" action="/admin/two_factor" {
+			(csrf::field())
+			input id="two-factor-required" name="required" type="checkbox" checked[state.staff_two_factor] {}
+			label for="two-factor-required" { "Require two-factor authentication for every role that does more than reserve" }
+			" "
+			button { "Save" }
+		}
+	}
+}
+
+fn view_qr(uri: &str) -> Markup {
+	let svg = qrcode::QrCode::new(uri.as_bytes())
+		.map(|qr| qr.render::<qrcode::render::svg::Color>().min_dimensions(200, 200).build())
+		.unwrap_or_default();
+	// without the xml declaration, it goes in the page as is
+	let svg = svg.find("<svg").map_or("", |at| &svg[at..]).to_owned();
+	html! { (PreEscaped(svg)) }
+}
+
+// what the page needs from the state, taken before awaiting anything
+#[derive(Debug, Clone, Copy)]
+struct Requirement {
+	required: bool,
+	missing: bool,
+}
+
+fn requirement(state: &ServerState, acc: &Account) -> Requirement {
+	Requirement{
+		required: is_required(state, acc),
+		missing: missing(state, acc),
+	}
+}
+
+async fn view_two_factor(db: &sqlx::Pool<sqlx::Sqlite>, acc: &Account, requirement: Requirement, error: &str) -> Markup {
+	let totp = sqlx::query!(
+		"SELECT secret, enabled FROM totp WHERE user_id = ?",
+		acc.uid,
+	).fetch_optional(db).await;
+	let unused = sqlx::query!(
+		"SELECT COUNT(*) AS count FROM recovery_codes WHERE user_id = ? AND used IS NULL",
+		acc.uid,
+	).fetch_one(db).await.map_or(0, |row| row.count);
+	let (totp, error) = match totp {
+		Ok(totp)=>(totp, error.to_owned()),
+		Err(e)=>(None, e.to_string()),
+	};
+
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		title { "LSYS - Two-factor authentication" }
+	} body {
+		p style="color: red;" { (error) }
+		nav {
+			a href="/dashboard" { "Dashboard" }
+		}
+		h1 { "Two-factor authentication" }
+		@if requirement.missing {
+			p { b { "Your role requires two-factor authentication, set it up to keep using it." } }
+		}
+
+		@match totp {
+			Some(totp) if totp.enabled.is_some()=>{
+				p { "Two-factor authentication is on since " (sessions::view_time(totp.enabled.unwrap_or_default())) "." }
+				p { (unused) " recovery codes left." }
+				fieldset {
+					legend { "New recovery codes" }
+					form method="POST" action="/two_factor/recovery" {
+						(csrf::field())
+						label for="recovery-code" { "code from your app:" }
+						input id="recovery-code" name="code" type="text" inputmode="numeric" autocomplete="one-time-code" {}
+						br {}
+						button { "Make new codes" }
+					}
+					p { "The codes you have now stop working." }
+				}
+				@if !requirement.required {
+					fieldset {
+						legend { "Turn off" }
+						form method="POST" action="/two_factor/disable" {
+							(csrf::field())
+							label for="disable-code" { "code from your app, or a recovery code:" }
+							input id="disable-code" name="code" type="text" autocomplete="one-time-code" {}
+							br {}
+							button { "Turn off" }
+						}
+					}
+				}
+			}
+			Some(totp)=>{
+				@let secret = decode_hex(&totp.secret).map(|secret| base32(&secret)).unwrap_or_default();
+				@let label = utf8_percent_encode(&format!("{ISSUER}:{}", acc.email), NON_ALPHANUMERIC).to_string();
+				@let uri = format!(
+					"otpauth://totp/{label}?secret={secret}&issuer={ISSUER}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECS}"
+				);
+				p { "Scan this with an authenticator app, then enter the code it shows." }
+				(view_qr(&uri))
+				p { "Or type in this key: " code { (secret) } }
+				fieldset {
+					legend { "Confirm" }
+					form method="POST" action="/two_factor/enable" {
+						(csrf::field())
+						label for="enable-code" { "code:" }
+						input id="enable-code" name="code" type="text" inputmode="numeric" autocomplete="one-time-code" {}
+						br {}
+						button { "Turn on" }
+					}
+				}
+				form method="POST" action="/two_factor/setup" {
+					(csrf::field())
+					button { "Start over with a new key" }
+				}
+			}
+			None=>{
+				p { "After your password, logging in will also ask for a code from an app on your phone." }
+				form method="POST" action="/two_factor/setup" {
+					(csrf::field())
+					button { "Set up" }
+				}
+			}
+		}
+	} }
+}
+
+fn view_recovery_codes(codes: &[String]) -> Markup {
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		title { "LSYS - Recovery codes" }
+	} body {
+		h1 { "Your recovery codes" }
+		p { "Each logs you in once without your phone. Keep them somewhere safe, they won't be shown again." }
+		ul {
+			@for code in codes {
+				li { code { (code) } }
+			}
+		}
+		a href="/two_factor" { "Done" }
+	} }
+}
+
+fn view_second_step(goto: &str, error: &str) -> Markup {
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		title { "LSYS - Login" }
+	} body {
+		p style="color: red;" { (error) }
+		fieldset {
+			legend { "Two-factor authentication" }
+			form method="POST" action=(with_goto("/login/two_factor", goto)) {
+				(csrf::field())
+				label for="second-step-code" { "code from your app, or a recovery code:" }
+				input id="second-step-code" name="code" type="text" autocomplete="one-time-code" autofocus {}
+				br {}
+				button { "Log in" }
+			}
+		}
+		a href=(login_url(goto)) { "Start over" }
+	} }
+}
@@ -1,8 +1,12 @@
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize};
+use axum::http::Method;
 use chrono::Duration;
 use uuid::Uuid;
 use chrono::{NaiveDate};
 use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::AtomicI64;
 use sqlx::{Pool, Sqlite};
 
 pub type Bid = i64;
@@ -33,11 +37,31 @@ pub struct BookForm {
 }
 
 #[allow(non_snake_case)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct NewBookForm {
 	pub ISBN: ISBN,
 	pub name: String,
 	pub published: String,
+	// separated by ';', like in the CSV import
+	#[serde(default)]
+	pub authors: String,
+	#[serde(default, deserialize_with = "empty_as_none")]
+	pub copies: Option<usize>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+pub struct ISBNLookupParam {
+	#[serde(default)]
+	pub ISBN: Option<String>,
+}
+
+// what a metadata provider knows about an ISBN
+#[derive(Debug, Clone, Default)]
+pub struct BookMetadata {
+	pub name: String,
+	pub published: String,
+	pub authors: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -66,7 +90,7 @@ pub enum ReserveBookError {
 
 impl Book {
 	//TODO: db update
-	pub fn reserve(&self, account: &Account, db: &Pool<Sqlite>) -> Option<ReserveBookError> {
+	pub fn reserve(&self, account: &Account, _db: &Pool<Sqlite>) -> Option<ReserveBookError> {
 		match self.status.get() {
 			BorrowStatus::Reserved(_, until) => {
 				Some(ReserveBookError::Reserved(until))
@@ -105,13 +129,13 @@ impl Book {
 	//}
 }
 // TODO could use uuid_v3 with week + email + year, to keep UUIDs
-impl std::string::ToString for BorrowStatus {
-	fn to_string(&self) -> String {
-		match self {
+impl std::fmt::Display for BorrowStatus {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
 			BorrowStatus::Avaliable => "avaliable",
 			BorrowStatus::Reserved(_,_) => "reserved",
 			BorrowStatus::Borrowed(_,_) => "borrowed",
-		}.to_string()
+		})
 	}
 }
 
@@ -151,6 +175,20 @@ impl BorrowStatus {
 	}
 }
 
+// html forms send "" for an unselected option
+pub fn empty_as_none<'de, D, T>(de: D) -> Result<Option<T>, D::Error>
+where
+	D: Deserializer<'de>,
+	T: std::str::FromStr,
+	T::Err: std::fmt::Display,
+{
+	let raw = Option::<String>::deserialize(de)?;
+	match raw.as_deref().map(str::trim) {
+		None | Some("") => Ok(None),
+		Some(raw) => raw.parse().map(Some).map_err(serde::de::Error::custom),
+	}
+}
+
 #[derive(Debug, Deserialize)]
 pub struct BookParam {
 	pub bid: i64,
@@ -161,6 +199,104 @@ pub type Aid = i64;
 pub struct Author {
 	pub id: i64,
 	pub name: String,
+	pub born: Option<i64>,
+	pub died: Option<i64>,
+	// other forms of the name, like pen names or other spellings
+	pub alt_names: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContributorRole {
+	Author,
+	Editor,
+	Translator,
+	Illustrator,
+}
+
+impl ContributorRole {
+	pub const ALL: [ContributorRole; 4] = [
+		ContributorRole::Author,
+		ContributorRole::Editor,
+		ContributorRole::Translator,
+		ContributorRole::Illustrator,
+	];
+
+	// as stored in wrote.role
+	pub fn as_str(self) -> &'static str {
+		match self {
+			ContributorRole::Author => "author",
+			ContributorRole::Editor => "editor",
+			ContributorRole::Translator => "translator",
+			ContributorRole::Illustrator => "illustrator",
+		}
+	}
+
+	pub fn from_db(role: &str) -> Self {
+		Self::ALL.into_iter()
+			.find(|known| known.as_str() == role)
+			.unwrap_or(ContributorRole::Author)
+	}
+}
+
+impl std::fmt::Display for ContributorRole {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+// an author linked to a title in one role
+#[derive(Debug, Clone)]
+pub struct Contributor {
+	pub author: Arc<Author>,
+	pub role: ContributorRole,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthorityForm {
+	pub aid: Aid,
+	#[serde(default, deserialize_with = "empty_as_none")]
+	pub born: Option<i64>,
+	#[serde(default, deserialize_with = "empty_as_none")]
+	pub died: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AltNameForm {
+	pub aid: Aid,
+	pub name: String,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+pub struct ContributorForm {
+	pub ISBN: ISBN,
+	pub name: String,
+	pub role: ContributorRole,
+	#[serde(default, deserialize_with = "empty_as_none")]
+	pub bid: Option<Bid>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+pub struct RemoveContributorForm {
+	pub ISBN: ISBN,
+	pub aid: Aid,
+	pub role: ContributorRole,
+	#[serde(default, deserialize_with = "empty_as_none")]
+	pub bid: Option<Bid>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthorParam {
+	pub aid: Aid,
+}
+
+// "from" is deleted, its titles and page going to "into"
+#[derive(Debug, Deserialize)]
+pub struct AuthorMergeForm {
+	pub from: Aid,
+	pub into: Aid,
 }
 
 pub type Uid = i64;
@@ -171,8 +307,146 @@ pub struct Account {
 	pub name: String,
 	pub email: String,
 	pub pass_hash: String,
-	pub uuid: Uuid,
-	pub is_worker: bool,
+	pub role: Rid,
+	pub verified: bool,
+}
+
+pub type Rid = i64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Permission {
+	Reserve,
+	Circulate,
+	Catalog,
+	ManageAccounts,
+}
+
+impl Permission {
+	pub const ALL: [Permission; 4] = [
+		Permission::Reserve,
+		Permission::Circulate,
+		Permission::Catalog,
+		Permission::ManageAccounts,
+	];
+
+	// as stored in role_permissions.permission
+	pub fn as_str(self) -> &'static str {
+		match self {
+			Permission::Reserve => "reserve",
+			Permission::Circulate => "circulate",
+			Permission::Catalog => "catalog",
+			Permission::ManageAccounts => "manage_accounts",
+		}
+	}
+
+	pub fn from_db(permission: &str) -> Option<Self> {
+		Self::ALL.into_iter().find(|known| known.as_str() == permission)
+	}
+
+	pub fn describe(self) -> &'static str {
+		match self {
+			Permission::Reserve => "reserve copies and hold works",
+			Permission::Circulate => "lend and take back copies",
+			Permission::Catalog => "edit the catalog",
+			Permission::ManageAccounts => "assign roles to accounts",
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct Role {
+	pub id: Rid,
+	pub name: String,
+	pub permissions: HashSet<Permission>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RoleForm {
+	pub uid: Uid,
+	pub rid: Rid,
+}
+
+#[derive(Debug)]
+pub struct Session {
+	pub id: i64,
+	pub token_hash: String,
+	pub user_id: Uid,
+	// unix times
+	pub created: i64,
+	// atomic so every copy of the state sees a request touch it, without locking the state
+	pub last_seen: AtomicI64,
+	pub user_agent: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SessionLimits {
+	// seconds without a request, and seconds since logging in
+	pub idle: i64,
+	pub max: i64,
+}
+
+// what a personal API token may be used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenScope {
+	Read,
+	Write,
+}
+
+impl TokenScope {
+	pub const ALL: [TokenScope; 2] = [TokenScope::Read, TokenScope::Write];
+
+	// as stored in api_tokens.scope
+	pub fn as_str(self) -> &'static str {
+		match self {
+			TokenScope::Read => "read",
+			TokenScope::Write => "write",
+		}
+	}
+
+	pub fn from_db(scope: &str) -> Option<Self> {
+		Self::ALL.into_iter().find(|known| known.as_str() == scope)
+	}
+
+	pub fn describe(self) -> &'static str {
+		match self {
+			TokenScope::Read => "only look things up",
+			TokenScope::Write => "look things up and change them",
+		}
+	}
+
+	// read tokens can't send forms, only look at pages
+	pub fn allows(self, method: &Method) -> bool {
+		match self {
+			TokenScope::Read => method == Method::GET || method == Method::HEAD,
+			TokenScope::Write => true,
+		}
+	}
+}
+
+#[derive(Debug)]
+pub struct ApiToken {
+	pub id: i64,
+	pub user_id: Uid,
+	pub name: String,
+	pub scope: TokenScope,
+	// unix times
+	pub created: i64,
+	pub expires: i64,
+	// atomic like Session::last_seen, 0 when never used
+	pub last_used: AtomicI64,
+}
+
+// set on requests whose bearer token was accepted, which then carry no cookies
+#[derive(Debug, Clone)]
+pub struct BearerToken(pub Arc<ApiToken>);
+
+// failed logins from one address or against one email
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoginFailures {
+	pub count: u32,
+	// unix times
+	pub last: i64,
+	pub locked_until: i64,
 }
 
 #[derive(Debug, Clone)]
@@ -181,7 +455,8 @@ pub struct AccountQuery {
 	pub name: String,
 	pub email: String,
 	pub pass_hash: String,
-	pub is_worker: bool,
+	pub role_id: i64,
+	pub verified: bool,
 }
 
 #[derive(Deserialize, Debug)]
@@ -190,6 +465,128 @@ pub struct FormLogin {
 	pub pass: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct SessionForm {
+	pub id: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PasswordChangeForm {
+	pub pass: String,
+	pub new_pass: String,
+	pub confirm_pass: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ForgotPasswordForm {
+	pub email: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResetParam {
+	pub token: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResetPasswordForm {
+	pub token: String,
+	pub new_pass: String,
+	pub confirm_pass: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct VerifyParam {
+	pub uid: Uid,
+	pub expires: i64,
+	pub sig: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AccountForm {
+	pub uid: Uid,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LockoutForm {
+	pub id: i64,
+}
+
+// a login waiting for its second step, the password was right
+#[derive(Debug, Clone)]
+pub struct PendingLogin {
+	pub uid: Uid,
+	pub user_agent: String,
+	// unix time
+	pub expires: i64,
+	pub attempts: u32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CodeForm {
+	pub code: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TwoFactorPolicyForm {
+	// a checkbox, only sent when checked
+	pub required: Option<String>,
+}
+
+// a passkey ceremony the browser still has to answer, for adding a passkey to uid or for logging in;
+// kept per browser, under the token in its lsys-passkey cookie
+#[derive(Debug, Clone)]
+pub struct PasskeyChallenge {
+	pub challenge: String,
+	pub uid: Option<Uid>,
+	// unix time
+	pub expires: i64,
+}
+
+// the browser's answers are in unpadded base64url
+#[derive(Deserialize, Debug)]
+pub struct PasskeyRegisterForm {
+	pub name: String,
+	pub client_data: String,
+	pub attestation: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PasskeyLoginForm {
+	pub credential_id: String,
+	pub client_data: String,
+	pub authenticator_data: String,
+	pub signature: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PasskeyForm {
+	pub id: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewApiTokenForm {
+	pub name: String,
+	pub scope: String,
+	pub days: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ApiTokenForm {
+	pub id: i64,
+}
+
+// a row of borrow_log as /api/loans gives it
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize)]
+pub struct Loan {
+	pub user_id: Uid,
+	pub email: Option<String>,
+	pub book_id: Bid,
+	pub ISBN: Option<ISBN>,
+	pub borrow_time: String,
+	pub return_time: String,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct FormRegister {
 	pub name: String,
@@ -197,3 +594,245 @@ pub struct FormRegister {
 	pub pass: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct AccountNameForm {
+	pub name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AccountEmailForm {
+	pub email: String,
+	pub pass: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AccountDeleteForm {
+	pub pass: String,
+}
+
+
+pub type Sid = i64;
+#[derive(Debug, Clone)]
+pub struct Subject {
+	pub id: Sid,
+	pub name: String,
+	pub parent_id: Option<Sid>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubjectParam {
+	pub sid: Sid,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct CatalogFilter {
+	pub q: Option<String>,
+	#[serde(default, deserialize_with = "empty_as_none")]
+	pub subject: Option<Sid>,
+	pub tag: Option<String>,
+	// one row per work instead of per copy
+	pub collapse: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewSubjectForm {
+	pub name: String,
+	#[serde(default, deserialize_with = "empty_as_none")]
+	pub parent_id: Option<Sid>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+pub struct BookSubjectForm {
+	pub ISBN: ISBN,
+	pub sid: Sid,
+	// book page to return to
+	#[serde(default, deserialize_with = "empty_as_none")]
+	pub bid: Option<Bid>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+pub struct BookTagForm {
+	pub ISBN: ISBN,
+	pub tag: String,
+	#[serde(default, deserialize_with = "empty_as_none")]
+	pub bid: Option<Bid>,
+}
+
+pub type Srid = i64;
+#[derive(Debug, Clone)]
+pub struct Series {
+	pub id: Srid,
+	pub name: String,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone)]
+pub struct SeriesVolume {
+	pub series_id: Srid,
+	pub ISBN: ISBN,
+	pub volume: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SeriesParam {
+	pub srid: Srid,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewSeriesForm {
+	pub name: String,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+pub struct SeriesVolumeForm {
+	pub srid: Srid,
+	pub ISBN: ISBN,
+	#[serde(default, deserialize_with = "empty_as_none")]
+	pub volume: Option<i64>,
+}
+
+pub type Wid = i64;
+#[derive(Debug, Clone)]
+pub struct Work {
+	pub id: Wid,
+	pub title: String,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone)]
+pub struct Edition {
+	pub ISBN: ISBN,
+	pub work_id: Wid,
+	pub label: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Hold {
+	pub id: i64,
+	pub user_id: Uid,
+	pub work_id: Wid,
+	pub placed: NaiveDate,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WorkParam {
+	pub wid: Wid,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewWorkForm {
+	pub title: String,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+pub struct EditionForm {
+	pub wid: Wid,
+	pub ISBN: ISBN,
+	#[serde(default)]
+	pub label: String,
+}
+
+// one title read from an import file
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Default)]
+pub struct CatalogEntry {
+	pub ISBN: ISBN,
+	pub name: String,
+	pub published: String,
+	pub authors: Vec<String>,
+	pub copies: usize,
+}
+
+// record number and the entry read from it, or why it couldn't be read
+pub type ImportRecord = (usize, Result<CatalogEntry, String>);
+
+#[derive(Debug, Clone)]
+pub enum ImportOutcome {
+	Created,
+	Updated(Vec<String>),
+	Skipped(String),
+}
+
+// what happened to one record of an import, record numbers start at 1
+#[allow(non_snake_case)]
+#[derive(Debug, Clone)]
+pub struct ImportRow {
+	pub record: usize,
+	pub ISBN: Option<ISBN>,
+	pub name: String,
+	pub outcome: ImportOutcome,
+}
+
+// a previewed import waiting for the worker to commit it
+#[derive(Debug, Clone)]
+pub struct PendingImport {
+	pub uid: Uid,
+	pub entries: Vec<ImportRecord>,
+	pub created: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportCommitForm {
+	pub token: Uuid,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CitationFormat {
+	Bibtex,
+	Ris,
+	Csljson,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CiteParam {
+	pub format: CitationFormat,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Copy)]
+pub struct Cover {
+	pub ISBN: ISBN,
+	// of the uploaded image, each thumbnail is scaled down from it
+	pub width: i64,
+	pub height: i64,
+	// unix time, appended to the image urls so browsers refetch a replaced cover
+	pub uploaded: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CoverSize {
+	Small,
+	Medium,
+	Large,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+pub struct CoverParam {
+	pub ISBN: ISBN,
+	pub size: CoverSize,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+pub struct CoverForm {
+	pub ISBN: ISBN,
+	#[serde(default, deserialize_with = "empty_as_none")]
+	pub bid: Option<Bid>,
+}
+
+// item to item similarity from borrow_log, recomputed in the background
+#[derive(Debug, Clone, Default)]
+pub struct Recommendations {
+	// most similar titles first, with their cosine similarity
+	pub similar: HashMap<ISBN, Vec<(ISBN, f64)>>,
+	// every title each patron borrowed, as logged
+	pub borrowed: HashMap<Uid, HashSet<ISBN>>,
+	pub computed: Option<chrono::DateTime<chrono::Utc>>,
+}
@@ -0,0 +1,237 @@
+// confirming an account's email with a signed link, needed before reserving
+
+use axum::{
+	Form,
+	extract::{Query, State},
+	response::Redirect,
+};
+use hmac::{Hmac, Mac};
+use maud::{html, Markup, DOCTYPE};
+use sha2::Sha256;
+use tower_cookies::Cookies;
+use crate::types::*;
+use crate::mail::{Mail, public_url};
+use crate::roles::{Authorized, Circulate};
+use crate::{
+	SharedState, ServerState,
+	read_state, read_viewer, make_redirect, login_url, view_404, csrf, settings,
+};
+
+const SECRET_SETTING: &str = "link_secret";
+const VERIFY_DAYS: i64 = 7;
+
+// the key links are signed with is made once and kept, so links outlive restarts
+pub async fn load(state: &mut ServerState) {
+	let secret = settings::get(&state.db, SECRET_SETTING).await
+		.expect("can't read the link secret");
+	state.link_secret = match secret {
+		Some(secret)=>secret,
+		None=>{
+			let secret = crate::sessions::new_token();
+			settings::set(&state.db, SECRET_SETTING, &secret).await
+				.expect("can't store the link secret");
+			secret
+		}
+	};
+}
+
+fn now() -> i64 {
+	chrono::Utc::now().timestamp()
+}
+
+// the email is signed too, changing it makes links sent to the old one useless
+fn signature(state: &ServerState, uid: Uid, email: &str, expires: i64) -> Hmac<Sha256> {
+	let mut mac = Hmac::<Sha256>::new_from_slice(state.link_secret.as_bytes())
+		.expect("hmac takes keys of any size");
+	mac.update(format!("{uid}:{email}:{expires}").as_bytes());
+	mac
+}
+
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+	if !hex.len().is_multiple_of(2) {
+		return None;
+	}
+	(0..hex.len()).step_by(2)
+		.map(|at| u8::from_str_radix(hex.get(at..at+2)?, 16).ok())
+		.collect()
+}
+
+fn verify_link(state: &ServerState, acc: &Account, param: &VerifyParam) -> bool {
+	let Some(sig) = decode_hex(&param.sig) else {
+		return false;
+	};
+	param.expires > now()
+		&& signature(state, acc.uid, &acc.email, param.expires).verify_slice(&sig).is_ok()
+}
+
+// the mail goes out in the background, a failure is only logged
+pub fn send_verification(state: &ServerState, acc: &Account) {
+	let expires = now() + VERIFY_DAYS * 24 * 60 * 60;
+	let sig = signature(state, acc.uid, &acc.email, expires).finalize().into_bytes();
+	let sig = sig.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+	let link = format!("{}/verify?uid={}&expires={expires}&sig={sig}", public_url(), acc.uid);
+	let mail = Mail{
+		to: acc.email.clone(),
+		subject: "Confirm your LSYS email".to_owned(),
+		body: format!(
+			"Hello {},\r\n\r\nTo confirm this is your email and start reserving books, \
+			open this link within {VERIFY_DAYS} days:\r\n\r\n{link}\r\n",
+			acc.name,
+		),
+	};
+	let (transport, uid) = (state.mail.clone(), acc.uid);
+	tokio::spawn(async move {
+		if let Err(e) = transport.send(&mail).await {
+			eprintln!("can't send verification mail to account {uid}: {e}");
+		}
+	});
+}
+
+async fn mark_verified(state: &mut ServerState, acc: &Account) -> Result<(), String> {
+	sqlx::query!(
+		"UPDATE accounts SET verified = true WHERE id = ?",
+		acc.uid,
+	).execute(&state.db).await.map_err(|e| e.to_string())?;
+	Account::update_maps(state, Account{ verified: true, ..acc.clone() });
+	Ok(())
+}
+
+pub async fn display_verify(
+	State(stt): State<SharedState>,
+	Query(param): Query<VerifyParam>,
+) -> Markup {
+	let mut state = stt.lock().await;
+	let Some(acc) = state.uid_to_account.get(&param.uid).cloned() else {
+		return view_404(format!("/verify?uid={}", param.uid));
+	};
+	if !acc.verified {
+		if !verify_link(&state, &acc, &param) {
+			return view_verified("That link has expired or isn't right, log in to get a new one.");
+		}
+		if let Err(e) = mark_verified(&mut state, &acc).await {
+			return view_verified(&e);
+		}
+	}
+	view_verified("Your email is confirmed, you can reserve books now.")
+}
+
+pub async fn perform_resend_verification(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+) -> Redirect {
+	let state = read_state(stt).await;
+	let Some(acc) = read_viewer(&state, &cookies) else {
+		return make_redirect(login_url("/dashboard"));
+	};
+	if !acc.verified {
+		send_verification(&state, &acc);
+	}
+	make_redirect("/dashboard".to_owned())
+}
+
+pub async fn display_unverified(
+	State(stt): State<SharedState>,
+	_: Authorized<Circulate>,
+) -> Markup {
+	let state = read_state(stt).await;
+	view_unverified(&state, "")
+}
+
+pub async fn perform_staff_resend(
+	State(stt): State<SharedState>,
+	_: Authorized<Circulate>,
+	Form(form): Form<AccountForm>,
+) -> Result<Redirect, Markup> {
+	let state = read_state(stt).await;
+	let acc = state.uid_to_account.get(&form.uid)
+		.ok_or(view_unverified(&state, "No such account"))?;
+	send_verification(&state, acc);
+	Ok( make_redirect("/accounts/unverified".to_owned()) )
+}
+
+// for patrons who show up at the desk in person
+pub async fn perform_staff_verify(
+	State(stt): State<SharedState>,
+	_: Authorized<Circulate>,
+	Form(form): Form<AccountForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+	let acc = state.uid_to_account.get(&form.uid).cloned()
+		.ok_or(view_unverified(&state, "No such account"))?;
+	mark_verified(&mut state, &acc).await
+		.map_err(|e| view_unverified(&state, &e))?;
+	Ok( make_redirect("/accounts/unverified".to_owned()) )
+}
+
+fn view_verified(message: &str) -> Markup {
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		title { "LSYS - Email confirmation" }
+	} body {
+		h1 { (message) }
+		a href="/" { "Catalog" }
+	} }
+}
+
+// in place of reserving, for accounts that didn't confirm their email yet
+pub fn view_verify_first() -> Markup {
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		title { "LSYS - Confirm your email" }
+	} body {
+		h1 { "Confirm your email before reserving" }
+		(view_resend())
+	} }
+}
+
+pub fn view_resend() -> Markup {
+	html! {
+		p { "We sent you a link to confirm your email, open it to start reserving books." }
+		form method="POST" action="/verify/resend" {
+			(csrf::field())
+			button { "Send the link again" }
+		}
+	}
+}
+
+fn view_unverified(state: &ServerState, error: &str) -> Markup {
+	let mut accounts = state.uid_to_account.values()
+		.filter(|acc| !acc.verified)
+		.collect::<Vec<_>>();
+	accounts.sort_by_key(|acc| acc.uid);
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		title { "LSYS - Unconfirmed accounts" }
+	} body {
+		p style="color: red;" { (error) }
+		nav {
+			a href="/dashboard" { "Dashboard" }
+		}
+		h1 { "Accounts without a confirmed email" }
+		@if accounts.is_empty() {
+			p { "Every account is confirmed." }
+		}
+		table {
+			tbody {
+				@for acc in &accounts { tr {
+					td { (acc.name) }
+					td { (acc.email) }
+					td {
+						form method="POST" action="/accounts/resend_verification" {
+							(csrf::field())
+							input type="hidden" name="uid" value=(acc.uid) {}
+							button { "Resend link" }
+						}
+					}
+					td {
+						form method="POST" action="/accounts/verify" {
+							(csrf::field())
+							input type="hidden" name="uid" value=(acc.uid) {}
+							button { "Confirm" }
+						}
+					}
+				} }
+			}
+		}
+	} }
+}
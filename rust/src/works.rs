@@ -0,0 +1,474 @@
+// works group the editions (ISBNs) of one title, holds can be placed on any edition of a work
+
+use axum::{
+	Form,
+	extract::State,
+	extract::Query,
+	response::Redirect,
+};
+use maud::{html, Markup, DOCTYPE};
+use std::{
+	sync::Arc,
+	collections::BTreeMap,
+};
+use tower_cookies::Cookies;
+use chrono::NaiveDate;
+use crate::types::*;
+use crate::roles::{Authorized, Catalog, Reserve};
+use crate::{
+	SharedState, ServerState,
+	read_state, read_viewer, make_redirect, login_url,
+	view_404, title_copy, availability, csrf,
+};
+
+pub async fn load(state: &mut ServerState) {
+	let works = sqlx::query_as!(
+		Work,
+		"SELECT id, title FROM works",
+	).fetch_all(&state.db).await.expect("can't parse row from works into Work");
+
+	let editions = sqlx::query_as!(
+		Edition,
+		"SELECT ISBN, work_id, label FROM editions",
+	).fetch_all(&state.db).await.expect("can't parse row from editions into Edition");
+
+	let holds = sqlx::query_as!(
+		Hold,
+		r#"SELECT id, user_id, work_id, placed as "placed: NaiveDate" FROM holds ORDER BY placed, id"#,
+	).fetch_all(&state.db).await.expect("can't parse row from holds into Hold");
+
+	works.into_iter().for_each(|work| Work::update_maps(state, work));
+	for edition in editions {
+		state.ISBN_to_edition.insert(edition.ISBN, edition);
+	}
+	for hold in holds {
+		state.wid_to_holds.entry(hold.work_id).or_default().push(hold);
+	}
+}
+
+impl Work {
+	fn update_maps(state: &mut ServerState, work: Self) {
+		state.wid_to_work.insert(work.id, Arc::new(work));
+	}
+}
+
+// editions of a work, sorted by ISBN
+pub fn work_editions(state: &ServerState, wid: Wid) -> Vec<&Edition> {
+	let mut editions = state.ISBN_to_edition
+		.values()
+		.filter(|edition| edition.work_id == wid)
+		.collect::<Vec<_>>();
+	editions.sort_by_key(|edition| edition.ISBN);
+	editions
+}
+
+// an avaliable copy of any edition of the work
+fn avaliable_copy(state: &ServerState, wid: Wid) -> Option<&Book> {
+	work_editions(state, wid)
+		.into_iter()
+		.filter_map(|edition| title_copy(state, edition.ISBN))
+		.find(|book| book.status.get().is_avaliable())
+}
+
+pub fn hold_position(state: &ServerState, wid: Wid, uid: Uid) -> Option<usize> {
+	state.wid_to_holds
+		.get(&wid)?
+		.iter()
+		.position(|hold| hold.user_id == uid)
+		.map(|at| at+1)
+}
+
+pub async fn display_work(
+	State(stt): State<SharedState>,
+	cookies: Cookies,
+	Query(wid): Query<WorkParam>,
+) -> Result<Markup, Redirect> {
+	let state = read_state(stt).await;
+	let acc = read_viewer(&state, &cookies);
+
+	Ok( match state.wid_to_work.get(&wid.wid) {
+		Some(work)=>view_work(&state, work, acc.as_deref(), ""),
+		None=>view_404(format!("/work?wid={}", wid.wid)),
+	} )
+}
+
+pub async fn perform_hold(
+	State(stt): State<SharedState>,
+	Authorized(acc, _): Authorized<Reserve>,
+	Form(wid): Form<WorkParam>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+	let work = state.wid_to_work
+		.get(&wid.wid)
+		.cloned()
+		.ok_or(view_404(format!("/work?wid={}", wid.wid)))?;
+
+	// reserve straight away when some edition is on the shelf
+	if let Some(book) = avaliable_copy(&state, work.id) {
+		return match book.reserve(&acc, &state.db) {
+			None=>Ok( make_redirect(format!("/book?bid={}", book.bid)) ),
+			Some(_)=>Err( view_work(&state, &work, Some(&acc), "Couldn't reserve that copy") ),
+		};
+	}
+
+	if hold_position(&state, work.id, acc.uid).is_some() {
+		return Ok( make_redirect(format!("/work?wid={}", work.id)) );
+	}
+	let placed = chrono::Utc::now().date_naive();
+	let result = sqlx::query!(
+		"INSERT INTO holds (user_id, work_id, placed) VALUES (?, ?, ?)",
+		acc.uid, work.id, placed,
+	).execute(&state.db).await;
+	let result = result.map_err(|e| view_work(&state, &work, Some(&acc), &e.to_string()))?;
+
+	state.wid_to_holds.entry(work.id).or_default().push(Hold{
+		id: result.last_insert_rowid(),
+		user_id: acc.uid,
+		work_id: work.id,
+		placed,
+	});
+	Ok( make_redirect(format!("/work?wid={}", work.id)) )
+}
+
+pub async fn perform_cancel_hold(
+	State(stt): State<SharedState>,
+	Authorized(acc, _): Authorized<Reserve>,
+	Form(wid): Form<WorkParam>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	sqlx::query!(
+		"DELETE FROM holds WHERE user_id = ? AND work_id = ?",
+		acc.uid, wid.wid,
+	).execute(&state.db).await
+		.map_err(|e| crate::view_error(e.to_string()))?;
+	if let Some(holds) = state.wid_to_holds.get_mut(&wid.wid) {
+		holds.retain(|hold| hold.user_id != acc.uid);
+	}
+	Ok( make_redirect(format!("/work?wid={}", wid.wid)) )
+}
+
+pub async fn display_manage_works(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+) -> Result<Markup, Redirect> {
+	let state = read_state(stt).await;
+
+	Ok( view_manage_works(&state, "") )
+}
+
+pub async fn perform_new_work(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<NewWorkForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	let title = form.title.trim();
+	if title.is_empty() {
+		return Err(view_manage_works(&state, "Work needs a title"));
+	}
+
+	let result = sqlx::query!(
+		"INSERT INTO works (title) VALUES (?)", title,
+	).execute(&state.db).await;
+	let result = result.map_err(|e| view_manage_works(&state, &e.to_string()))?;
+
+	Work::update_maps(&mut state, Work{
+		id: result.last_insert_rowid() as Wid,
+		title: title.to_owned(),
+	});
+	Ok( make_redirect("/works/manage".to_owned()) )
+}
+
+pub async fn perform_link_edition(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<EditionForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	if !state.wid_to_work.contains_key(&form.wid) {
+		return Err(view_manage_works(&state, "No such work"));
+	}
+	if title_copy(&state, form.ISBN).is_none() {
+		return Err(view_manage_works(&state, "No such ISBN"));
+	}
+
+	// an edition belongs to one work, linking again moves it
+	let label = form.label.trim();
+	sqlx::query!(
+		"INSERT INTO editions (ISBN, work_id, label) VALUES (?, ?, ?)
+		ON CONFLICT(ISBN) DO UPDATE SET work_id = excluded.work_id, label = excluded.label",
+		form.ISBN, form.wid, label,
+	).execute(&state.db).await
+		.map_err(|e| view_manage_works(&state, &e.to_string()))?;
+
+	state.ISBN_to_edition.insert(form.ISBN, Edition{
+		ISBN: form.ISBN,
+		work_id: form.wid,
+		label: label.to_owned(),
+	});
+	Ok( make_redirect(format!("/work?wid={}", form.wid)) )
+}
+
+pub async fn perform_unlink_edition(
+	State(stt): State<SharedState>,
+	_: Authorized<Catalog>,
+	Form(form): Form<EditionForm>,
+) -> Result<Redirect, Markup> {
+	let mut state = stt.lock().await;
+
+	sqlx::query!(
+		"DELETE FROM editions WHERE ISBN = ? AND work_id = ?",
+		form.ISBN, form.wid,
+	).execute(&state.db).await
+		.map_err(|e| view_manage_works(&state, &e.to_string()))?;
+
+	if state.ISBN_to_edition.get(&form.ISBN).is_some_and(|edition| edition.work_id == form.wid) {
+		state.ISBN_to_edition.remove(&form.ISBN);
+	}
+	Ok( make_redirect(format!("/work?wid={}", form.wid)) )
+}
+
+fn view_edition_label(state: &ServerState, edition: &Edition) -> Markup {
+	html! {
+		@match title_copy(state, edition.ISBN) {
+			Some(book)=>{ a href={"/book?bid="(book.bid)} { (edition.ISBN) } },
+			None=>{ (edition.ISBN) },
+		}
+		@if !edition.label.is_empty() {
+			" (" (edition.label) ")"
+		}
+	}
+}
+
+// the other editions of this title, if it belongs to a work
+pub fn view_book_editions(state: &ServerState, book: &Book) -> Markup {
+	let Some(edition) = state.ISBN_to_edition.get(&book.ISBN) else {
+		return html! {};
+	};
+	let Some(work) = state.wid_to_work.get(&edition.work_id) else {
+		return html! {};
+	};
+	let others = work_editions(state, work.id)
+		.into_iter()
+		.filter(|other| other.ISBN != book.ISBN)
+		.collect::<Vec<_>>();
+	html! {
+		section id="editions" {
+			p {
+				"Edition of "
+				a href={"/work?wid="(work.id)} { i { (work.title) } }
+				@if !edition.label.is_empty() {
+					" (" (edition.label) ")"
+				}
+			}
+			@if !others.is_empty() {
+				p {
+					"Other editions: "
+					@for other in others {
+						(view_edition_label(state, other)) " "
+					}
+				}
+			}
+		}
+	}
+}
+
+struct TitleGroup<'a> {
+	name: String,
+	work: Option<Wid>,
+	copy: &'a Book,
+	editions: Vec<ISBN>,
+	authors: Vec<String>,
+}
+
+// one row per work, titles without a work get a row per ISBN
+pub fn view_works_table(state: &ServerState, books: &[Book]) -> Markup {
+	let mut groups = BTreeMap::<(String, Option<Wid>, Option<ISBN>), TitleGroup>::new();
+	for book in books {
+		let work = state.ISBN_to_edition
+			.get(&book.ISBN)
+			.and_then(|edition| state.wid_to_work.get(&edition.work_id));
+		let (key, name) = match work {
+			Some(work)=>((work.title.clone(), Some(work.id), None), work.title.clone()),
+			None=>((book.name.clone(), None, Some(book.ISBN)), book.name.clone()),
+		};
+		let group = groups.entry(key).or_insert_with(|| TitleGroup{
+			name,
+			work: work.map(|work| work.id),
+			copy: book,
+			editions: Vec::new(),
+			authors: Vec::new(),
+		});
+		if !group.editions.contains(&book.ISBN) {
+			group.editions.push(book.ISBN);
+		}
+		for author in &book.authors {
+			if !group.authors.contains(author) {
+				group.authors.push(author.clone());
+			}
+		}
+	}
+
+	html! {
+		table {
+			thead { tr {
+				td { "Name" }
+				td { "Editions" }
+				td { "Authors" }
+				td { "Avaliable copies" }
+			} }
+			tbody {
+				@for group in groups.values() { tr {
+					@let (avaliable, total) = group.editions
+						.iter()
+						.map(|isbn| availability(state, *isbn))
+						.fold((0, 0), |(a, t), (avaliable, total)| (a+avaliable, t+total));
+					td {
+						@match group.work {
+							Some(wid)=>{ a href={"/work?wid="(wid)} { i { (group.name) } } },
+							None=>{ a href={"/book?bid="(group.copy.bid)} { i { (group.name) } } },
+						}
+					}
+					td {
+						@for isbn in &group.editions {
+							@match state.ISBN_to_edition.get(isbn) {
+								Some(edition)=>{ p { (view_edition_label(state, edition)) } },
+								None=>{ p { (isbn) } },
+							}
+						}
+					}
+					td {
+						@for author in &group.authors {
+							p { (author) }
+						}
+					}
+					td { (avaliable) " of " (total) }
+				} }
+			}
+		}
+	}
+}
+
+fn view_work(state: &ServerState, work: &Work, viewer: Option<&Account>, error: &str) -> Markup {
+	let holds = state.wid_to_holds.get(&work.id).map_or(0, Vec::len);
+	let position = viewer.and_then(|viewer| hold_position(state, work.id, viewer.uid));
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		title { {"LSYS - " (work.title)} }
+	} body {
+		p style="color: red;" { (error) }
+		h1 { i { (work.title) } }
+		a href="/?collapse=true" { "All works" }
+		table {
+			thead { tr {
+				td { "Edition" }
+				td { "Name" }
+				td { "Published" }
+				td { "Avaliable copies" }
+			} }
+			tbody {
+				@for edition in work_editions(state, work.id) { tr {
+					@let (avaliable, total) = availability(state, edition.ISBN);
+					td { (view_edition_label(state, edition)) }
+					@match title_copy(state, edition.ISBN) {
+						Some(book)=>{
+							td { i { (book.name) } }
+							td { (book.published) }
+						},
+						None=>{ td {} td {} },
+					}
+					td { (avaliable) "/" (total) }
+				} }
+			}
+		}
+
+		section {
+			@if let Some(position) = position {
+				p { {"You hold position " (position) " of " (holds) " for this work"} }
+				form method="POST" action="/work/unhold" {
+					(csrf::field())
+					input type="hidden" name="wid" value=(work.id) {}
+					button { "Cancel hold" }
+				}
+			} @else {
+				@if holds > 0 {
+					p { (holds) " patrons are waiting for this work" }
+				}
+				@if viewer.is_some() {
+					form method="POST" action="/work/hold" {
+						(csrf::field())
+						input type="hidden" name="wid" value=(work.id) {}
+						button { "Reserve any edition" }
+					}
+				} @else {
+					a href=(login_url(&format!("/work?wid={}", work.id))) { "Log in to reserve" }
+				}
+			}
+		}
+	} }
+}
+
+fn view_manage_works(state: &ServerState, error: &str) -> Markup {
+	let mut works = state.wid_to_work.values().collect::<Vec<_>>();
+	works.sort_by(|a, b| a.title.cmp(&b.title));
+	html! { (DOCTYPE) head {
+		meta charset="UTF-8"{}
+		link rel="stylesheet" type="text/css" href="/files/css/login.css"{}
+		title { "LSYS - Manage works" }
+	} body {
+		p style="color: red;" { (error) }
+		a href="/?collapse=true" { "Browse works" }
+
+		fieldset {
+			legend { "New work" }
+			form method="POST" action="/works/new" {
+				(csrf::field())
+				label for="work-title" { "title:" }
+				input id="work-title" name="title" type="text" placeholder="title" {}
+				br {}
+				button { "Create" }
+			}
+		}
+
+		fieldset {
+			legend { "Link edition" }
+			form method="POST" action="/works/link" {
+				(csrf::field())
+				label for="edition-work" { "work:" }
+				select id="edition-work" name="wid" {
+					@for work in &works {
+						option value=(work.id) { (work.title) }
+					}
+				}
+				br {}
+				label for="edition-isbn" { "ISBN:" }
+				input id="edition-isbn" name="ISBN" type="number" placeholder="ISBN" {}
+				br {}
+				label for="edition-label" { "label:" }
+				input id="edition-label" name="label" type="text" placeholder="hardcover" {}
+				br {}
+				button { "Link" }
+			}
+		}
+
+		@for work in &works {
+			h3 { a href={"/work?wid="(work.id)} { (work.title) } }
+			ul {
+				@for edition in work_editions(state, work.id) {
+					li {
+						(view_edition_label(state, edition))
+						form method="POST" action="/works/unlink" style="display: inline;" {
+							(csrf::field())
+							input type="hidden" name="wid" value=(work.id) {}
+							input type="hidden" name="ISBN" value=(edition.ISBN) {}
+							button { "unlink" }
+						}
+					}
+				}
+			}
+		}
+	} }
+}